@@ -39,7 +39,7 @@ fn start_visual_novel(
     mut state: ResMut<NextState<AppState>>,
 ) {
     if let Some(rpy) = rpy_assets.get(scenario.id()) {
-        ew_start_scenario.write(EventStartScenario { ast: rpy.0.clone() });
+        ew_start_scenario.write(EventStartScenario::from_asset(scenario.id(), rpy));
         state.set(AppState::Novel);
     }
 }
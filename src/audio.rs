@@ -0,0 +1,182 @@
+//! Named audio channels and their volume.
+//!
+//! Every [`AudioMode`](crate::messages::AudioMode) plays through the kira
+//! `DynamicAudioChannel` of the same name. `music`, `sound` and `voice` always exist, custom
+//! channels are declared with [`NovelAudioSettings::add_channel`]. Volumes live in
+//! [`NovelSettings::audio`] and are written to `audio.ron` of the save directory whenever
+//! they change.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use bevy::prelude::*;
+use bevy_kira_audio::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    NovelSettings,
+    save::{DelayedWrite, NovelSaveError, write_atomic},
+};
+
+pub const MUSIC_CHANNEL: &str = "music";
+pub const SOUND_CHANNEL: &str = "sound";
+pub const VOICE_CHANNEL: &str = "voice";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NovelChannelVolume {
+    /// Linear volume, 1.0 plays the audio as is
+    pub volume: f32,
+    pub muted: bool,
+}
+
+impl Default for NovelChannelVolume {
+    fn default() -> Self {
+        NovelChannelVolume {
+            volume: 1.0,
+            muted: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NovelAudioSettings {
+    /// Linear volume applied on top of every channel
+    pub master_volume: f32,
+    pub muted: bool,
+    /// Volume of every channel, keyed by channel name
+    pub channels: BTreeMap<String, NovelChannelVolume>,
+}
+
+impl Default for NovelAudioSettings {
+    fn default() -> Self {
+        NovelAudioSettings {
+            master_volume: 1.0,
+            muted: false,
+            channels: [MUSIC_CHANNEL, SOUND_CHANNEL, VOICE_CHANNEL]
+                .into_iter()
+                .map(|name| (name.to_string(), NovelChannelVolume::default()))
+                .collect(),
+        }
+    }
+}
+
+impl NovelAudioSettings {
+    /// Declares a custom channel `play` and `stop` can name
+    pub fn add_channel(&mut self, name: impl Into<String>) {
+        self.channels.entry(name.into()).or_default();
+    }
+
+    pub fn channel(&self, name: &str) -> Option<&NovelChannelVolume> {
+        self.channels.get(name)
+    }
+
+    pub fn channel_mut(&mut self, name: &str) -> Option<&mut NovelChannelVolume> {
+        self.channels.get_mut(name)
+    }
+
+    /// Volume `name` plays at once master volume and mutes are applied
+    pub fn effective_volume(&self, name: &str) -> f32 {
+        let Some(channel) = self.channel(name) else {
+            return 0.0;
+        };
+
+        if self.muted || channel.muted {
+            return 0.0;
+        }
+
+        (self.master_volume * channel.volume).max(0.0)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), NovelSaveError> {
+        write_atomic(path, &ron::to_string(self)?)
+    }
+
+    pub fn read(path: &Path) -> Result<Self, NovelSaveError> {
+        let content = fs::read_to_string(path)?;
+        Ok(ron::from_str(&content)?)
+    }
+}
+
+/// Kira volume for a linear `volume`, anything at or below -60 dB is silence
+fn decibels(volume: f32) -> Decibels {
+    if volume <= 0.0 {
+        return Decibels::SILENCE;
+    }
+
+    Decibels((20.0 * volume.log10()).max(Decibels::SILENCE.0))
+}
+
+pub fn audio_settings_path(novel_settings: &NovelSettings) -> PathBuf {
+    novel_settings.save_directory.join("audio.ron")
+}
+
+pub fn load_audio_settings(mut novel_settings: ResMut<NovelSettings>) {
+    let path = audio_settings_path(&novel_settings);
+    if !path.exists() {
+        return;
+    }
+
+    match NovelAudioSettings::read(&path) {
+        Ok(loaded) => {
+            // Channels declared by the game stay even if the file predates them
+            let mut audio = loaded;
+            for (name, volume) in novel_settings.audio.channels.iter() {
+                audio
+                    .channels
+                    .entry(name.clone())
+                    .or_insert_with(|| volume.clone());
+            }
+            novel_settings.audio = audio;
+        }
+        Err(err) => error!("Loading audio settings: {}", err),
+    }
+}
+
+/// Writes the audio settings a moment after they changed, so that dragging a slider doesn't
+/// write the file every frame, and right away on exit
+pub fn write_audio_settings(
+    mut written: Local<Option<NovelAudioSettings>>,
+    mut write: Local<DelayedWrite>,
+    mut er_app_exit: MessageReader<AppExit>,
+    novel_settings: Res<NovelSettings>,
+    time: Res<Time>,
+) {
+    let written = written.get_or_insert_with(|| novel_settings.audio.clone());
+    if novel_settings.is_changed() && novel_settings.audio != *written {
+        write.schedule();
+    }
+
+    if !write.due(&time, &mut er_app_exit) {
+        return;
+    }
+
+    *written = novel_settings.audio.clone();
+
+    if let Err(err) = novel_settings
+        .audio
+        .write(&audio_settings_path(&novel_settings))
+    {
+        error!("Writing audio settings: {}", err);
+    }
+}
+
+/// Creates the declared channels and sets their volume whenever the settings change
+pub fn apply_audio_settings(
+    mut channels: ResMut<DynamicAudioChannels>,
+    novel_settings: Res<NovelSettings>,
+) {
+    if !novel_settings.is_changed() {
+        return;
+    }
+
+    for name in novel_settings.audio.channels.keys() {
+        if !channels.is_channel(name) {
+            channels.create_channel(name);
+        }
+
+        channels
+            .channel(name)
+            .set_volume(decibels(novel_settings.audio.effective_volume(name)));
+    }
+}
@@ -0,0 +1,240 @@
+use bevy::prelude::*;
+use bevy_kira_audio::prelude::*;
+
+use crate::{
+    NovelData, NovelSettings, VoiceHandle,
+    audio::VOICE_CHANNEL,
+    backlog::NovelBacklog,
+    messages::{EventSwitchNextNode, EventTextRevealed},
+    text::NovelTextReveal,
+};
+
+/// "Auto" label shown while auto-forward is on
+#[derive(Component)]
+pub struct NovelAutoForwardIndicator;
+
+/// Delay before auto-forward moves on from a line of `length` characters
+pub fn auto_forward_delay(novel_settings: &NovelSettings, length: usize) -> f32 {
+    novel_settings.auto_forward_delay + novel_settings.auto_forward_character_delay * length as f32
+}
+
+/// Advances the story once a line has been shown for [`auto_forward_delay`] and its voice
+/// has finished, while [`NovelSettings::auto_forward`] is on and
+/// [`NovelSettings::pause_handle_switch_node`] isn't. A line stopped at `{w}` or `{p}` goes on
+/// the same way once the text before it has been shown for its delay.
+#[allow(clippy::too_many_arguments)]
+pub fn auto_forward(
+    mut countdown: Local<Option<(usize, usize, f32)>>,
+    novel_settings: Res<NovelSettings>,
+    novel_data: Res<NovelData>,
+    backlog: Res<NovelBacklog>,
+    mut text_reveal: ResMut<NovelTextReveal>,
+    voice_handle: Res<VoiceHandle>,
+    channels: Res<DynamicAudioChannels>,
+    audio_instances: Res<Assets<AudioInstance>>,
+    time: Res<Time>,
+    mut er_text_revealed: MessageReader<EventTextRevealed>,
+    mut ew_switch_next_node: MessageWriter<EventSwitchNextNode>,
+) {
+    // Countdowns are keyed by line and by stop within the line
+    let stop = (novel_data.current_index, text_reveal.waits_reached());
+
+    let revealed = er_text_revealed.read().count() > 0;
+    let paused = text_reveal.is_waiting_for_click()
+        && countdown.is_none_or(|(index, waits, _)| (index, waits) != stop);
+    if revealed || paused {
+        let delay = auto_forward_delay(&novel_settings, text_reveal.read_len());
+        *countdown = Some((stop.0, stop.1, delay));
+    }
+
+    let Some((index, waits, seconds)) = *countdown else {
+        return;
+    };
+
+    // The story moved on by other means, or the player went on from a wait
+    if (index, waits) != stop || (text_reveal.is_running() && !text_reveal.is_waiting_for_click()) {
+        *countdown = None;
+        return;
+    }
+
+    if !novel_settings.auto_forward
+        || novel_settings.pause_handle_switch_node
+        || novel_data.menu.is_some()
+        || backlog.open
+    {
+        return;
+    }
+
+    if let Some(handle) = voice_handle.0.as_ref() {
+        // The instance of a voice is only made once its file has loaded, the play command
+        // waits in the channel until then. Finished instances are removed.
+        let queued = channels
+            .get_channel(VOICE_CHANNEL)
+            .is_some_and(|channel| matches!(channel.state(handle), PlaybackState::Queued));
+        let playing = audio_instances
+            .get(handle)
+            .is_some_and(|instance| !matches!(instance.state(), PlaybackState::Stopped));
+
+        if queued || playing {
+            return;
+        }
+    }
+
+    let seconds = seconds - time.delta_secs();
+    if seconds > 0.0 {
+        *countdown = Some((index, waits, seconds));
+        return;
+    }
+
+    *countdown = None;
+    if text_reveal.is_waiting_for_click() {
+        text_reveal.advance();
+    } else {
+        ew_switch_next_node.write(EventSwitchNextNode {});
+    }
+}
+
+pub fn update_auto_forward_indicator(
+    novel_settings: Res<NovelSettings>,
+    mut indicators: Query<&mut Visibility, With<NovelAutoForwardIndicator>>,
+) {
+    if !novel_settings.is_changed() {
+        return;
+    }
+
+    for mut visibility in indicators.iter_mut() {
+        *visibility = if novel_settings.auto_forward {
+            Visibility::Visible
+        } else {
+            Visibility::Hidden
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bevy::time::TimeUpdateStrategy;
+
+    use super::*;
+    use crate::text::reveal_text;
+
+    #[derive(Resource, Default)]
+    struct Switches(usize);
+
+    fn count_switches(
+        mut er_switch_next_node: MessageReader<EventSwitchNextNode>,
+        mut switches: ResMut<Switches>,
+    ) {
+        switches.0 += er_switch_next_node.read().count();
+    }
+
+    fn settings() -> NovelSettings {
+        NovelSettings {
+            auto_forward: true,
+            auto_forward_delay: 1.0,
+            auto_forward_character_delay: 0.1,
+            text_speed: 0.0,
+            ..default()
+        }
+    }
+
+    /// App showing `text` at once, each update a quarter of a second long
+    fn app(text: &str) -> App {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, AssetPlugin::default()))
+            .init_asset::<AudioInstance>()
+            .add_message::<EventTextRevealed>()
+            .add_message::<EventSwitchNextNode>()
+            .init_resource::<NovelData>()
+            .init_resource::<NovelBacklog>()
+            .init_resource::<NovelTextReveal>()
+            .init_resource::<DynamicAudioChannels>()
+            .init_resource::<Switches>()
+            .insert_resource(VoiceHandle(None))
+            .insert_resource(settings())
+            .insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_millis(
+                250,
+            )))
+            .add_systems(Update, (reveal_text, auto_forward, count_switches).chain());
+
+        app.world_mut()
+            .resource_mut::<NovelTextReveal>()
+            .start(text, None);
+        app
+    }
+
+    /// Runs `seconds` worth of updates
+    fn run(app: &mut App, seconds: f32) {
+        for _ in 0..(seconds * 4.0) as usize {
+            app.update();
+        }
+    }
+
+    fn switches(app: &App) -> usize {
+        app.world().resource::<Switches>().0
+    }
+
+    #[test]
+    fn counts_characters() {
+        let novel_settings = settings();
+
+        assert_eq!(auto_forward_delay(&novel_settings, 0), 1.0);
+        assert_eq!(auto_forward_delay(&novel_settings, 10), 2.0);
+        // Characters, not bytes
+        let mut reveal = NovelTextReveal::default();
+        reveal.start("héllo…", None);
+        reveal.advance();
+        assert_eq!(reveal.text().len(), 6);
+        assert_eq!(reveal.read_len(), 6);
+    }
+
+    #[test]
+    fn waits_for_line_delay() {
+        // 1.0 + 5 * 0.1 seconds
+        let mut app = app("Hello");
+
+        run(&mut app, 1.25);
+        assert_eq!(switches(&app), 0);
+
+        run(&mut app, 1.0);
+        assert_eq!(switches(&app), 1);
+    }
+
+    #[test]
+    fn goes_on_from_click_waits() {
+        // 1.0 + 4 * 0.1 seconds before the wait, 1.0 + 5 * 0.1 after it
+        let mut app = app("Wait{w} more");
+
+        run(&mut app, 1.0);
+        assert!(
+            app.world()
+                .resource::<NovelTextReveal>()
+                .is_waiting_for_click()
+        );
+
+        run(&mut app, 1.0);
+        let reveal = app.world().resource::<NovelTextReveal>();
+        assert!(!reveal.is_waiting_for_click());
+        assert!(!reveal.is_running());
+        assert_eq!(switches(&app), 0);
+
+        run(&mut app, 2.0);
+        assert_eq!(switches(&app), 1);
+    }
+
+    #[test]
+    fn holds_while_off() {
+        let mut app = app("Wait{w} more");
+        app.world_mut().resource_mut::<NovelSettings>().auto_forward = false;
+
+        run(&mut app, 5.0);
+        assert!(
+            app.world()
+                .resource::<NovelTextReveal>()
+                .is_waiting_for_click()
+        );
+        assert_eq!(switches(&app), 0);
+    }
+}
@@ -0,0 +1,292 @@
+use bevy::{
+    input::mouse::{AccumulatedMouseScroll, MouseScrollUnit},
+    prelude::*,
+};
+use renpy_parser::parsers::AST;
+
+use crate::{
+    NovelData, NovelSettings,
+    messages::{AudioMode, EventHandleNode, EventPlayAudio, EventToggleBacklog},
+    text::parse_text_tags,
+};
+
+const BACKLOG_COLOR: Color = Color::srgba(0.0, 0.0, 0.0, 0.85);
+const BUTTON_COLOR: Color = Color::srgba(0.1, 0.1, 0.1, 0.85);
+const BUTTON_HOVER_COLOR: Color = Color::srgba(0.25, 0.25, 0.25, 0.9);
+
+/// Pixels scrolled per line of mouse wheel
+const SCROLL_LINE_HEIGHT: f32 = 24.0;
+
+/// Say line shown this session
+#[derive(Clone, Debug, PartialEq)]
+pub struct BacklogEntry {
+    /// Name of the speaker as displayed, `None` for narration
+    pub who: Option<String>,
+    /// Dialogue without its text tags
+    pub what: String,
+    /// Voice line played along with it
+    pub voice: Option<String>,
+}
+
+/// Say lines shown this session, oldest first, up to [`NovelSettings::backlog_limit`]
+#[derive(Resource, Default)]
+pub struct NovelBacklog {
+    pub entries: Vec<BacklogEntry>,
+    /// Whether the backlog screen is open, input doesn't reach the story meanwhile
+    pub open: bool,
+}
+
+/// Container of the built-in backlog screen
+#[derive(Component)]
+pub struct NovelBacklogPanel;
+
+/// Button replaying the voice of the backlog entry at the index it holds
+#[derive(Component)]
+pub struct NovelBacklogVoiceButton(pub usize);
+
+/// Adds say lines to the backlog along with the voice given for them with `voice`
+pub fn record_backlog(
+    mut er_handle_node: MessageReader<EventHandleNode>,
+    mut novel_data: ResMut<NovelData>,
+    mut backlog: ResMut<NovelBacklog>,
+    novel_settings: Res<NovelSettings>,
+) {
+    for event in er_handle_node.read() {
+        let AST::Say(_, who, what) = &event.ast else {
+            continue;
+        };
+
+        // Lines brought back by rollback are already in the backlog
+        if novel_data.replaying {
+            continue;
+        }
+
+        // The voice was played by `play_voice`, it belongs to this line only
+        let voice = novel_data.voice.take();
+
+        let character = novel_data
+            .characters
+            .get(who.as_deref().unwrap_or("narrator"));
+
+        let (who, what) = match character {
+            Some(character) => (
+                character.name.as_ref().map(|_| character.display_name()),
+                character.display_what(what),
+            ),
+            None => (who.clone(), what.clone()),
+        };
+
+        backlog.entries.push(BacklogEntry {
+            who,
+            what: parse_text_tags(&what).plain_text(),
+            voice,
+        });
+
+        let excess = backlog
+            .entries
+            .len()
+            .saturating_sub(novel_settings.backlog_limit);
+        backlog.entries.drain(..excess);
+    }
+}
+
+pub fn handle_toggle_backlog(
+    mut commands: Commands,
+    mut er_toggle_backlog: MessageReader<EventToggleBacklog>,
+    mut backlog: ResMut<NovelBacklog>,
+    novel_settings: Res<NovelSettings>,
+    panels: Query<Entity, With<NovelBacklogPanel>>,
+) {
+    for _ in er_toggle_backlog.read() {
+        backlog.open = !backlog.open;
+
+        for entity in panels.iter() {
+            commands.entity(entity).despawn();
+        }
+
+        if !backlog.open || novel_settings.custom_backlog_ui {
+            continue;
+        }
+
+        commands
+            .spawn((
+                NovelBacklogPanel,
+                Name::new("Novel Backlog"),
+                Node {
+                    position_type: PositionType::Absolute,
+                    width: percent(100),
+                    height: percent(100),
+                    flex_direction: FlexDirection::Column,
+                    padding: UiRect::all(px(24)),
+                    row_gap: px(12),
+                    overflow: Overflow::scroll_y(),
+                    ..default()
+                },
+                BackgroundColor(BACKLOG_COLOR),
+                // Opened at the latest lines
+                ScrollPosition(Vec2::new(0.0, f32::MAX)),
+            ))
+            .with_children(|p| {
+                for (index, entry) in backlog.entries.iter().enumerate() {
+                    p.spawn(Node {
+                        flex_direction: FlexDirection::Row,
+                        align_items: AlignItems::Center,
+                        column_gap: px(12),
+                        flex_shrink: 0.0,
+                        ..default()
+                    })
+                    .with_children(|p| {
+                        if entry.voice.is_some() {
+                            p.spawn((
+                                Button,
+                                NovelBacklogVoiceButton(index),
+                                Node {
+                                    padding: UiRect::axes(px(8), px(4)),
+                                    ..default()
+                                },
+                                BackgroundColor(BUTTON_COLOR),
+                            ))
+                            .with_child(Text::new("Voice"));
+                        }
+
+                        let line = match &entry.who {
+                            Some(who) => format!("{}\n{}", who, entry.what),
+                            None => entry.what.clone(),
+                        };
+                        p.spawn(Text::new(line));
+                    });
+                }
+            });
+    }
+}
+
+#[allow(clippy::type_complexity)]
+pub fn handle_backlog_buttons(
+    mut buttons: Query<
+        (&Interaction, &NovelBacklogVoiceButton, &mut BackgroundColor),
+        Changed<Interaction>,
+    >,
+    backlog: Res<NovelBacklog>,
+    mut ew_play_audio: MessageWriter<EventPlayAudio>,
+) {
+    for (interaction, button, mut color) in buttons.iter_mut() {
+        match interaction {
+            Interaction::Pressed => {
+                if let Some(filename) = backlog
+                    .entries
+                    .get(button.0)
+                    .and_then(|entry| entry.voice.clone())
+                {
+                    ew_play_audio.write(EventPlayAudio::new(filename, AudioMode::Voice));
+                }
+            }
+            Interaction::Hovered => {
+                *color = BackgroundColor(BUTTON_HOVER_COLOR);
+            }
+            Interaction::None => {
+                *color = BackgroundColor(BUTTON_COLOR);
+            }
+        }
+    }
+}
+
+pub fn scroll_backlog(
+    mouse_scroll: Res<AccumulatedMouseScroll>,
+    mut panels: Query<&mut ScrollPosition, With<NovelBacklogPanel>>,
+) {
+    if mouse_scroll.delta.y == 0.0 {
+        return;
+    }
+
+    let delta = match mouse_scroll.unit {
+        MouseScrollUnit::Line => mouse_scroll.delta.y * SCROLL_LINE_HEIGHT,
+        MouseScrollUnit::Pixel => mouse_scroll.delta.y,
+    };
+
+    for mut scroll_position in panels.iter_mut() {
+        scroll_position.y = (scroll_position.y - delta).max(0.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn app(backlog_limit: usize) -> App {
+        let mut app = App::new();
+        app.add_message::<EventHandleNode>()
+            .init_resource::<NovelData>()
+            .init_resource::<NovelBacklog>()
+            .insert_resource(NovelSettings {
+                backlog_limit,
+                ..default()
+            })
+            .add_systems(Update, record_backlog);
+        app
+    }
+
+    fn say(app: &mut App, who: Option<&str>, what: &str) {
+        app.world_mut().write_message(EventHandleNode {
+            ast: AST::Say(0, who.map(str::to_string), what.to_string()),
+        });
+        app.update();
+    }
+
+    fn whats(app: &App) -> Vec<String> {
+        app.world()
+            .resource::<NovelBacklog>()
+            .entries
+            .iter()
+            .map(|entry| entry.what.clone())
+            .collect()
+    }
+
+    #[test]
+    fn caps_backlog() {
+        let mut app = app(2);
+
+        say(&mut app, None, "One");
+        say(&mut app, None, "Two");
+        assert_eq!(whats(&app), vec!["One", "Two"]);
+
+        say(&mut app, Some("e"), "{b}Three{/b}");
+        assert_eq!(whats(&app), vec!["Two", "Three"]);
+    }
+
+    #[test]
+    fn records_voice_with_its_line() {
+        let mut app = app(10);
+
+        app.world_mut().resource_mut::<NovelData>().voice = Some("line1.ogg".to_string());
+        say(&mut app, Some("e"), "Hello");
+        say(&mut app, Some("e"), "Again");
+
+        let backlog = app.world().resource::<NovelBacklog>();
+        assert_eq!(
+            backlog.entries,
+            vec![
+                BacklogEntry {
+                    who: Some("e".to_string()),
+                    what: "Hello".to_string(),
+                    voice: Some("line1.ogg".to_string()),
+                },
+                BacklogEntry {
+                    who: Some("e".to_string()),
+                    what: "Again".to_string(),
+                    voice: None,
+                },
+            ]
+        );
+        assert!(app.world().resource::<NovelData>().voice.is_none());
+    }
+
+    #[test]
+    fn skips_replayed_lines() {
+        let mut app = app(10);
+
+        app.world_mut().resource_mut::<NovelData>().replaying = true;
+        say(&mut app, None, "Seen before");
+
+        assert!(whats(&app).is_empty());
+    }
+}
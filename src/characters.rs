@@ -0,0 +1,204 @@
+use std::sync::LazyLock;
+
+use bevy::prelude::*;
+use regex::Regex;
+
+static CHARACTER_DEFINE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^([A-Za-z_][\w.]*)\s*=\s*Character\s*\((.*)\)\s*$").unwrap());
+
+/// Speaker declared with `define name = Character(...)`
+#[derive(Clone, Debug, Default)]
+pub struct Character {
+    /// Name shown in `NovelTextWho`, `None` for `Character(None)`
+    pub name: Option<String>,
+    /// Colour of the name, from `color=` or `who_color=`
+    pub color: Option<Color>,
+    /// Colour of the dialogue, from `what_color=`
+    pub what_color: Option<Color>,
+    pub who_prefix: String,
+    pub who_suffix: String,
+    pub what_prefix: String,
+    pub what_suffix: String,
+    /// Image tag linked to the character, its `side <tag>` image is shown while it speaks
+    pub image: Option<String>,
+}
+
+impl Character {
+    pub fn display_name(&self) -> String {
+        match &self.name {
+            Some(name) => format!("{}{}{}", self.who_prefix, name, self.who_suffix),
+            None => String::new(),
+        }
+    }
+
+    pub fn display_what(&self, what: &str) -> String {
+        format!("{}{}{}", self.what_prefix, what, self.what_suffix)
+    }
+}
+
+/// Parses the body of a `define` statement, returning the variable name and the character
+/// if it is a `Character(...)` declaration.
+pub fn parse_character_define(definition: &str) -> Option<(String, Character)> {
+    let captures = CHARACTER_DEFINE.captures(definition.trim())?;
+
+    let mut character = Character::default();
+
+    for (i, argument) in split_arguments(&captures[2]).into_iter().enumerate() {
+        let (key, value) = match argument.split_once('=') {
+            Some((key, value)) if !key.trim_start().starts_with(['"', '\'']) => {
+                (Some(key.trim()), value.trim())
+            }
+            _ => (None, argument.as_str()),
+        };
+
+        match key {
+            None if i == 0 => character.name = parse_string(value),
+            Some("name") => character.name = parse_string(value),
+            Some("color") | Some("who_color") => character.color = parse_color(value),
+            Some("what_color") => character.what_color = parse_color(value),
+            Some("who_prefix") => character.who_prefix = parse_string(value).unwrap_or_default(),
+            Some("who_suffix") => character.who_suffix = parse_string(value).unwrap_or_default(),
+            Some("what_prefix") => character.what_prefix = parse_string(value).unwrap_or_default(),
+            Some("what_suffix") => character.what_suffix = parse_string(value).unwrap_or_default(),
+            Some("image") => character.image = parse_string(value),
+            _ => warn!("Unsupported Character argument `{}`", argument),
+        }
+    }
+
+    Some((captures[1].to_string(), character))
+}
+
+/// Splits call arguments on commas that are outside of quotes and brackets
+fn split_arguments(arguments: &str) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut current = String::new();
+    let mut delimiter: Option<char> = None;
+    let mut depth = 0;
+
+    for c in arguments.chars() {
+        match (delimiter, c) {
+            (Some(d), _) if c == d => delimiter = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => delimiter = Some(c),
+            (None, '(' | '[' | '{') => depth += 1,
+            (None, ')' | ']' | '}') => depth -= 1,
+            (None, ',') if depth == 0 => {
+                parts.push(current.trim().to_string());
+                current.clear();
+                continue;
+            }
+            _ => {}
+        }
+        current.push(c);
+    }
+
+    if !current.trim().is_empty() {
+        parts.push(current.trim().to_string());
+    }
+
+    parts
+}
+
+fn parse_string(value: &str) -> Option<String> {
+    let value = value.trim();
+    if value == "None" || value.len() < 2 {
+        return None;
+    }
+
+    let quote = value.chars().next()?;
+    if (quote == '"' || quote == '\'') && value.ends_with(quote) {
+        Some(value[1..value.len() - 1].to_string())
+    } else {
+        None
+    }
+}
+
+fn parse_color(value: &str) -> Option<Color> {
+    let hex = parse_string(value)?;
+    match Srgba::hex(&hex) {
+        Ok(color) => Some(color.into()),
+        Err(err) => {
+            warn!("Invalid colour {}: {}", hex, err);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(definition: &str) -> Character {
+        let (_, character) = parse_character_define(definition).unwrap();
+        character
+    }
+
+    #[test]
+    fn names() {
+        let (variable, character) = parse_character_define(r#"e = Character("Eileen")"#).unwrap();
+        assert_eq!(variable, "e");
+        assert_eq!(character.name.as_deref(), Some("Eileen"));
+        assert_eq!(character.display_name(), "Eileen");
+
+        let narrator = parse("narrator = Character(None)");
+        assert_eq!(narrator.name, None);
+        assert_eq!(narrator.display_name(), "");
+
+        let named = parse(r#"e = Character(name='Eileen')"#);
+        assert_eq!(named.name.as_deref(), Some("Eileen"));
+    }
+
+    #[test]
+    fn colors() {
+        let red = Some(Color::srgb(1.0, 0.0, 0.0));
+        let green = Some(Color::srgb(0.0, 1.0, 0.0));
+
+        let character = parse(r##"e = Character("Eileen", color="#ff0000")"##);
+        assert_eq!(character.color, red);
+        assert_eq!(character.what_color, None);
+
+        let character = parse(r##"e = Character("Eileen", who_color="#00ff00")"##);
+        assert_eq!(character.color, green);
+
+        // Both set the colour of the name, the last one wins
+        let character = parse(
+            r##"e = Character("Eileen", color="#ff0000", who_color="#00ff00", what_color="#ff0000")"##,
+        );
+        assert_eq!(character.color, green);
+        assert_eq!(character.what_color, red);
+    }
+
+    #[test]
+    fn quoted_commas() {
+        let character =
+            parse(r#"j = Character("Doe, Jane", who_suffix=", said", what_prefix='"')"#);
+
+        assert_eq!(character.name.as_deref(), Some("Doe, Jane"));
+        assert_eq!(character.display_name(), "Doe, Jane, said");
+        assert_eq!(character.display_what("Hi"), "\"Hi");
+    }
+
+    #[test]
+    fn image() {
+        let character = parse(r#"e = Character("Eileen", image="eileen")"#);
+        assert_eq!(character.image.as_deref(), Some("eileen"));
+
+        assert_eq!(parse(r#"e = Character("Eileen")"#).image, None);
+    }
+
+    #[test]
+    fn other_defines() {
+        for definition in [
+            "points = 5",
+            r#"config.name = "Game""#,
+            r#"e = Characters("Eileen")"#,
+            r#"e = "Character(Eileen)""#,
+        ] {
+            assert!(
+                parse_character_define(definition).is_none(),
+                "{}",
+                definition
+            );
+        }
+    }
+}
@@ -0,0 +1,1298 @@
+//! Dialogue, backgrounds and music made at runtime for `llm_generate`, `scene_generate` and
+//! `music_generate` statements.
+//!
+//! A [`NovelTextGenerator`] gets the prompt of the statement along with the recent backlog
+//! and the script variables, and the line it returns is injected after the statement with
+//! [`NovelData::push_text_node`]. A generator failing or taking longer than
+//! [`NovelSettings::generation_timeout`] is replaced by
+//! [`NovelSettings::generation_fallback`].
+//!
+//! A [`NovelImageGenerator`] makes the background of a `scene_generate` statement, which is
+//! cached with [`NovelData::write_image_cache`] and shown by an injected `scene`. The story
+//! waits behind a placeholder meanwhile. A [`NovelMusicGenerator`] makes the music of a
+//! `music_generate` statement in the background, played once it is ready. Generated
+//! backgrounds and music are written to the `generated` folder of the save directory, named
+//! after their content, and saves refer to them by path.
+//!
+//! Injected nodes are recorded in [`NovelData::generated`] and saved along with the game, so
+//! that loading it injects them again before its indices are used.
+//!
+//! Generators run on the `AsyncComputeTaskPool` rather than the `bevy_defer` executor:
+//! `bevy_defer` 0.17 is built against bevy 0.18, so its plugin can't be added to a bevy 0.19
+//! app. A generator should wait on IO asynchronously instead of blocking a thread of the
+//! pool, the ones shipped here do. A generator that times out is cancelled by dropping its
+//! task.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::future::Future;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+
+use bevy::{
+    asset::RenderAssetUsages,
+    image::{CompressedImageFormats, ImageFormat, ImageSampler, ImageType},
+    prelude::*,
+    tasks::{AsyncComputeTaskPool, Task, block_on, futures_lite::future},
+};
+use bevy_kira_audio::prelude::*;
+use renpy_parser::parsers::AST;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    NovelData, NovelSettings,
+    backlog::{BacklogEntry, NovelBacklog},
+    messages::{AudioMode, EventHandleNode, EventPlayAudio, EventSwitchNextNode},
+    save::{NovelSaveState, fnv1a, write_atomic},
+    variables::NovelVariables,
+};
+
+/// Backlog lines handed to generators
+const CONTEXT_LINES: usize = 10;
+
+/// Possible errors that can be produced by a [`NovelTextGenerator`]
+#[non_exhaustive]
+#[derive(Debug, Error)]
+pub enum NovelGenerateError {
+    #[error("Generation took longer than {0} seconds")]
+    Timeout(f32),
+    #[error("Could not reach the generator: {0}")]
+    Request(String),
+    #[error("Could not read the generated text: {0}")]
+    InvalidResponse(String),
+    /// An [IO](std::io) Error
+    #[error("Could not read the generated file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Nothing matches `{0}`")]
+    NoMatch(String),
+    #[error("Could not write the generated file: {0}")]
+    Write(String),
+}
+
+/// Node injected after a generating statement
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum GeneratedNode {
+    /// Line of an `llm_generate` statement
+    Line { character: String, text: String },
+    /// Background of a `scene_generate` statement, the path of the file it was written to
+    Scene { image: String },
+}
+
+impl GeneratedNode {
+    fn ast(&self, index: usize) -> AST {
+        match self.clone() {
+            GeneratedNode::Line { character, text } => AST::Say(index, Some(character), text),
+            GeneratedNode::Scene { image } => AST::Scene(index, Some(image), "master".into()),
+        }
+    }
+}
+
+/// What a generator knows about the story when it is asked for a line
+#[derive(Clone, Debug)]
+pub struct TextGenerationContext {
+    /// Character the line is said by, as written in the script
+    pub character: String,
+    /// Display name of the character, from its `define`
+    pub character_name: Option<String>,
+    pub prompt: Option<String>,
+    /// Latest backlog lines, oldest first
+    pub backlog: Vec<BacklogEntry>,
+    pub variables: NovelVariables,
+}
+
+pub type GenerateFuture<T> = Pin<Box<dyn Future<Output = Result<T, NovelGenerateError>> + Send>>;
+
+/// Writes the line said at an `llm_generate` statement
+pub trait NovelTextGenerator: Send + Sync + 'static {
+    fn generate(&self, context: TextGenerationContext) -> GenerateFuture<String>;
+}
+
+/// Generator used for `llm_generate` statements, [`TemplateTextGenerator`] by default
+#[derive(Resource, Clone)]
+pub struct NovelTextGeneration(pub Arc<dyn NovelTextGenerator>);
+
+impl Default for NovelTextGeneration {
+    fn default() -> Self {
+        NovelTextGeneration(Arc::new(TemplateTextGenerator::default()))
+    }
+}
+
+impl NovelTextGeneration {
+    pub fn new(generator: impl NovelTextGenerator) -> Self {
+        NovelTextGeneration(Arc::new(generator))
+    }
+}
+
+/// Offline generator filling in one of its templates, picked from the character, prompt and
+/// backlog length so that the same story gets the same line every time.
+///
+/// Templates can use `{character}`, `{prompt}` and `{last}`, the text of the latest backlog
+/// line.
+#[derive(Clone, Debug)]
+pub struct TemplateTextGenerator {
+    pub templates: Vec<String>,
+}
+
+impl Default for TemplateTextGenerator {
+    fn default() -> Self {
+        TemplateTextGenerator {
+            templates: vec![
+                "{prompt}".to_string(),
+                "I keep thinking about {prompt}.".to_string(),
+                "...".to_string(),
+            ],
+        }
+    }
+}
+
+impl TemplateTextGenerator {
+    pub fn fill(&self, context: &TextGenerationContext) -> String {
+        if self.templates.is_empty() {
+            return String::new();
+        }
+
+        let prompt = context.prompt.as_deref().unwrap_or_default();
+        let seed_bytes: Vec<u8> = context
+            .character
+            .bytes()
+            .chain([0])
+            .chain(prompt.bytes())
+            .collect();
+        let seed = fnv1a(&seed_bytes).wrapping_add(context.backlog.len() as u64);
+        let template = &self.templates[(seed % self.templates.len() as u64) as usize];
+
+        let last = context
+            .backlog
+            .last()
+            .map(|entry| entry.what.as_str())
+            .unwrap_or_default();
+        let character = context
+            .character_name
+            .as_deref()
+            .unwrap_or(&context.character);
+
+        template
+            .replace("{character}", character)
+            .replace("{prompt}", prompt)
+            .replace("{last}", last)
+    }
+}
+
+impl NovelTextGenerator for TemplateTextGenerator {
+    fn generate(&self, context: TextGenerationContext) -> GenerateFuture<String> {
+        let line = self.fill(&context);
+        Box::pin(async move { Ok(line) })
+    }
+}
+
+/// Generator asking an OpenAI compatible chat completions endpoint, such as a local
+/// llama.cpp or Ollama server. Only `http://` URLs are supported, and the request needs a
+/// native target.
+#[cfg(feature = "openai")]
+#[derive(Clone, Debug)]
+pub struct OpenAiTextGenerator {
+    /// Chat completions URL, such as `http://localhost:8080/v1/chat/completions`
+    pub url: String,
+    pub model: String,
+    pub api_key: Option<String>,
+    /// Instructions sent ahead of the story
+    pub system_prompt: String,
+    pub max_tokens: u32,
+}
+
+#[cfg(feature = "openai")]
+impl OpenAiTextGenerator {
+    pub fn new(url: impl Into<String>, model: impl Into<String>) -> Self {
+        OpenAiTextGenerator {
+            url: url.into(),
+            model: model.into(),
+            api_key: None,
+            system_prompt: "You write the next line of a visual novel. Answer with the line \
+                            only, without the name of the speaker or quotes."
+                .to_string(),
+            max_tokens: 120,
+        }
+    }
+
+    fn request_body(&self, context: &TextGenerationContext) -> String {
+        let character = context
+            .character_name
+            .as_deref()
+            .unwrap_or(&context.character);
+
+        let mut story = String::new();
+        for entry in context.backlog.iter() {
+            match &entry.who {
+                Some(who) => story.push_str(&format!("{}: {}\n", who, entry.what)),
+                None => story.push_str(&format!("{}\n", entry.what)),
+            }
+        }
+
+        let variables: Vec<String> = context
+            .variables
+            .iter()
+            .map(|(name, value)| format!("{} = {}", name, value))
+            .collect();
+
+        let mut user = format!("Story so far:\n{}\n", story);
+        if !variables.is_empty() {
+            user.push_str(&format!("Variables: {}\n", variables.join(", ")));
+        }
+        user.push_str(&format!("Write the next line said by {}.", character));
+        if let Some(prompt) = &context.prompt {
+            user.push_str(&format!(" {}", prompt));
+        }
+
+        serde_json::json!({
+            "model": self.model,
+            "max_tokens": self.max_tokens,
+            "messages": [
+                { "role": "system", "content": self.system_prompt },
+                { "role": "user", "content": user },
+            ],
+        })
+        .to_string()
+    }
+}
+
+#[cfg(feature = "openai")]
+impl NovelTextGenerator for OpenAiTextGenerator {
+    fn generate(&self, context: TextGenerationContext) -> GenerateFuture<String> {
+        let body = self.request_body(&context);
+        let url = self.url.clone();
+        let api_key = self.api_key.clone();
+
+        Box::pin(async move {
+            let response = crate::http::post_json(&url, api_key.as_deref(), &body).await?;
+
+            let response: serde_json::Value = serde_json::from_str(&response)
+                .map_err(|err| NovelGenerateError::InvalidResponse(err.to_string()))?;
+
+            response["choices"][0]["message"]["content"]
+                .as_str()
+                .map(|line| line.trim().trim_matches('"').to_string())
+                .ok_or_else(|| {
+                    NovelGenerateError::InvalidResponse("no choices[0].message.content".to_string())
+                })
+        })
+    }
+}
+
+/// Makes the background of a `scene_generate` statement from its prompt
+pub trait NovelImageGenerator: Send + Sync + 'static {
+    fn generate(&self, prompt: String) -> GenerateFuture<Image>;
+}
+
+/// Makes the music of a `music_generate` statement from its prompt, as the bytes of an ogg,
+/// mp3 or wav file
+pub trait NovelMusicGenerator: Send + Sync + 'static {
+    fn generate(&self, prompt: String) -> GenerateFuture<Vec<u8>>;
+}
+
+/// Generator used for `scene_generate` statements, they are skipped when it is `None`
+#[derive(Resource, Clone, Default)]
+pub struct NovelSceneGeneration(pub Option<Arc<dyn NovelImageGenerator>>);
+
+impl NovelSceneGeneration {
+    pub fn new(generator: impl NovelImageGenerator) -> Self {
+        NovelSceneGeneration(Some(Arc::new(generator)))
+    }
+}
+
+/// Generator used for `music_generate` statements, they are skipped when it is `None`
+#[derive(Resource, Clone, Default)]
+pub struct NovelMusicGeneration(pub Option<Arc<dyn NovelMusicGenerator>>);
+
+impl NovelMusicGeneration {
+    pub fn new(generator: impl NovelMusicGenerator) -> Self {
+        NovelMusicGeneration(Some(Arc::new(generator)))
+    }
+}
+
+const IMAGE_EXTENSIONS: [&str; 3] = ["png", "jpg", "jpeg"];
+const AUDIO_EXTENSIONS: [&str; 4] = ["ogg", "mp3", "wav", "flac"];
+
+/// Offline image and music generator picking the file of `directory` whose name shares the
+/// most words with the prompt
+#[derive(Clone, Debug)]
+pub struct DirectoryGenerator {
+    pub directory: PathBuf,
+}
+
+impl DirectoryGenerator {
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        DirectoryGenerator {
+            directory: directory.into(),
+        }
+    }
+
+    /// Best match for `prompt` among the files with one of `extensions`, by name when tied
+    pub fn pick(&self, prompt: &str, extensions: &[&str]) -> Option<PathBuf> {
+        let words = |text: &str| -> Vec<String> {
+            text.split(|c: char| !c.is_alphanumeric())
+                .filter(|word| !word.is_empty())
+                .map(str::to_lowercase)
+                .collect()
+        };
+        let keywords = words(prompt);
+
+        let mut files: Vec<PathBuf> = fs::read_dir(&self.directory)
+            .ok()?
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|path| {
+                path.extension()
+                    .and_then(|extension| extension.to_str())
+                    .is_some_and(|extension| {
+                        extensions.contains(&extension.to_lowercase().as_str())
+                    })
+            })
+            .collect();
+        files.sort();
+
+        files
+            .into_iter()
+            .map(|path| {
+                let name = path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .unwrap_or_default();
+                let score = words(name)
+                    .iter()
+                    .filter(|word| keywords.contains(word))
+                    .count();
+                (score, path)
+            })
+            // max_by_key keeps the last maximum, reversing keeps the first by name
+            .rev()
+            .max_by_key(|(score, _)| *score)
+            .map(|(_, path)| path)
+    }
+
+    fn read(
+        &self,
+        prompt: &str,
+        extensions: &[&str],
+    ) -> Result<(PathBuf, Vec<u8>), NovelGenerateError> {
+        let path = self
+            .pick(prompt, extensions)
+            .ok_or_else(|| NovelGenerateError::NoMatch(prompt.to_string()))?;
+        let bytes = fs::read(&path)?;
+
+        Ok((path, bytes))
+    }
+}
+
+fn decode_image(path: &Path, bytes: &[u8]) -> Result<Image, NovelGenerateError> {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or("png");
+
+    Image::from_buffer(
+        bytes,
+        ImageType::Extension(extension),
+        CompressedImageFormats::NONE,
+        true,
+        ImageSampler::Default,
+        RenderAssetUsages::default(),
+    )
+    .map_err(|err| NovelGenerateError::InvalidResponse(err.to_string()))
+}
+
+impl NovelImageGenerator for DirectoryGenerator {
+    fn generate(&self, prompt: String) -> GenerateFuture<Image> {
+        let generator = self.clone();
+        Box::pin(async move {
+            let (path, bytes) = generator.read(&prompt, &IMAGE_EXTENSIONS)?;
+            decode_image(&path, &bytes)
+        })
+    }
+}
+
+impl NovelMusicGenerator for DirectoryGenerator {
+    fn generate(&self, prompt: String) -> GenerateFuture<Vec<u8>> {
+        let generator = self.clone();
+        Box::pin(async move { Ok(generator.read(&prompt, &AUDIO_EXTENSIONS)?.1) })
+    }
+}
+
+/// Generation running on the `AsyncComputeTaskPool`, cancelled when dropped
+struct GenerationTask<T> {
+    task: Task<Result<T, NovelGenerateError>>,
+    elapsed: f32,
+}
+
+impl<T: Send + 'static> GenerationTask<T> {
+    fn new(future: GenerateFuture<T>) -> Self {
+        GenerationTask {
+            task: AsyncComputeTaskPool::get().spawn(future),
+            elapsed: 0.0,
+        }
+    }
+
+    /// The result once the generator is done or has taken longer than `timeout` seconds
+    fn poll(&mut self, delta: f32, timeout: f32) -> Option<Result<T, NovelGenerateError>> {
+        self.elapsed += delta;
+
+        match block_on(future::poll_once(&mut self.task)) {
+            Some(result) => Some(result),
+            None if self.elapsed > timeout => Some(Err(NovelGenerateError::Timeout(timeout))),
+            None => None,
+        }
+    }
+}
+
+/// `generated` with the keys moved along with a node injected at `index`
+pub(crate) fn shift_generated(
+    generated: &BTreeMap<usize, GeneratedNode>,
+    index: usize,
+) -> BTreeMap<usize, GeneratedNode> {
+    generated
+        .iter()
+        .map(|(generated_index, node)| {
+            let generated_index = if *generated_index >= index {
+                generated_index + 1
+            } else {
+                *generated_index
+            };
+            (generated_index, node.clone())
+        })
+        .collect()
+}
+
+/// Injects `node` at `index`, or replaces the node injected there on an earlier visit of
+/// its statement. The indices the story keeps are shifted the way the script was.
+fn inject_generated(novel_data: &mut NovelData, index: usize, node: GeneratedNode) {
+    if novel_data.generated.contains_key(&index) {
+        novel_data.replace_node(node.ast(index));
+        novel_data.generated.insert(index, node);
+        return;
+    }
+
+    match node.clone() {
+        GeneratedNode::Line { character, text } => {
+            novel_data.push_text_node(Some(character), text, index)
+        }
+        GeneratedNode::Scene { image } => novel_data.push_scene_node(image, index),
+    }
+
+    for call_index in novel_data.call_stack.iter_mut() {
+        if *call_index >= index {
+            *call_index += 1;
+        }
+    }
+    for state in novel_data
+        .history
+        .iter_mut()
+        .chain(novel_data.rolled_back.iter_mut())
+    {
+        state.shift_indices(index);
+    }
+
+    novel_data.generated = shift_generated(&novel_data.generated, index);
+    novel_data.generated.insert(index, node);
+}
+
+/// Folder of the save directory generated backgrounds and music are written to
+pub fn generated_directory(novel_settings: &NovelSettings) -> PathBuf {
+    novel_settings.save_directory.join("generated")
+}
+
+/// Writes generated content to `directory`, named after a hash of it so that a file is never
+/// overwritten by other content, and returns the path it was written to
+fn write_generated(
+    directory: &Path,
+    prefix: &str,
+    extension: &str,
+    bytes: &[u8],
+) -> Result<String, NovelGenerateError> {
+    let path = directory.join(format!("{}_{:016x}.{}", prefix, fnv1a(bytes), extension));
+    if !path.exists() {
+        write_atomic(&path, bytes).map_err(|err| NovelGenerateError::Write(err.to_string()))?;
+    }
+
+    Ok(path.to_string_lossy().into_owned())
+}
+
+fn encode_png(image: Image) -> Result<Vec<u8>, NovelGenerateError> {
+    let format = ImageFormat::Png
+        .as_image_crate_format()
+        .ok_or_else(|| NovelGenerateError::Write("png is not supported".to_string()))?;
+
+    let mut bytes = Vec::new();
+    image
+        .try_into_dynamic()
+        .map_err(|err| NovelGenerateError::Write(err.to_string()))?
+        .write_to(&mut Cursor::new(&mut bytes), format)
+        .map_err(|err| NovelGenerateError::Write(err.to_string()))?;
+
+    Ok(bytes)
+}
+
+/// Extension of an ogg, wav or flac file, mp3 otherwise
+fn audio_extension(bytes: &[u8]) -> &'static str {
+    if bytes.starts_with(b"OggS") {
+        "ogg"
+    } else if bytes.starts_with(b"RIFF") {
+        "wav"
+    } else if bytes.starts_with(b"fLaC") {
+        "flac"
+    } else {
+        "mp3"
+    }
+}
+
+fn decode_audio(bytes: Vec<u8>) -> Result<StaticSoundData, NovelGenerateError> {
+    StaticSoundData::from_cursor(Cursor::new(bytes))
+        .map_err(|err| NovelGenerateError::InvalidResponse(err.to_string()))
+}
+
+/// Caches the generated backgrounds and music `state` refers to, read back from the files
+/// they were written to when they were made in another session
+pub fn load_generated_assets(
+    state: &NovelSaveState,
+    novel_data: &mut NovelData,
+    novel_settings: &NovelSettings,
+    images: &mut Assets<Image>,
+    audio_sources: &mut Assets<AudioSource>,
+) {
+    let directory = generated_directory(novel_settings);
+    let is_generated = |name: &&String| Path::new(name.as_str()).starts_with(&directory);
+
+    let scenes = state.generated.values().filter_map(|node| match node {
+        GeneratedNode::Scene { image } => Some(image),
+        GeneratedNode::Line { .. } => None,
+    });
+    for name in state.background.iter().chain(scenes).filter(is_generated) {
+        if novel_data.cached_images.contains_key(name) {
+            continue;
+        }
+
+        let path = Path::new(name);
+        match fs::read(path)
+            .map_err(NovelGenerateError::from)
+            .and_then(|bytes| decode_image(path, &bytes))
+        {
+            Ok(image) => {
+                novel_data.write_image_cache(name.clone(), Sprite::from_image(images.add(image)))
+            }
+            Err(err) => warn!("Generated background {}: {}", name, err),
+        }
+    }
+
+    for name in state.music.iter().filter(is_generated) {
+        if novel_data.cached_audio.contains_key(name) {
+            continue;
+        }
+
+        match fs::read(name)
+            .map_err(NovelGenerateError::from)
+            .and_then(decode_audio)
+        {
+            Ok(sound) => {
+                let handle = audio_sources.add(AudioSource { sound });
+                novel_data.cached_audio.insert(name.clone(), handle);
+            }
+            Err(err) => warn!("Generated music {}: {}", name, err),
+        }
+    }
+}
+
+/// Line being generated for an `llm_generate` statement
+#[derive(Component)]
+pub struct NovelTextGenerationTask {
+    task: GenerationTask<String>,
+    character: String,
+    /// Index the line is injected at
+    index: usize,
+}
+
+/// Asks the [`NovelTextGeneration`] generator for the line of `llm_generate` statements, the
+/// story waits until it has been injected
+pub fn handle_generate_text(
+    mut commands: Commands,
+    mut er_handle_node: MessageReader<EventHandleNode>,
+    mut novel_data: ResMut<NovelData>,
+    generation: Res<NovelTextGeneration>,
+    backlog: Res<NovelBacklog>,
+    variables: Res<NovelVariables>,
+) {
+    for event in er_handle_node.read() {
+        let AST::LLMGenerate(index, character, prompt) = &event.ast else {
+            continue;
+        };
+
+        let context = TextGenerationContext {
+            character: character.clone(),
+            character_name: novel_data
+                .characters
+                .get(character)
+                .and_then(|c| c.name.clone()),
+            prompt: prompt.clone(),
+            backlog: backlog
+                .entries
+                .iter()
+                .rev()
+                .take(CONTEXT_LINES)
+                .rev()
+                .cloned()
+                .collect(),
+            variables: variables.clone(),
+        };
+
+        novel_data.generating = true;
+
+        commands.spawn((
+            Name::new("Novel Text Generation"),
+            NovelTextGenerationTask {
+                task: GenerationTask::new(generation.0.generate(context)),
+                character: character.clone(),
+                index: index + 1,
+            },
+        ));
+    }
+}
+
+/// Injects generated lines, or the fallback line once the generator fails or times out
+pub fn poll_generate_text(
+    mut commands: Commands,
+    mut tasks: Query<(Entity, &mut NovelTextGenerationTask)>,
+    mut novel_data: ResMut<NovelData>,
+    novel_settings: Res<NovelSettings>,
+    time: Res<Time>,
+    mut ew_switch_next_node: MessageWriter<EventSwitchNextNode>,
+) {
+    for (entity, mut generation) in tasks.iter_mut() {
+        let Some(result) = generation
+            .task
+            .poll(time.delta_secs(), novel_settings.generation_timeout)
+        else {
+            continue;
+        };
+
+        // Dropping the task cancels a generator still running after a timeout
+        commands.entity(entity).despawn();
+
+        let line = match result {
+            Ok(line) => line,
+            Err(err) => {
+                warn!("llm_generate for {}: {}", generation.character, err);
+                novel_settings.generation_fallback.clone()
+            }
+        };
+
+        inject_generated(
+            &mut novel_data,
+            generation.index,
+            GeneratedNode::Line {
+                character: generation.character.clone(),
+                text: line,
+            },
+        );
+        novel_data.generating = false;
+
+        ew_switch_next_node.write(EventSwitchNextNode {});
+    }
+}
+
+/// "Generating..." label shown while the story waits on a generated background
+#[derive(Component)]
+pub struct NovelGenerationPlaceholder;
+
+/// Background being generated for a `scene_generate` statement
+#[derive(Component)]
+pub struct NovelSceneGenerationTask {
+    task: GenerationTask<Image>,
+    /// Index the `scene` is injected at
+    index: usize,
+}
+
+/// Music being generated for a `music_generate` statement
+#[derive(Component)]
+pub struct NovelMusicGenerationTask {
+    task: GenerationTask<Vec<u8>>,
+}
+
+/// Generations running and the placeholder shown meanwhile
+pub type NovelGenerationEntities<'w, 's> = Query<
+    'w,
+    's,
+    Entity,
+    Or<(
+        With<NovelTextGenerationTask>,
+        With<NovelSceneGenerationTask>,
+        With<NovelMusicGenerationTask>,
+        With<NovelGenerationPlaceholder>,
+    )>,
+>;
+
+/// Drops the generations running when the story is moved elsewhere, by a rollback, a load or
+/// another scenario: the nodes they make would land at indices that mean something else by
+/// then. The story doesn't wait on them anymore.
+pub fn cancel_generations(
+    commands: &mut Commands,
+    generations: &NovelGenerationEntities,
+    novel_data: &mut NovelData,
+) {
+    for entity in generations.iter() {
+        commands.entity(entity).despawn();
+    }
+
+    novel_data.generating = false;
+}
+
+pub fn handle_generate_scene(
+    mut commands: Commands,
+    mut er_handle_node: MessageReader<EventHandleNode>,
+    mut novel_data: ResMut<NovelData>,
+    generation: Res<NovelSceneGeneration>,
+    mut ew_switch_next_node: MessageWriter<EventSwitchNextNode>,
+) {
+    for event in er_handle_node.read() {
+        let AST::SceneGenerate(index, prompt) = &event.ast else {
+            continue;
+        };
+
+        let Some(generator) = generation.0.as_ref() else {
+            warn!("scene_generate without a NovelSceneGeneration generator");
+            ew_switch_next_node.write(EventSwitchNextNode {});
+            continue;
+        };
+
+        novel_data.generating = true;
+
+        commands.spawn((
+            Name::new("Novel Scene Generation"),
+            NovelSceneGenerationTask {
+                task: GenerationTask::new(generator.generate(prompt.clone())),
+                index: index + 1,
+            },
+        ));
+
+        commands.spawn((
+            Name::new("Generation Placeholder"),
+            Text::new("Generating..."),
+            NovelGenerationPlaceholder,
+            Node {
+                position_type: PositionType::Absolute,
+                top: percent(50),
+                width: percent(100),
+                justify_content: JustifyContent::Center,
+                ..default()
+            },
+        ));
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn poll_generate_scene(
+    mut commands: Commands,
+    mut tasks: Query<(Entity, &mut NovelSceneGenerationTask)>,
+    placeholders: Query<Entity, With<NovelGenerationPlaceholder>>,
+    mut images: ResMut<Assets<Image>>,
+    mut novel_data: ResMut<NovelData>,
+    novel_settings: Res<NovelSettings>,
+    time: Res<Time>,
+    mut ew_switch_next_node: MessageWriter<EventSwitchNextNode>,
+) {
+    for (entity, mut generation) in tasks.iter_mut() {
+        let Some(result) = generation
+            .task
+            .poll(time.delta_secs(), novel_settings.generation_timeout)
+        else {
+            continue;
+        };
+
+        commands.entity(entity).despawn();
+        for placeholder in placeholders.iter() {
+            commands.entity(placeholder).despawn();
+        }
+
+        novel_data.generating = false;
+
+        let directory = generated_directory(&novel_settings);
+        let written = result.and_then(|image| {
+            let path = write_generated(&directory, "scene", "png", &encode_png(image.clone())?)?;
+            Ok((path, image))
+        });
+
+        match written {
+            Ok((path, image)) => {
+                let sprite = Sprite::from_image(images.add(image));
+                novel_data.write_image_cache(path.clone(), sprite);
+                inject_generated(
+                    &mut novel_data,
+                    generation.index,
+                    GeneratedNode::Scene { image: path },
+                );
+            }
+            Err(err) => warn!("scene_generate: {}", err),
+        }
+
+        ew_switch_next_node.write(EventSwitchNextNode {});
+    }
+}
+
+pub fn handle_generate_music(
+    mut commands: Commands,
+    mut er_handle_node: MessageReader<EventHandleNode>,
+    generation: Res<NovelMusicGeneration>,
+) {
+    for event in er_handle_node.read() {
+        let AST::MusicGenerate(_, prompt) = &event.ast else {
+            continue;
+        };
+
+        let Some(generator) = generation.0.as_ref() else {
+            warn!("music_generate without a NovelMusicGeneration generator");
+            continue;
+        };
+
+        commands.spawn((
+            Name::new("Novel Music Generation"),
+            NovelMusicGenerationTask {
+                task: GenerationTask::new(generator.generate(prompt.clone())),
+            },
+        ));
+    }
+}
+
+pub fn poll_generate_music(
+    mut commands: Commands,
+    mut tasks: Query<(Entity, &mut NovelMusicGenerationTask)>,
+    mut audio_sources: ResMut<Assets<AudioSource>>,
+    mut novel_data: ResMut<NovelData>,
+    novel_settings: Res<NovelSettings>,
+    time: Res<Time>,
+    mut ew_play_audio: MessageWriter<EventPlayAudio>,
+) {
+    for (entity, mut generation) in tasks.iter_mut() {
+        let Some(result) = generation
+            .task
+            .poll(time.delta_secs(), novel_settings.generation_timeout)
+        else {
+            continue;
+        };
+
+        commands.entity(entity).despawn();
+
+        let directory = generated_directory(&novel_settings);
+        let written = result.and_then(|bytes| {
+            let path = write_generated(&directory, "music", audio_extension(&bytes), &bytes)?;
+            Ok((path, decode_audio(bytes)?))
+        });
+
+        match written {
+            Ok((path, sound)) => {
+                let handle = audio_sources.add(AudioSource { sound });
+                novel_data.cached_audio.insert(path.clone(), handle);
+
+                ew_play_audio.write(EventPlayAudio::new(path, AudioMode::Music));
+            }
+            Err(err) => warn!("music_generate: {}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use renpy_parser::parse_scenario_from_string;
+
+    use super::*;
+    use crate::{
+        MusicHandle, find_element_with_index,
+        messages::{EventRestoreState, EventRollback, handle_restore_state},
+        rollback::handle_rollback,
+        rpy_asset_loader::parse_script,
+        save::test_directory,
+        script::Statements,
+        text::NovelTextReveal,
+        transitions::NovelTransitionState,
+    };
+
+    const SCRIPT: &str = r#"label start:
+    e "Before"
+    llm_generate e "a greeting"
+    "After"
+    return
+"#;
+
+    const TWO_LABELS: &str = r#"label start:
+    llm_generate e "a greeting"
+    jump two
+
+label two:
+    $ points = 1
+    "In two"
+    return
+"#;
+
+    fn context(backlog: &[&str]) -> TextGenerationContext {
+        TextGenerationContext {
+            character: "e".to_string(),
+            character_name: Some("Eileen".to_string()),
+            prompt: Some("the weather".to_string()),
+            backlog: backlog
+                .iter()
+                .map(|what| BacklogEntry {
+                    who: None,
+                    what: what.to_string(),
+                    voice: None,
+                })
+                .collect(),
+            variables: NovelVariables::default(),
+        }
+    }
+
+    fn novel_data() -> NovelData {
+        let (ast, _) = parse_scenario_from_string(SCRIPT, "script.rpy").unwrap();
+        let mut novel_data = NovelData::default();
+        novel_data.set_scenario(ast, Statements::default());
+        novel_data
+    }
+
+    /// Index and text of every say line of the labels, in script order
+    fn says(ast: &[AST]) -> Vec<(usize, String)> {
+        ast.iter()
+            .filter_map(|node| match node {
+                AST::Label(_, _, label_ast, _) => Some(label_ast),
+                _ => None,
+            })
+            .flatten()
+            .filter_map(|node| match node {
+                AST::Say(index, _, what) => Some((*index, what.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn two_labels() -> NovelData {
+        let (ast, statements) = parse_script(TWO_LABELS, Path::new("script.rpy")).unwrap();
+        let mut novel_data = NovelData::default();
+        novel_data.set_scenario(ast, statements);
+        novel_data
+    }
+
+    /// Node at `index`, inside labels or not
+    fn node(ast: &[AST], index: usize) -> Option<AST> {
+        find_element_with_index(ast.to_vec(), index).or_else(|| {
+            ast.iter().find_map(|label| match label {
+                AST::Label(_, _, label_ast, _) => node(label_ast, index),
+                _ => None,
+            })
+        })
+    }
+
+    fn line(text: &str) -> GeneratedNode {
+        GeneratedNode::Line {
+            character: "e".to_string(),
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn rolling_back_cancels_generation() {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, AssetPlugin::default()))
+            .init_asset::<AudioInstance>()
+            .add_message::<EventRollback>()
+            .add_message::<EventRestoreState>()
+            .add_message::<EventPlayAudio>()
+            .add_message::<EventSwitchNextNode>()
+            .init_resource::<NovelVariables>()
+            .init_resource::<NovelTransitionState>()
+            .init_resource::<NovelTextReveal>()
+            .insert_resource(NovelSettings::default())
+            .insert_resource(MusicHandle(None))
+            .add_systems(
+                Update,
+                (handle_rollback, handle_restore_state, poll_generate_text).chain(),
+            );
+
+        // Waiting on the line of the llm_generate after "Before"
+        let mut novel_data = novel_data();
+        for current_index in [2, 3] {
+            novel_data.history.push_back(NovelSaveState {
+                current_index,
+                ..default()
+            });
+        }
+        novel_data.current_index = 3;
+        novel_data.generating = true;
+        app.insert_resource(novel_data);
+
+        app.world_mut().spawn(NovelTextGenerationTask {
+            task: GenerationTask::new(Box::pin(future::pending())),
+            character: "e".to_string(),
+            index: 4,
+        });
+        app.world_mut().spawn(NovelGenerationPlaceholder);
+
+        app.world_mut().write_message(EventRollback {});
+        app.update();
+
+        let world = app.world_mut();
+        assert_eq!(
+            world
+                .query_filtered::<Entity, Or<(
+                    With<NovelTextGenerationTask>,
+                    With<NovelGenerationPlaceholder>,
+                )>>()
+                .iter(world)
+                .count(),
+            0
+        );
+
+        let novel_data = world.resource::<NovelData>();
+        assert!(!novel_data.generating);
+        assert!(novel_data.generated.is_empty());
+        // The line rolled back to is handled again
+        assert_eq!(novel_data.current_index, 1);
+        assert_eq!(says(&novel_data.ast), says(&self::novel_data().ast));
+    }
+
+    #[test]
+    fn fills_templates() {
+        let generator = TemplateTextGenerator {
+            templates: vec!["{character} on {prompt} after \"{last}\"".to_string()],
+        };
+        assert_eq!(
+            generator.fill(&context(&["Hello", "Hi"])),
+            "Eileen on the weather after \"Hi\""
+        );
+
+        let mut unnamed = context(&[]);
+        unnamed.character_name = None;
+        unnamed.prompt = None;
+        assert_eq!(generator.fill(&unnamed), "e on  after \"\"");
+
+        let empty = TemplateTextGenerator { templates: vec![] };
+        assert_eq!(empty.fill(&context(&[])), "");
+    }
+
+    #[test]
+    fn picks_the_same_template_for_the_same_story() {
+        let generator = TemplateTextGenerator::default();
+        let context = context(&["Hello"]);
+
+        assert_eq!(generator.fill(&context), generator.fill(&context));
+    }
+
+    #[test]
+    fn picks_files_by_keyword() {
+        let directory = test_directory("picks_files_by_keyword");
+        for file in [
+            "city_night.png",
+            "forest_day.png",
+            "forest_night.jpg",
+            "rain.ogg",
+        ] {
+            fs::write(directory.join(file), b"").unwrap();
+        }
+        let generator = DirectoryGenerator::new(&directory);
+
+        let pick = |prompt: &str, extensions: &[&str]| {
+            generator
+                .pick(prompt, extensions)
+                .and_then(|path| Some(path.file_name()?.to_str()?.to_string()))
+        };
+
+        assert_eq!(
+            pick("A night in the Forest", &IMAGE_EXTENSIONS).as_deref(),
+            Some("forest_night.jpg")
+        );
+        assert_eq!(
+            pick("city", &IMAGE_EXTENSIONS).as_deref(),
+            Some("city_night.png")
+        );
+        // Ties go to the first file by name
+        assert_eq!(
+            pick("nothing alike", &IMAGE_EXTENSIONS).as_deref(),
+            Some("city_night.png")
+        );
+        assert_eq!(pick("rain", &AUDIO_EXTENSIONS).as_deref(), Some("rain.ogg"));
+        assert_eq!(pick("rain", &["wav"]), None);
+        assert_eq!(
+            DirectoryGenerator::new(directory.join("missing")).pick("rain", &AUDIO_EXTENSIONS),
+            None
+        );
+    }
+
+    #[test]
+    fn names_generated_files_by_content() {
+        let directory = test_directory("names_generated_files_by_content");
+
+        let first = write_generated(&directory, "music", "ogg", b"OggS first").unwrap();
+        let again = write_generated(&directory, "music", "ogg", b"OggS first").unwrap();
+        let second = write_generated(&directory, "music", "ogg", b"OggS second").unwrap();
+
+        assert_eq!(first, again);
+        assert_ne!(first, second);
+        assert!(first.ends_with(".ogg"));
+        assert_eq!(fs::read(&first).unwrap(), b"OggS first");
+
+        assert_eq!(audio_extension(b"OggS"), "ogg");
+        assert_eq!(audio_extension(b"RIFF"), "wav");
+        assert_eq!(audio_extension(b"fLaC"), "flac");
+        assert_eq!(audio_extension(b"ID3"), "mp3");
+    }
+
+    #[test]
+    fn injects_generated_lines() {
+        let mut novel_data = novel_data();
+        novel_data.call_stack = vec![5];
+        novel_data.history.push_back(NovelSaveState {
+            current_index: 4,
+            call_stack: vec![5],
+            ..default()
+        });
+        novel_data.rolled_back.push(NovelSaveState {
+            current_index: 2,
+            ..default()
+        });
+
+        inject_generated(&mut novel_data, 4, line("Hi"));
+
+        assert_eq!(
+            says(&novel_data.ast),
+            vec![
+                (2, "Before".to_string()),
+                (4, "Hi".to_string()),
+                (5, "After".to_string())
+            ]
+        );
+        assert_eq!(novel_data.call_stack, vec![6]);
+        assert_eq!(novel_data.history[0].current_index, 5);
+        assert_eq!(novel_data.history[0].call_stack, vec![6]);
+        assert_eq!(novel_data.rolled_back[0].current_index, 2);
+        assert_eq!(novel_data.generated.keys().collect::<Vec<_>>(), vec![&4]);
+    }
+
+    #[test]
+    fn replaces_lines_generated_before() {
+        let mut novel_data = novel_data();
+        inject_generated(&mut novel_data, 4, line("Hi"));
+        inject_generated(&mut novel_data, 4, line("Hello again"));
+
+        assert_eq!(
+            says(&novel_data.ast),
+            vec![
+                (2, "Before".to_string()),
+                (4, "Hello again".to_string()),
+                (5, "After".to_string())
+            ]
+        );
+        assert_eq!(novel_data.generated.get(&4), Some(&line("Hello again")));
+    }
+
+    #[test]
+    fn shifts_nodes_generated_before() {
+        let mut novel_data = novel_data();
+        inject_generated(&mut novel_data, 5, line("Later"));
+        inject_generated(&mut novel_data, 4, line("Sooner"));
+
+        assert_eq!(
+            novel_data.generated,
+            BTreeMap::from([(4, line("Sooner")), (6, line("Later"))])
+        );
+        assert_eq!(
+            says(&novel_data.ast),
+            vec![
+                (2, "Before".to_string()),
+                (4, "Sooner".to_string()),
+                (5, "After".to_string()),
+                (6, "Later".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn shifts_labels_after_generated_lines() {
+        let mut novel_data = two_labels();
+        let label = novel_data.labels["two"];
+        let statement = novel_data.statements.keys().copied().max().unwrap();
+
+        inject_generated(&mut novel_data, 3, line("Hi"));
+
+        assert_eq!(novel_data.labels["two"], label + 1);
+        assert!(matches!(
+            node(&novel_data.ast, label + 1),
+            Some(AST::Label(..))
+        ));
+        assert!(novel_data.statements.contains_key(&(statement + 1)));
+        assert!(matches!(
+            node(&novel_data.ast, statement + 2),
+            Some(AST::Say(_, None, what)) if what == "In two"
+        ));
+        // Only the label the generate line is in gets the line
+        assert_eq!(
+            says(&novel_data.ast),
+            vec![(3, "Hi".to_string()), (statement + 2, "In two".to_string())]
+        );
+        assert!(
+            !novel_data
+                .ast
+                .iter()
+                .any(|node| matches!(node, AST::Say(..)))
+        );
+    }
+
+    #[test]
+    fn shifts_labels_after_generated_scenes() {
+        let mut novel_data = two_labels();
+        let label = novel_data.labels["two"];
+
+        inject_generated(
+            &mut novel_data,
+            3,
+            GeneratedNode::Scene {
+                image: "forest".to_string(),
+            },
+        );
+
+        assert_eq!(novel_data.labels["two"], label + 1);
+        assert!(matches!(
+            node(&novel_data.ast, label + 1),
+            Some(AST::Label(..))
+        ));
+        assert!(matches!(
+            node(&novel_data.ast, 3),
+            Some(AST::Scene(_, Some(image), _)) if image == "forest"
+        ));
+        assert!(
+            !novel_data
+                .ast
+                .iter()
+                .any(|node| matches!(node, AST::Scene(..)))
+        );
+        assert_eq!(
+            says(&novel_data.ast),
+            vec![(label + 3, "In two".to_string())]
+        );
+    }
+
+    #[test]
+    fn restores_generated_nodes() {
+        let mut played = novel_data();
+        inject_generated(&mut played, 5, line("Later"));
+        inject_generated(&mut played, 4, line("Sooner"));
+
+        let mut loaded = novel_data();
+        loaded.restore_generated(&played.generated);
+
+        assert_eq!(format!("{:?}", loaded.ast), format!("{:?}", played.ast));
+        assert_eq!(loaded.labels, played.labels);
+        assert_eq!(loaded.generated, played.generated);
+        // The parsed script stays the one saves are checked against
+        assert_eq!(loaded.script_hash, novel_data().script_hash);
+
+        loaded.restore_generated(&BTreeMap::new());
+        assert_eq!(
+            format!("{:?}", loaded.ast),
+            format!("{:?}", novel_data().ast)
+        );
+    }
+}
@@ -0,0 +1,381 @@
+use std::collections::{BTreeMap, HashMap};
+
+use bevy::prelude::*;
+use renpy_parser::parsers::AST;
+
+use crate::{
+    NovelData, build_label_table,
+    characters::parse_character_define,
+    enclosing_label,
+    generate::{NovelGenerationEntities, cancel_generations},
+    list_ast_indices, list_defines,
+    mechanics::parse_mechanic,
+    messages::{EventSwitchNextNode, define_variables},
+    rpy_asset_loader::Rpy,
+    save::{NovelSaveState, script_hash},
+    scenario::Scenario,
+    script::Statements,
+    text::NovelTextReveal,
+    variables::NovelVariables,
+};
+
+/// Every node of `ast` with the nodes inside labels, keyed by index
+pub(crate) fn collect_nodes(ast: &[AST], nodes: &mut HashMap<usize, AST>) {
+    for node in ast.iter() {
+        if matches!(node, AST::Error) {
+            continue;
+        }
+
+        nodes.insert(node.index(), node.clone());
+        if let AST::Label(_, _, label_ast, _) = node {
+            collect_nodes(label_ast, nodes);
+        }
+    }
+}
+
+/// Maps an index of the old script onto the new one: a say line is found again by its
+/// speaker and text, anything else keeps its offset from the start of its label
+fn remap_index(
+    index: usize,
+    old_nodes: &HashMap<usize, AST>,
+    old_labels: &HashMap<String, usize>,
+    new_nodes: &HashMap<usize, AST>,
+    new_labels: &HashMap<String, usize>,
+    max_index: usize,
+) -> usize {
+    // Where the line would be if nothing moved inside its label
+    let expected = match enclosing_label(old_labels, index) {
+        Some((label, label_index)) => match new_labels.get(label) {
+            Some(new_label_index) => new_label_index + (index - label_index),
+            None => index,
+        },
+        None => index,
+    }
+    .min(max_index);
+
+    if let Some(AST::Say(_, who, what)) = old_nodes.get(&index)
+        && let Some(new_index) = find_line(new_nodes, who, what, expected)
+    {
+        return new_index;
+    }
+
+    expected
+}
+
+/// Index of the say line of `who` saying `what` nearest to `expected`
+pub(crate) fn find_line(
+    nodes: &HashMap<usize, AST>,
+    who: &Option<String>,
+    what: &str,
+    expected: usize,
+) -> Option<usize> {
+    nodes
+        .iter()
+        .filter(|(_, node)| matches!(node, AST::Say(_, w, t) if w == who && t == what))
+        .map(|(index, _)| *index)
+        .min_by_key(|index| index.abs_diff(expected))
+}
+
+impl NovelData {
+    /// Swaps in an edited version of the running script, keeping the player at the same
+    /// line, or the nearest one left of it. The call stack and the rollback snapshots are
+    /// moved along. Generated nodes are dropped, and a game mechanic the story waits on is
+    /// let go unless its statement is still at the current line.
+    pub fn reload_scenario(&mut self, ast: Vec<AST>, statements: Statements) {
+        let mut old_nodes = HashMap::new();
+        collect_nodes(&self.ast, &mut old_nodes);
+        let mut new_nodes = HashMap::new();
+        collect_nodes(&ast, &mut new_nodes);
+
+        let new_labels = build_label_table(&ast);
+
+        let mut indices = list_ast_indices(ast.clone());
+        indices.extend(statements.keys());
+        let max_index = indices.into_iter().max().unwrap_or_default();
+
+        let remap = |index: usize| {
+            remap_index(
+                index,
+                &old_nodes,
+                &self.labels,
+                &new_nodes,
+                &new_labels,
+                max_index,
+            )
+        };
+
+        let current_index = remap(self.current_index);
+        let call_stack = self.call_stack.iter().map(|index| remap(*index)).collect();
+
+        let hash = script_hash(&ast, &statements);
+        let remap_state = |state: &NovelSaveState| NovelSaveState {
+            current_index: remap(state.current_index),
+            call_stack: state.call_stack.iter().map(|index| remap(*index)).collect(),
+            script_hash: Some(hash),
+            generated: BTreeMap::new(),
+            ..state.clone()
+        };
+        let history = self.history.iter().map(remap_state).collect();
+        let rolled_back = self.rolled_back.iter().map(remap_state).collect();
+
+        let mechanic = self.mechanic.clone().filter(|name| {
+            matches!(
+                new_nodes.get(&current_index),
+                Some(AST::GameMechanic(_, mechanic))
+                    if parse_mechanic(mechanic).is_some_and(|(new_name, _)| new_name == *name)
+            )
+        });
+
+        let characters = list_defines(&ast)
+            .iter()
+            .filter_map(|definition| parse_character_define(definition))
+            .collect();
+
+        self.script_hash = hash;
+        self.source_ast = ast.clone();
+        self.source_statements = statements.clone();
+        self.ast = ast;
+        self.statements = statements;
+        self.labels = new_labels;
+        self.characters = characters;
+        self.current_index = current_index;
+        self.call_stack = call_stack;
+        self.history = history;
+        self.rolled_back = rolled_back;
+        self.generated.clear();
+        // The generation running injects into the old script, it is cancelled
+        self.generating = false;
+        self.mechanic = mechanic;
+    }
+}
+
+/// Re-merges the running script when one of its `.rpy` files changes and shows the current
+/// line or menu again from the new version. A generation running is started again, as is a
+/// game mechanic whose statement moved. Constants and defaults are set again as the scenario
+/// start sets them.
+#[allow(clippy::too_many_arguments)]
+pub fn hot_reload_scenario(
+    mut commands: Commands,
+    mut er_asset_event: MessageReader<AssetEvent<Rpy>>,
+    generations: NovelGenerationEntities,
+    rpy_assets: Res<Assets<Rpy>>,
+    mut novel_data: ResMut<NovelData>,
+    mut variables: ResMut<NovelVariables>,
+    mut text_reveal: ResMut<NovelTextReveal>,
+    mut ew_switch_next_node: MessageWriter<EventSwitchNextNode>,
+) {
+    for event in er_asset_event.read() {
+        let AssetEvent::Modified { id } = event else {
+            continue;
+        };
+
+        if !novel_data.files.contains(id) {
+            continue;
+        }
+
+        let files = novel_data
+            .files
+            .iter()
+            .map(|id| Some((*id, rpy_assets.get(*id)?)))
+            .collect::<Option<Vec<_>>>();
+        let Some(files) = files else {
+            continue;
+        };
+
+        info!("Reloading the scenario");
+        let scenario = Scenario::merge(files);
+        define_variables(&mut variables, &scenario.ast, &scenario.statements);
+
+        let generating = novel_data.generating;
+        let mechanic = novel_data.mechanic.is_some();
+        cancel_generations(&mut commands, &generations, &mut novel_data);
+        novel_data.reload_scenario(scenario.ast, scenario.statements);
+        let mechanic_dropped = mechanic && novel_data.mechanic.is_none();
+
+        let mut nodes = HashMap::new();
+        collect_nodes(&novel_data.ast, &mut nodes);
+        let current_say = matches!(
+            nodes.get(&novel_data.current_index),
+            Some(AST::Say(_, _, _))
+        );
+
+        if !current_say && novel_data.menu.is_none() && !generating && !mechanic_dropped {
+            continue;
+        }
+
+        // Handle the current node again, without adding it to the backlog twice
+        novel_data.menu = None;
+        novel_data.replaying = current_say;
+        novel_data.current_index = novel_data.current_index.saturating_sub(1);
+        text_reveal.stop();
+        ew_switch_next_node.write(EventSwitchNextNode {});
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::{generate::GeneratedNode, rpy_asset_loader::parse_script};
+
+    const SCRIPT: &str = r#"label start:
+    e "One"
+    e "Two"
+    show eileen
+    game_mechanic "battle goblin"
+label other:
+    "Other"
+    show lucy
+    return
+"#;
+
+    /// A line added to `start`, and `Two` moved past `show eileen`
+    const INSERTED: &str = r#"label start:
+    e "Zero"
+    e "One"
+    show eileen
+    e "Two"
+    game_mechanic "battle goblin"
+label other:
+    "Other"
+    show lucy
+    return
+"#;
+
+    /// `One` removed and the game mechanic replaced
+    const REMOVED: &str = r#"label start:
+    e "Two"
+    show eileen
+    e "Three"
+label other:
+    "Other"
+    show lucy
+    return
+"#;
+
+    fn parse(content: &str) -> (Vec<AST>, Statements) {
+        parse_script(content, Path::new("script.rpy")).unwrap()
+    }
+
+    /// Index of `index` of [`SCRIPT`] in the script `content`
+    fn remap(content: &str, index: usize) -> usize {
+        let (old_ast, _) = parse(SCRIPT);
+        let (new_ast, _) = parse(content);
+
+        let mut old_nodes = HashMap::new();
+        collect_nodes(&old_ast, &mut old_nodes);
+        let mut new_nodes = HashMap::new();
+        collect_nodes(&new_ast, &mut new_nodes);
+        let max_index = list_ast_indices(new_ast.clone()).into_iter().max().unwrap();
+
+        remap_index(
+            index,
+            &old_nodes,
+            &build_label_table(&old_ast),
+            &new_nodes,
+            &build_label_table(&new_ast),
+            max_index,
+        )
+    }
+
+    fn novel_data() -> NovelData {
+        let (ast, statements) = parse(SCRIPT);
+        let mut novel_data = NovelData::default();
+        novel_data.set_scenario(ast, statements);
+        novel_data
+    }
+
+    #[test]
+    fn finds_say_lines_by_text() {
+        // `Two` at 3 moved to 5, `One` at 2 to 3
+        assert_eq!(remap(INSERTED, 3), 5);
+        assert_eq!(remap(INSERTED, 2), 3);
+        assert_eq!(remap(REMOVED, 3), 2);
+    }
+
+    #[test]
+    fn keeps_other_nodes_at_their_label_offset() {
+        // `show lucy` is 2 lines into `other`, which moved down a line and then up one
+        assert_eq!(remap(INSERTED, 8), 9);
+        assert_eq!(remap(REMOVED, 8), 7);
+        assert_eq!(remap(INSERTED, 6), 7);
+        assert_eq!(remap(REMOVED, 6), 5);
+    }
+
+    #[test]
+    fn falls_back_to_the_label_offset_for_removed_lines() {
+        // `One` is gone, the line at its offset into `start` is taken
+        assert_eq!(remap(REMOVED, 2), 2);
+        // The `return` of `other` keeps its offset too
+        assert_eq!(remap(REMOVED, 9), 8);
+    }
+
+    #[test]
+    fn moves_the_call_stack_and_history() {
+        let mut novel_data = novel_data();
+        novel_data.current_index = 3;
+        novel_data.call_stack = vec![8];
+        novel_data.history.push_back(NovelSaveState {
+            current_index: 2,
+            call_stack: vec![8],
+            ..default()
+        });
+        novel_data.rolled_back.push(NovelSaveState {
+            current_index: 7,
+            ..default()
+        });
+
+        let (ast, statements) = parse(INSERTED);
+        novel_data.reload_scenario(ast, statements);
+
+        assert_eq!(novel_data.current_index, 5);
+        assert_eq!(novel_data.call_stack, vec![9]);
+        assert_eq!(novel_data.history[0].current_index, 3);
+        assert_eq!(novel_data.history[0].call_stack, vec![9]);
+        assert_eq!(
+            novel_data.history[0].script_hash,
+            Some(novel_data.script_hash)
+        );
+        assert_eq!(novel_data.rolled_back[0].current_index, 8);
+        assert_eq!(novel_data.labels.get("other"), Some(&7));
+    }
+
+    #[test]
+    fn keeps_a_mechanic_still_at_the_current_line() {
+        let mut novel_data = novel_data();
+        novel_data.current_index = 5;
+        novel_data.mechanic = Some("battle".to_string());
+
+        let (ast, statements) = parse(&SCRIPT.replace("Other", "Another"));
+        novel_data.reload_scenario(ast, statements);
+        assert_eq!(novel_data.current_index, 5);
+        assert_eq!(novel_data.mechanic.as_deref(), Some("battle"));
+
+        // The line at the offset of the mechanic into `start` is `label other` now
+        let (ast, statements) = parse(REMOVED);
+        novel_data.reload_scenario(ast, statements);
+        assert_eq!(novel_data.current_index, 5);
+        assert_eq!(novel_data.mechanic, None);
+    }
+
+    #[test]
+    fn drops_pending_generation() {
+        let mut novel_data = novel_data();
+        novel_data.generating = true;
+        novel_data.push_text_node(Some("e".to_string()), "Generated".to_string(), 3);
+        novel_data.generated.insert(
+            3,
+            GeneratedNode::Line {
+                character: "e".to_string(),
+                text: "Generated".to_string(),
+            },
+        );
+
+        let (ast, statements) = parse(SCRIPT);
+        novel_data.reload_scenario(ast, statements);
+
+        assert!(!novel_data.generating);
+        assert!(novel_data.generated.is_empty());
+    }
+}
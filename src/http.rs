@@ -0,0 +1,348 @@
+//! Minimal asynchronous HTTP/1.1 client for the `openai` generator.
+//!
+//! Requests go over an `async-io` socket, so waiting on the server holds no thread. Only
+//! plain `http://` URLs are supported, enough for a local OpenAI compatible server.
+
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+
+use async_io::Async;
+use bevy::tasks::futures_lite::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+
+use crate::generate::NovelGenerateError;
+
+/// Largest response read before giving up on the server, far over any chat completion
+const MAX_RESPONSE_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Host, port and path of an `http://` URL
+#[derive(Debug, PartialEq)]
+struct Url {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+fn parse_url(url: &str) -> Result<Url, NovelGenerateError> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| NovelGenerateError::Request(format!("`{}` is not an http:// URL", url)))?;
+
+    let (authority, path) = match rest.find('/') {
+        Some(slash) => rest.split_at(slash),
+        None => (rest, "/"),
+    };
+
+    let invalid_port = || NovelGenerateError::Request(format!("Invalid port in `{}`", url));
+    let parse_port = |port: &str| port.parse().map_err(|_| invalid_port());
+
+    let (host, port) = match authority.strip_prefix('[') {
+        // The colons of an IPv6 address are inside its brackets
+        Some(bracketed) => {
+            let (host, rest) = bracketed
+                .split_once(']')
+                .ok_or_else(|| NovelGenerateError::Request(format!("Unclosed `[` in `{}`", url)))?;
+            let port = match rest {
+                "" => 80,
+                rest => parse_port(rest.strip_prefix(':').ok_or_else(invalid_port)?)?,
+            };
+            (host, port)
+        }
+        None => match authority.rsplit_once(':') {
+            Some((host, port)) => (host, parse_port(port)?),
+            None => (authority, 80),
+        },
+    };
+
+    if host.is_empty() {
+        return Err(NovelGenerateError::Request(format!("No host in `{}`", url)));
+    }
+
+    Ok(Url {
+        host: host.to_string(),
+        port,
+        path: path.to_string(),
+    })
+}
+
+impl Url {
+    /// Value of the `Host` header, which names the port unless it is the default one
+    fn host_header(&self) -> String {
+        let host = if self.host.contains(':') {
+            format!("[{}]", self.host)
+        } else {
+            self.host.clone()
+        };
+
+        match self.port {
+            80 => host,
+            port => format!("{}:{}", host, port),
+        }
+    }
+}
+
+/// Status and body of a response read to the end of the connection
+fn parse_response(response: &[u8]) -> Result<(u16, Vec<u8>), NovelGenerateError> {
+    let invalid = |message: &str| NovelGenerateError::InvalidResponse(message.to_string());
+
+    let head_end = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or_else(|| invalid("incomplete response head"))?;
+    let head = std::str::from_utf8(&response[..head_end])
+        .map_err(|_| invalid("response head is not UTF-8"))?;
+    let body = &response[head_end + 4..];
+
+    let mut lines = head.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| invalid("invalid status line"))?;
+
+    let chunked = lines.any(|line| {
+        line.split_once(':').is_some_and(|(name, value)| {
+            name.trim().eq_ignore_ascii_case("transfer-encoding")
+                && value.trim().eq_ignore_ascii_case("chunked")
+        })
+    });
+
+    let body = if chunked {
+        decode_chunked(body)?
+    } else {
+        body.to_vec()
+    };
+
+    Ok((status, body))
+}
+
+/// Body of a `Transfer-Encoding: chunked` response
+fn decode_chunked(mut body: &[u8]) -> Result<Vec<u8>, NovelGenerateError> {
+    let invalid = || NovelGenerateError::InvalidResponse("invalid chunked body".to_string());
+    let mut decoded = Vec::new();
+
+    loop {
+        let line_end = body
+            .windows(2)
+            .position(|window| window == b"\r\n")
+            .ok_or_else(invalid)?;
+        let size = std::str::from_utf8(&body[..line_end]).map_err(|_| invalid())?;
+        // Chunk extensions follow a `;`
+        let size = size.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| invalid())?;
+        body = &body[line_end + 2..];
+
+        if size == 0 {
+            return Ok(decoded);
+        }
+
+        let chunk = body.get(..size).ok_or_else(invalid)?;
+        decoded.extend_from_slice(chunk);
+        body = body.get(size + 2..).ok_or_else(invalid)?;
+    }
+}
+
+/// Connection to the first of `addresses` that accepts one. A name like `localhost` can
+/// resolve to `::1` before `127.0.0.1` while the server only listens on one of them.
+async fn connect(
+    host: &str,
+    addresses: &[SocketAddr],
+) -> Result<Async<TcpStream>, NovelGenerateError> {
+    let mut last_error = None;
+
+    for address in addresses {
+        match Async::<TcpStream>::connect(*address).await {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_error = Some(err),
+        }
+    }
+
+    Err(NovelGenerateError::Request(match last_error {
+        Some(err) => err.to_string(),
+        None => format!("`{}` has no address", host),
+    }))
+}
+
+/// Everything `stream` sends until it closes, up to `limit` bytes
+async fn read_response(
+    stream: impl AsyncRead + Unpin,
+    limit: u64,
+) -> Result<Vec<u8>, NovelGenerateError> {
+    let mut response = Vec::new();
+    stream
+        .take(limit + 1)
+        .read_to_end(&mut response)
+        .await
+        .map_err(|err| NovelGenerateError::Request(err.to_string()))?;
+
+    if response.len() as u64 > limit {
+        return Err(NovelGenerateError::InvalidResponse(format!(
+            "response is larger than {} bytes",
+            limit
+        )));
+    }
+
+    Ok(response)
+}
+
+/// Posts a JSON `body` to `url` and returns the body of the response
+pub(crate) async fn post_json(
+    url: &str,
+    api_key: Option<&str>,
+    body: &str,
+) -> Result<String, NovelGenerateError> {
+    let url = parse_url(url)?;
+    let request_error = |err: std::io::Error| NovelGenerateError::Request(err.to_string());
+
+    // Name lookups block, they run on the thread pool of `blocking`
+    let (host, port) = (url.host.clone(), url.port);
+    let addresses: Vec<SocketAddr> =
+        blocking::unblock(move || (host.as_str(), port).to_socket_addrs().map(Vec::from_iter))
+            .await
+            .map_err(request_error)?;
+    let mut stream = connect(&url.host, &addresses).await?;
+
+    let mut request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n",
+        url.path,
+        url.host_header(),
+        body.len()
+    );
+    if let Some(api_key) = api_key {
+        request.push_str(&format!("Authorization: Bearer {}\r\n", api_key));
+    }
+    request.push_str("\r\n");
+    request.push_str(body);
+
+    stream
+        .write_all(request.as_bytes())
+        .await
+        .map_err(request_error)?;
+
+    let response = read_response(stream, MAX_RESPONSE_SIZE).await?;
+    let (status, body) = parse_response(&response)?;
+    let body = String::from_utf8_lossy(&body).into_owned();
+    if !(200..300).contains(&status) {
+        return Err(NovelGenerateError::Request(format!(
+            "HTTP {}: {}",
+            status, body
+        )));
+    }
+
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use bevy::tasks::block_on;
+
+    use super::*;
+
+    #[test]
+    fn parses_urls() {
+        assert_eq!(
+            parse_url("http://localhost:8080/v1/chat/completions").unwrap(),
+            Url {
+                host: "localhost".to_string(),
+                port: 8080,
+                path: "/v1/chat/completions".to_string(),
+            }
+        );
+        assert_eq!(
+            parse_url("http://example.com").unwrap(),
+            Url {
+                host: "example.com".to_string(),
+                port: 80,
+                path: "/".to_string(),
+            }
+        );
+        assert_eq!(
+            parse_url("http://[::1]:8080/v1").unwrap(),
+            Url {
+                host: "::1".to_string(),
+                port: 8080,
+                path: "/v1".to_string(),
+            }
+        );
+        assert_eq!(parse_url("http://[::1]").unwrap().port, 80);
+        assert!(parse_url("https://example.com/").is_err());
+        assert!(parse_url("http://[::1/").is_err());
+        assert!(parse_url("http://[::1]8080/").is_err());
+        assert!(parse_url("http://:80/").is_err());
+        assert!(parse_url("http://localhost:port/").is_err());
+    }
+
+    #[test]
+    fn host_headers() {
+        let host_header = |url: &str| parse_url(url).unwrap().host_header();
+
+        assert_eq!(host_header("http://example.com/"), "example.com");
+        assert_eq!(host_header("http://localhost:8080/"), "localhost:8080");
+        assert_eq!(host_header("http://[::1]/"), "[::1]");
+        assert_eq!(host_header("http://[::1]:8080/"), "[::1]:8080");
+    }
+
+    #[test]
+    fn parses_responses() {
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n{}";
+        assert_eq!(parse_response(response).unwrap(), (200, b"{}".to_vec()));
+
+        let response =
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n4;ext\r\nWiki\r\n5\r\npedia\r\n0\r\n\r\n";
+        assert_eq!(
+            parse_response(response).unwrap(),
+            (200, b"Wikipedia".to_vec())
+        );
+
+        let response = b"HTTP/1.1 404 Not Found\r\n\r\nmissing";
+        assert_eq!(
+            parse_response(response).unwrap(),
+            (404, b"missing".to_vec())
+        );
+    }
+
+    #[test]
+    fn rejects_broken_responses() {
+        assert!(parse_response(b"HTTP/1.1 200 OK\r\n").is_err());
+        assert!(parse_response(b"garbage\r\n\r\n").is_err());
+        assert!(
+            parse_response(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n9\r\nshort")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn connects_to_any_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        // Nothing listens on a port once its listener is gone
+        let refused = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let stream = block_on(connect(
+            "localhost",
+            &[refused, listener.local_addr().unwrap()],
+        ))
+        .unwrap();
+        assert_eq!(
+            stream.get_ref().peer_addr().unwrap(),
+            listener.local_addr().unwrap()
+        );
+
+        assert!(block_on(connect("localhost", &[refused])).is_err());
+        assert!(block_on(connect("localhost", &[])).is_err());
+    }
+
+    #[test]
+    fn caps_response_size() {
+        assert_eq!(
+            block_on(read_response(&b"response"[..], 8)).unwrap(),
+            b"response".to_vec()
+        );
+        assert!(matches!(
+            block_on(read_response(&b"response"[..], 7)),
+            Err(NovelGenerateError::InvalidResponse(_))
+        ));
+    }
+}
@@ -0,0 +1,216 @@
+use std::collections::HashMap;
+
+use bevy::{
+    ecs::system::SystemParam,
+    input::{gamepad::Gamepad, mouse::AccumulatedMouseScroll, touch::Touches},
+    prelude::*,
+};
+
+/// Something the player can do with the story
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum NovelAction {
+    /// Finish the line being typed, or go on to the next one
+    Advance,
+    Rollback,
+    RollForward,
+    /// Skip read lines while held
+    Skip,
+    /// Turn skip mode on or off
+    ToggleSkip,
+    /// Turn auto-forward on or off
+    AutoForward,
+    /// Hide the dialogue box until any advance input
+    HideUi,
+    /// Save to [`crate::NovelSettings::quick_save_slot`]
+    QuickSave,
+    /// Open or close the backlog
+    Backlog,
+}
+
+/// Physical input an action can be bound to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum NovelInput {
+    Key(KeyCode),
+    Mouse(MouseButton),
+    /// Button of any connected gamepad
+    Gamepad(GamepadButton),
+    /// Any touch on the screen
+    Touch,
+    MouseWheelUp,
+    MouseWheelDown,
+}
+
+/// Inputs bound to every [`NovelAction`], any of them triggers the action
+#[derive(Resource, Clone, Debug)]
+pub struct NovelInputMap {
+    pub bindings: HashMap<NovelAction, Vec<NovelInput>>,
+}
+
+impl Default for NovelInputMap {
+    fn default() -> Self {
+        use NovelInput::*;
+
+        let mut map = NovelInputMap {
+            bindings: HashMap::new(),
+        };
+
+        map.bind(NovelAction::Advance, Key(KeyCode::Space))
+            .bind(NovelAction::Advance, Key(KeyCode::Enter))
+            .bind(NovelAction::Advance, Key(KeyCode::NumpadEnter))
+            .bind(NovelAction::Advance, Mouse(MouseButton::Left))
+            .bind(NovelAction::Advance, Touch)
+            .bind(NovelAction::Advance, Gamepad(GamepadButton::South))
+            .bind(NovelAction::Rollback, Key(KeyCode::PageUp))
+            .bind(NovelAction::Rollback, MouseWheelUp)
+            .bind(NovelAction::Rollback, Gamepad(GamepadButton::LeftTrigger))
+            .bind(NovelAction::RollForward, Key(KeyCode::PageDown))
+            .bind(NovelAction::RollForward, MouseWheelDown)
+            .bind(
+                NovelAction::RollForward,
+                Gamepad(GamepadButton::RightTrigger),
+            )
+            .bind(NovelAction::Skip, Key(KeyCode::ControlLeft))
+            .bind(NovelAction::Skip, Key(KeyCode::ControlRight))
+            .bind(NovelAction::Skip, Gamepad(GamepadButton::RightTrigger2))
+            .bind(NovelAction::ToggleSkip, Key(KeyCode::Tab))
+            .bind(NovelAction::AutoForward, Key(KeyCode::KeyA))
+            .bind(NovelAction::AutoForward, Gamepad(GamepadButton::North))
+            .bind(NovelAction::HideUi, Key(KeyCode::KeyH))
+            .bind(NovelAction::HideUi, Mouse(MouseButton::Middle))
+            .bind(NovelAction::HideUi, Gamepad(GamepadButton::West))
+            .bind(NovelAction::QuickSave, Key(KeyCode::F5))
+            .bind(NovelAction::Backlog, Key(KeyCode::KeyL))
+            .bind(NovelAction::Backlog, Gamepad(GamepadButton::Select));
+
+        map
+    }
+}
+
+impl NovelInputMap {
+    /// Adds `input` to the inputs triggering `action`
+    pub fn bind(&mut self, action: NovelAction, input: NovelInput) -> &mut Self {
+        let inputs = self.bindings.entry(action).or_default();
+        if !inputs.contains(&input) {
+            inputs.push(input);
+        }
+        self
+    }
+
+    /// Removes every input bound to `action`
+    pub fn clear(&mut self, action: NovelAction) -> &mut Self {
+        self.bindings.remove(&action);
+        self
+    }
+
+    pub fn inputs(&self, action: NovelAction) -> &[NovelInput] {
+        self.bindings
+            .get(&action)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+}
+
+/// Input devices read through a [`NovelInputMap`]
+#[derive(SystemParam)]
+pub struct NovelInputs<'w, 's> {
+    pub map: Res<'w, NovelInputMap>,
+    keys: Res<'w, ButtonInput<KeyCode>>,
+    mouse_buttons: Res<'w, ButtonInput<MouseButton>>,
+    mouse_scroll: Res<'w, AccumulatedMouseScroll>,
+    touches: Res<'w, Touches>,
+    gamepads: Query<'w, 's, &'static Gamepad>,
+}
+
+impl NovelInputs<'_, '_> {
+    /// An input bound to `action` started this frame
+    pub fn just_pressed(&self, action: NovelAction) -> bool {
+        self.map.inputs(action).iter().any(|input| match *input {
+            NovelInput::Key(key) => self.keys.just_pressed(key),
+            NovelInput::Mouse(button) => self.mouse_buttons.just_pressed(button),
+            NovelInput::Gamepad(button) => self
+                .gamepads
+                .iter()
+                .any(|gamepad| gamepad.just_pressed(button)),
+            NovelInput::Touch => self.touches.any_just_pressed(),
+            NovelInput::MouseWheelUp => self.mouse_scroll.delta.y > 0.0,
+            NovelInput::MouseWheelDown => self.mouse_scroll.delta.y < 0.0,
+        })
+    }
+
+    /// An input bound to `action` is held down
+    pub fn pressed(&self, action: NovelAction) -> bool {
+        self.map.inputs(action).iter().any(|input| match *input {
+            NovelInput::Key(key) => self.keys.pressed(key),
+            NovelInput::Mouse(button) => self.mouse_buttons.pressed(button),
+            NovelInput::Gamepad(button) => {
+                self.gamepads.iter().any(|gamepad| gamepad.pressed(button))
+            }
+            NovelInput::Touch => self.touches.iter().next().is_some(),
+            NovelInput::MouseWheelUp => self.mouse_scroll.delta.y > 0.0,
+            NovelInput::MouseWheelDown => self.mouse_scroll.delta.y < 0.0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Resource, Default)]
+    struct Advanced(bool);
+
+    fn read_advance(inputs: NovelInputs, mut advanced: ResMut<Advanced>) {
+        advanced.0 = inputs.just_pressed(NovelAction::Advance);
+    }
+
+    /// Whether pressing `key` advances the story under `map`
+    fn advances(map: NovelInputMap, key: KeyCode) -> bool {
+        let mut app = App::new();
+        app.init_resource::<ButtonInput<KeyCode>>()
+            .init_resource::<ButtonInput<MouseButton>>()
+            .init_resource::<AccumulatedMouseScroll>()
+            .init_resource::<Touches>()
+            .init_resource::<Advanced>()
+            .insert_resource(map)
+            .add_systems(Update, read_advance);
+
+        app.world_mut()
+            .resource_mut::<ButtonInput<KeyCode>>()
+            .press(key);
+        app.update();
+
+        app.world().resource::<Advanced>().0
+    }
+
+    #[test]
+    fn default_bindings() {
+        let map = NovelInputMap::default();
+        let advance = map.inputs(NovelAction::Advance);
+
+        assert!(advance.contains(&NovelInput::Mouse(MouseButton::Left)));
+        assert!(advance.contains(&NovelInput::Key(KeyCode::Enter)));
+        assert!(advance.contains(&NovelInput::Touch));
+        assert!(advance.contains(&NovelInput::Gamepad(GamepadButton::South)));
+        assert!(advances(map, KeyCode::Enter));
+    }
+
+    #[test]
+    fn rebinds_actions() {
+        let mut map = NovelInputMap::default();
+        map.clear(NovelAction::Advance)
+            .bind(NovelAction::Advance, NovelInput::Key(KeyCode::KeyZ))
+            .bind(NovelAction::Advance, NovelInput::Key(KeyCode::KeyZ));
+
+        assert_eq!(
+            map.inputs(NovelAction::Advance),
+            &[NovelInput::Key(KeyCode::KeyZ)]
+        );
+        // Other actions keep their bindings
+        assert!(
+            map.inputs(NovelAction::Rollback)
+                .contains(&NovelInput::Key(KeyCode::PageUp))
+        );
+        assert!(advances(map.clone(), KeyCode::KeyZ));
+        assert!(!advances(map, KeyCode::Enter));
+    }
+}
@@ -1,20 +1,76 @@
+pub mod audio;
+pub mod auto_forward;
+pub mod backlog;
+pub mod characters;
+pub mod generate;
+pub mod hot_reload;
+#[cfg(feature = "openai")]
+mod http;
+pub mod input;
+pub mod mechanics;
+pub mod menu;
 pub mod messages;
+pub mod rollback;
 pub mod rpy_asset_loader;
-
-use std::collections::HashMap;
+pub mod save;
+pub mod scenario;
+pub mod script;
+pub mod skip;
+pub mod text;
+pub mod transforms;
+pub mod transitions;
+pub mod variables;
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::PathBuf;
 
 use bevy::prelude::*;
 use bevy_kira_audio::prelude::*;
-
-use renpy_parser::parsers::{AST, inject_node};
-
+use thiserror::Error;
+
+use renpy_parser::parsers::AST;
+
+use audio::{NovelAudioSettings, apply_audio_settings, load_audio_settings, write_audio_settings};
+use auto_forward::{NovelAutoForwardIndicator, auto_forward, update_auto_forward_indicator};
+use backlog::{
+    NovelBacklog, handle_backlog_buttons, handle_toggle_backlog, record_backlog, scroll_backlog,
+};
+use characters::{Character, parse_character_define};
+use generate::{
+    GeneratedNode, NovelMusicGeneration, NovelSceneGeneration, NovelTextGeneration,
+    handle_generate_music, handle_generate_scene, handle_generate_text, poll_generate_music,
+    poll_generate_scene, poll_generate_text,
+};
+use hot_reload::hot_reload_scenario;
+use input::NovelInputMap;
+use mechanics::{NovelMechanics, handle_game_mechanic, handle_resume_novel};
+use menu::*;
 use messages::*;
+use rollback::{handle_roll_forward, handle_rollback, record_history};
+use rpy_asset_loader::Rpy;
+use save::{NovelSaveState, handle_load_game, handle_save_game, script_hash};
+use script::{Menu, Statements, shift_statements};
+use skip::{
+    NovelReadLines, NovelSkip, NovelSkipIndicator, load_read_lines, record_read_lines, skip_lines,
+    update_skip_indicator, write_read_lines,
+};
+use text::{NovelTextReveal, reveal_text};
+use transforms::{NovelTransform, NovelTransforms};
+use transitions::{NovelTransitionState, NovelTransitions, animate_transition};
+use variables::NovelVariables;
 
 #[derive(Component)]
 pub struct NovelBackground;
 
+/// Sprite of an image shown with `show`, one per image tag
 #[derive(Component)]
-pub struct NovelImage;
+pub struct NovelImage {
+    /// First word of the image name, `eileen` for `eileen happy`
+    pub tag: String,
+    pub image: String,
+    /// Placement given with `at`, kept when the image is shown again without one
+    pub transform: NovelTransform,
+}
 
 #[derive(Component)]
 pub struct NovelText;
@@ -24,99 +80,188 @@ pub struct NovelTextWhat;
 
 #[derive(Component)]
 pub struct NovelTextWho;
+
+/// Side image of the speaking character, see [`Character::image`]
+#[derive(Component)]
+pub struct NovelSideImage;
 pub struct NovelPlugin;
 
 #[derive(Resource, Clone)]
 pub struct MusicHandle(Option<Handle<AudioInstance>>);
 
+/// Voice line playing, stopped when the next one starts
+#[derive(Resource, Clone)]
+pub struct VoiceHandle(Option<Handle<AudioInstance>>);
+
+/// Music and voice lines replaced while their play was still queued, stopped once kira
+/// created their instance
+#[derive(Resource, Default)]
+pub struct PendingAudioStops(Vec<(String, Handle<AudioInstance>, AudioTween)>);
+
+/// Errors raised while running a scenario
+#[derive(Debug, Error)]
+pub enum NovelError {
+    /// A `jump` targets a label that is not defined anywhere in the scenario
+    #[error("Label `{0}` is not defined in the scenario")]
+    LabelNotFound(String),
+}
+
 #[derive(Resource, Default)]
 pub struct NovelData {
     pub ast: Vec<AST>,
+    /// Script as it was parsed, without the nodes injected by generators
+    pub source_ast: Vec<AST>,
+    pub source_statements: Statements,
     pub current_index: usize,
     pub cached_images: HashMap<String, Sprite>,
+    /// Audio made at runtime, played instead of loading the file of the same name
+    pub cached_audio: HashMap<String, Handle<AudioSource>>,
+    /// Label name to the index of its `AST::Label` node
+    pub labels: HashMap<String, usize>,
+    pub statements: Statements,
+    /// Indices of the `call` statements waiting for a `return`
+    pub call_stack: Vec<usize>,
+    /// Menu waiting for the player to pick a choice
+    pub menu: Option<Menu>,
+    /// Characters declared with `define`, keyed by the name used in say statements
+    pub characters: HashMap<String, Character>,
+    /// Image of the last `scene` statement
+    pub background: Option<String>,
+    /// File of the music playing
+    pub music: Option<String>,
+    /// Voice file given with `voice`, played with the next say line
+    pub voice: Option<String>,
+    /// Snapshots of the say lines seen, the last one is the current line
+    pub history: VecDeque<NovelSaveState>,
+    /// Snapshots of the lines stepped back from, the next line is the last one
+    pub rolled_back: Vec<NovelSaveState>,
+    /// The next say line is brought back from a snapshot rather than newly reached
+    pub replaying: bool,
+    /// Files the running script was started from, reloaded when one of them changes
+    pub files: Vec<AssetId<Rpy>>,
+    /// Game mechanic the story waits on
+    pub mechanic: Option<String>,
+    /// The story waits on the line of an `llm_generate` statement
+    pub generating: bool,
+    /// [`save::script_hash`] of the script as it was started, saves of other versions are
+    /// refused
+    pub script_hash: u64,
+    /// Nodes injected by generators keyed by index, replaced rather than injected again when
+    /// their statement runs again
+    pub generated: BTreeMap<usize, GeneratedNode>,
 }
 
 impl NovelData {
+    // Navigate Scenario
+
+    pub fn set_scenario(&mut self, ast: Vec<AST>, statements: Statements) {
+        self.script_hash = script_hash(&ast, &statements);
+        self.source_ast = ast.clone();
+        self.source_statements = statements.clone();
+        self.ast = ast;
+        self.statements = statements;
+        self.current_index = 0;
+        self.call_stack.clear();
+        self.menu = None;
+        self.mechanic = None;
+        self.generating = false;
+        self.generated.clear();
+        self.history.clear();
+        self.rolled_back.clear();
+        self.replaying = false;
+        self.voice = None;
+        self.labels = build_label_table(&self.ast);
+        self.characters = list_defines(&self.ast)
+            .iter()
+            .filter_map(|definition| parse_character_define(definition))
+            .collect();
+    }
+
+    /// Moves `current_index` onto the node of `label`, so that the next switch lands on
+    /// the first node inside the label.
+    pub fn jump_to_label(&mut self, label: &str) -> Result<(), NovelError> {
+        let index = *self
+            .labels
+            .get(label)
+            .ok_or_else(|| NovelError::LabelNotFound(label.to_string()))?;
+
+        self.current_index = index;
+
+        Ok(())
+    }
+
+    /// Label the node at `index` is in and its offset from the label, leaving out nodes
+    /// generated before it so that the offset is the one written in the script
+    pub fn script_position(&self, index: usize) -> (Option<&str>, usize) {
+        let Some((label, label_index)) = enclosing_label(&self.labels, index) else {
+            return (None, index);
+        };
+        let generated = self.generated.range(label_index..index).count();
+
+        (Some(label.as_str()), index - label_index - generated)
+    }
+
     // Manipulate Scenario
 
+    /// Puts `node` at its index in the label it falls in, moving every node and statement
+    /// at or after that index one line down
+    fn inject_node(&mut self, node: AST) {
+        let index = node.index();
+        shift_ast(&mut self.ast, index);
+        insert_node(&mut self.ast, node);
+        self.statements = shift_statements(&self.statements, index);
+        self.labels = build_label_table(&self.ast);
+    }
+
     pub fn push_text_node(&mut self, who: Option<String>, what: String, index: usize) {
-        let node = AST::Say(index, who, what);
-        self.ast = inject_node(self.ast.clone(), node.clone());
-        for a in self.ast.iter_mut() {
-            if let AST::Label(node_index, label, node_ast, opts) = a {
-                let first_index = node_ast.first().unwrap().index();
-                let last_index = node_ast.last().unwrap().index();
-
-                if index >= first_index && index <= last_index {
-                    *a = AST::Label(
-                        *node_index,
-                        label.clone(),
-                        inject_node(node_ast.clone(), node.clone()).clone(),
-                        opts.clone(),
-                    )
-                }
-            }
-        }
+        self.inject_node(AST::Say(index, who, what));
     }
 
     pub fn push_show_node(&mut self, image: String, index: usize) {
-        let node = AST::Show(index, image);
-        self.ast = inject_node(self.ast.clone(), node.clone());
-        for a in self.ast.iter_mut() {
-            if let AST::Label(node_index, label, node_ast, opts) = a {
-                let first_index = node_ast.first().unwrap().index();
-                let last_index = node_ast.last().unwrap().index();
-
-                if index >= first_index && index <= last_index {
-                    *a = AST::Label(
-                        *node_index,
-                        label.clone(),
-                        inject_node(node_ast.clone(), node.clone()).clone(),
-                        opts.clone(),
-                    )
-                }
-            }
-        }
+        self.inject_node(AST::Show(index, image));
     }
 
     pub fn push_hide_node(&mut self, image: String, index: usize) {
-        let node = AST::Hide(index, image);
-        self.ast = inject_node(self.ast.clone(), node.clone());
-        for a in self.ast.iter_mut() {
-            if let AST::Label(node_index, label, node_ast, opts) = a {
-                let first_index = node_ast.first().unwrap().index();
-                let last_index = node_ast.last().unwrap().index();
-
-                if index >= first_index && index <= last_index {
-                    *a = AST::Label(
-                        *node_index,
-                        label.clone(),
-                        inject_node(node_ast.clone(), node.clone()).clone(),
-                        opts.clone(),
-                    )
+        self.inject_node(AST::Hide(index, image));
+    }
+
+    /// Puts `node` in place of the node with the same index
+    pub fn replace_node(&mut self, node: AST) {
+        fn replace(ast: &mut [AST], node: &AST) {
+            for a in ast.iter_mut() {
+                if let AST::Label(_, _, label_ast, _) = a {
+                    replace(label_ast, node);
+                } else if !matches!(a, AST::Error) && a.index() == node.index() {
+                    *a = node.clone();
                 }
             }
         }
+
+        replace(&mut self.ast, &node);
     }
 
-    pub fn push_scene_node(&mut self, image: String, index: usize) {
-        let node = AST::Scene(index, Some(image), "master".into());
-        self.ast = inject_node(self.ast.clone(), node.clone());
-        for a in self.ast.iter_mut() {
-            if let AST::Label(node_index, label, node_ast, opts) = a {
-                let first_index = node_ast.first().unwrap().index();
-                let last_index = node_ast.last().unwrap().index();
-
-                if index >= first_index && index <= last_index {
-                    *a = AST::Label(
-                        *node_index,
-                        label.clone(),
-                        inject_node(node_ast.clone(), node.clone()).clone(),
-                        opts.clone(),
-                    )
+    /// Puts back the script as parsed with `generated` injected into it, the nodes a loaded
+    /// game was saved with
+    pub fn restore_generated(&mut self, generated: &BTreeMap<usize, GeneratedNode>) {
+        self.ast = self.source_ast.clone();
+        self.statements = self.source_statements.clone();
+        self.labels = build_label_table(&self.ast);
+
+        // Injected by ascending index each node lands at the index it was saved with
+        for (index, node) in generated.iter() {
+            match node.clone() {
+                GeneratedNode::Line { character, text } => {
+                    self.push_text_node(Some(character), text, *index)
                 }
+                GeneratedNode::Scene { image } => self.push_scene_node(image, *index),
             }
         }
+
+        self.generated = generated.clone();
+    }
+
+    pub fn push_scene_node(&mut self, image: String, index: usize) {
+        self.inject_node(AST::Scene(index, Some(image), "master".into()));
     }
 
     // Manipulate Images
@@ -126,49 +271,188 @@ impl NovelData {
     }
 }
 
-#[derive(Resource, Default)]
+#[derive(Resource)]
 pub struct NovelSettings {
     pub assets_path: String,
     pub pause_handle_switch_node: bool,
     pub text_position: Option<(f32, f32)>,
+    /// Don't spawn the built-in menu buttons, the game answers `EventShowMenu` itself
+    pub custom_menu_ui: bool,
+    /// Don't spawn the built-in backlog screen, the game draws `NovelBacklog` itself
+    pub custom_backlog_ui: bool,
+    /// Characters of dialogue revealed per second, 0.0 shows whole lines at once
+    pub text_speed: f32,
+    /// Advance on its own once a line has been shown for a while
+    pub auto_forward: bool,
+    /// Seconds auto-forward waits on every line
+    pub auto_forward_delay: f32,
+    /// Seconds auto-forward waits on top of that for every character of the line
+    pub auto_forward_character_delay: f32,
+    /// Directory save slots are written to
+    pub save_directory: PathBuf,
+    /// Number of say lines rollback can step back through
+    pub rollback_limit: usize,
+    /// Number of say lines the backlog keeps, the oldest are dropped first
+    pub backlog_limit: usize,
+    /// Skip mode goes on through lines that haven't been read before
+    pub skip_unread: bool,
+    /// Save slot written by the quick-save action
+    pub quick_save_slot: usize,
+    /// Seconds `llm_generate` waits for its generator
+    pub generation_timeout: f32,
+    /// Line said when the generator fails or times out
+    pub generation_fallback: String,
+    /// Master and per-channel volume, kept across runs
+    pub audio: NovelAudioSettings,
+}
+
+impl Default for NovelSettings {
+    fn default() -> Self {
+        NovelSettings {
+            assets_path: String::new(),
+            pause_handle_switch_node: false,
+            text_position: None,
+            custom_menu_ui: false,
+            custom_backlog_ui: false,
+            text_speed: 40.0,
+            auto_forward: false,
+            auto_forward_delay: 1.0,
+            auto_forward_character_delay: 0.05,
+            save_directory: PathBuf::from("saves"),
+            rollback_limit: 100,
+            backlog_limit: 250,
+            skip_unread: false,
+            quick_save_slot: 0,
+            generation_timeout: 10.0,
+            generation_fallback: "...".to_string(),
+            audio: NovelAudioSettings::default(),
+        }
+    }
 }
 
 impl Plugin for NovelPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Startup, setup)
+        app.add_systems(Startup, (setup, load_read_lines, load_audio_settings))
+            .add_systems(Last, (write_read_lines, write_audio_settings))
             .add_systems(
                 Update,
                 (
                     handle_start_scenario,
+                    hot_reload_scenario,
                     handle_switch_next_node,
                     handle_new_node,
+                    (
+                        play_voice,
+                        record_backlog,
+                        record_history,
+                        record_read_lines,
+                    )
+                        .chain(),
+                    handle_new_statement,
+                    (
+                        spawn_menu,
+                        handle_menu_buttons,
+                        handle_menu_choice,
+                        despawn_menu,
+                    )
+                        .chain(),
+                    (
+                        handle_toggle_backlog,
+                        handle_backlog_buttons,
+                        scroll_backlog,
+                    )
+                        .chain(),
+                    (
+                        handle_call,
+                        handle_jump,
+                        handle_return,
+                        handle_stop_node,
+                        handle_game_mechanic,
+                        handle_resume_novel,
+                        handle_generate_text,
+                        poll_generate_text,
+                        handle_generate_scene,
+                        poll_generate_scene,
+                        handle_generate_music,
+                        poll_generate_music,
+                    )
+                        .chain(),
+                    (
+                        handle_save_game,
+                        handle_load_game,
+                        handle_rollback,
+                        handle_roll_forward,
+                        handle_restore_state,
+                    )
+                        .chain(),
+                    (handle_show, handle_hide).chain(),
                     (handle_hide_image_node, handle_hide_text_node).chain(),
                     (handle_show_image_node, handle_show_text_node).chain(),
+                    reveal_text,
+                    (auto_forward, update_auto_forward_indicator).chain(),
+                    (skip_lines, update_skip_indicator).chain(),
                     handle_press_key,
-                    handle_play_audio,
+                    (
+                        apply_audio_settings,
+                        stop_pending_audio,
+                        handle_play_audio,
+                        handle_stop_audio,
+                    )
+                        .chain(),
                     scale_images,
+                    animate_transition,
                 )
                     .chain(),
             )
+            .add_message::<EventCall>()
             .add_message::<EventHandleNode>()
+            .add_message::<EventGameMechanic>()
+            .add_message::<EventHandleStatement>()
+            .add_message::<EventHide>()
             .add_message::<EventHideImageNode>()
             .add_message::<EventHideTextNode>()
             .add_message::<EventJump>()
             .add_message::<EventLabel>()
+            .add_message::<EventLoadGame>()
+            .add_message::<EventMenuChoice>()
             .add_message::<EventPlayAudio>()
+            .add_message::<EventStopAudio>()
+            .add_message::<EventRestoreState>()
+            .add_message::<EventResumeNovel>()
             .add_message::<EventReturn>()
+            .add_message::<EventRollForward>()
+            .add_message::<EventRollback>()
+            .add_message::<EventSaveGame>()
             .add_message::<EventSay>()
             .add_message::<EventShow>()
             .add_message::<EventShowImageNode>()
+            .add_message::<EventShowMenu>()
             .add_message::<EventShowTextNode>()
             .add_message::<EventStartScenario>()
+            .add_message::<EventToggleBacklog>()
+            .add_message::<EventTextRevealed>()
             .add_message::<EventSwitchNextNode>()
             .add_message::<EventNovelEnd>()
             .init_resource::<NovelData>()
+            .init_resource::<NovelTextReveal>()
+            .init_resource::<NovelTransforms>()
+            .init_resource::<NovelTransitionState>()
+            .init_resource::<NovelTransitions>()
+            .init_resource::<NovelVariables>()
+            .init_resource::<NovelBacklog>()
+            .init_resource::<NovelReadLines>()
+            .init_resource::<NovelSkip>()
+            .init_resource::<NovelInputMap>()
+            .init_resource::<NovelMechanics>()
+            .init_resource::<NovelTextGeneration>()
+            .init_resource::<NovelSceneGeneration>()
+            .init_resource::<NovelMusicGeneration>()
             .insert_resource(MusicHandle(None))
+            .insert_resource(VoiceHandle(None))
+            .init_resource::<PendingAudioStops>()
             .insert_resource(NovelSettings::default())
             .init_asset_loader::<rpy_asset_loader::RpyAssetLoader>()
-            .init_asset::<rpy_asset_loader::Rpy>();
+            .init_asset::<Rpy>();
     }
 }
 
@@ -205,23 +489,49 @@ fn setup(mut commands: Commands, _novel_settings: Res<NovelSettings>) {
         });
 
     commands.spawn((
-        Name::new("Background Image"),
-        Sprite::default(),
-        NovelBackground,
+        Name::new("Auto Forward Indicator"),
+        Text::new("Auto"),
+        NovelAutoForwardIndicator,
         Node {
             position_type: PositionType::Absolute,
-            width: Val::Auto,
-            height: Val::Auto,
+            top: px(5),
+            right: px(15),
             ..default()
         },
         Visibility::Hidden,
     ));
 
     commands.spawn((
-        Name::new("Character Image"),
+        Name::new("Skip Indicator"),
+        Text::new("Skip"),
+        NovelSkipIndicator,
+        Node {
+            position_type: PositionType::Absolute,
+            top: px(30),
+            right: px(15),
+            ..default()
+        },
+        Visibility::Hidden,
+    ));
+
+    commands.spawn((
+        Name::new("Side Image"),
+        ImageNode::default(),
+        NovelSideImage,
+        Node {
+            position_type: PositionType::Absolute,
+            bottom: px(40),
+            left: px(15),
+            height: percent(30),
+            ..default()
+        },
+        Visibility::Hidden,
+    ));
+
+    commands.spawn((
+        Name::new("Background Image"),
         Sprite::default(),
-        NovelImage,
-        // ZIndex(2),
+        NovelBackground,
         Node {
             position_type: PositionType::Absolute,
             width: Val::Auto,
@@ -250,7 +560,8 @@ pub fn find_element_with_index(ast: Vec<AST>, index: usize) -> Option<AST> {
             AST::Comment(i, _) => *i,
             AST::SceneGenerate(i, _) => *i,
             AST::MusicGenerate(i, _) => *i,
-            AST::Error => panic!(),
+            // Statements renpy_parser couldn't parse are never run
+            AST::Error => continue,
         };
 
         if index == ast_index {
@@ -261,37 +572,161 @@ pub fn find_element_with_index(ast: Vec<AST>, index: usize) -> Option<AST> {
     None
 }
 
-fn list_ast_indices(ast: Vec<AST>) -> Vec<usize> {
+/// Tag of an image name, the first word of it
+pub fn image_tag(image: &str) -> &str {
+    image.split_whitespace().next().unwrap_or_default()
+}
+
+/// Bodies of every `define` statement, in script order
+pub fn list_defines(ast: &[AST]) -> Vec<String> {
+    let mut defines = Vec::new();
+
+    for node in ast.iter() {
+        match node {
+            AST::Define(_, definition) => defines.push(definition.clone()),
+            AST::Label(_, _, label_ast, _) => defines.extend(list_defines(label_ast)),
+            _ => {}
+        }
+    }
+
+    defines
+}
+
+/// Moves every node of `ast` at or after `index` one line down, inside labels too
+fn shift_ast(ast: &mut [AST], index: usize) {
+    for node in ast.iter_mut() {
+        if let AST::Label(_, _, label_ast, _) = node {
+            shift_ast(label_ast, index);
+        }
+        if !matches!(node, AST::Error) && node.index() >= index {
+            node.set_index(node.index() + 1);
+        }
+    }
+}
+
+/// Inserts `node` in index order into the body of the last label starting before it
+fn insert_node(ast: &mut Vec<AST>, node: AST) {
+    let label = ast
+        .iter_mut()
+        .rfind(|label| matches!(label, AST::Label(..)) && label.index() < node.index());
+
+    if let Some(AST::Label(_, _, label_ast, _)) = label {
+        insert_node(label_ast, node);
+        return;
+    }
+
+    let position = ast
+        .iter()
+        .position(|other| !matches!(other, AST::Error) && other.index() >= node.index())
+        .unwrap_or(ast.len());
+    ast.insert(position, node);
+}
+
+/// Label `index` is in, the one starting last at or before it
+pub(crate) fn enclosing_label(
+    labels: &HashMap<String, usize>,
+    index: usize,
+) -> Option<(&String, usize)> {
+    labels
+        .iter()
+        .filter(|(_, label_index)| **label_index <= index)
+        .max_by_key(|(_, label_index)| **label_index)
+        .map(|(label, label_index)| (label, *label_index))
+}
+
+pub(crate) fn build_label_table(ast: &[AST]) -> HashMap<String, usize> {
+    let mut labels = HashMap::new();
+
+    for node in ast.iter() {
+        if let AST::Label(index, label, label_ast, _) = node {
+            labels.insert(label.clone(), *index);
+            labels.extend(build_label_table(label_ast));
+        }
+    }
+
+    labels
+}
+
+pub(crate) fn list_ast_indices(ast: Vec<AST>) -> Vec<usize> {
     let mut indices: Vec<usize> = ast
         .iter()
-        .map(|a| match a {
-            AST::Return(i, _) => *i,
-            AST::Jump(i, _, _) => *i,
-            AST::Scene(i, _, _) => *i,
-            AST::Show(i, _) => *i,
-            AST::Hide(i, _) => *i,
-            AST::Label(i, _, _, _) => *i,
-            AST::Say(i, _, _) => *i,
-            AST::Play(i, _, _) => *i,
-            AST::Define(i, _) => *i,
-            AST::Stop(i, _, _, _) => *i,
-            AST::GameMechanic(i, _) => *i,
-            AST::LLMGenerate(i, _, _) => *i,
-            AST::Comment(i, _) => *i,
-            AST::SceneGenerate(i, _) => *i,
-            AST::MusicGenerate(i, _) => *i,
-            AST::Error => panic!(),
+        .filter_map(|a| match a {
+            AST::Return(i, _) => Some(*i),
+            AST::Jump(i, _, _) => Some(*i),
+            AST::Scene(i, _, _) => Some(*i),
+            AST::Show(i, _) => Some(*i),
+            AST::Hide(i, _) => Some(*i),
+            AST::Label(i, _, _, _) => Some(*i),
+            AST::Say(i, _, _) => Some(*i),
+            AST::Play(i, _, _) => Some(*i),
+            AST::Define(i, _) => Some(*i),
+            AST::Stop(i, _, _, _) => Some(*i),
+            AST::GameMechanic(i, _) => Some(*i),
+            AST::LLMGenerate(i, _, _) => Some(*i),
+            AST::Comment(i, _) => Some(*i),
+            AST::SceneGenerate(i, _) => Some(*i),
+            AST::MusicGenerate(i, _) => Some(*i),
+            AST::Error => None,
         })
         .collect();
 
     for ast in ast {
-        match ast {
-            AST::Label(_, _, vec, _) => {
-                indices.extend_from_slice(list_ast_indices(vec).as_slice());
-            }
-            _ => {}
+        if let AST::Label(_, _, vec, _) = ast {
+            indices.extend_from_slice(list_ast_indices(vec).as_slice());
         }
     }
 
     indices
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skips_error_nodes() {
+        let ast = vec![
+            AST::Error,
+            AST::Label(
+                1,
+                "start".to_string(),
+                vec![AST::Error, AST::Say(2, None, "Hello".to_string())],
+                None,
+            ),
+        ];
+
+        assert_eq!(list_ast_indices(ast.clone()), vec![1, 2]);
+        assert!(matches!(
+            find_element_with_index(ast.clone(), 1),
+            Some(AST::Label(..))
+        ));
+        assert!(find_element_with_index(ast, 2).is_none());
+    }
+
+    #[test]
+    fn injects_around_error_nodes() {
+        let mut novel_data = NovelData::default();
+        novel_data.set_scenario(
+            vec![AST::Label(
+                1,
+                "start".to_string(),
+                vec![
+                    AST::Say(2, None, "Before".to_string()),
+                    AST::Error,
+                    AST::Say(3, None, "After".to_string()),
+                ],
+                None,
+            )],
+            Statements::default(),
+        );
+
+        novel_data.push_text_node(None, "Generated".to_string(), 3);
+        novel_data.replace_node(AST::Say(3, None, "Replaced".to_string()));
+
+        let AST::Label(_, _, label_ast, _) = &novel_data.ast[0] else {
+            panic!("start is not a label");
+        };
+        assert!(matches!(&label_ast[2], AST::Say(3, _, what) if what == "Replaced"));
+        assert!(matches!(&label_ast[3], AST::Say(4, _, what) if what == "After"));
+    }
+}
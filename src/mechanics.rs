@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+
+use bevy::{ecs::system::SystemId, prelude::*};
+use renpy_parser::parsers::AST;
+
+use crate::{
+    NovelData,
+    messages::{EventGameMechanic, EventHandleNode, EventResumeNovel, EventSwitchNextNode},
+    variables::NovelVariables,
+};
+
+/// Variable the result of a game mechanic is stored in, as `_return` is in Ren'Py
+pub const MECHANIC_RESULT_VARIABLE: &str = "_return";
+
+/// Systems run by `game_mechanic` statements, keyed by mechanic name. A system gets the
+/// arguments written after the name, and the story waits until the game writes
+/// [`EventResumeNovel`]. Systems are registered with `App::register_system`.
+#[derive(Resource, Clone, Default)]
+pub struct NovelMechanics(HashMap<String, SystemId<In<Vec<String>>>>);
+
+impl NovelMechanics {
+    pub fn register(&mut self, name: impl Into<String>, system: SystemId<In<Vec<String>>>) {
+        self.0.insert(name.into(), system);
+    }
+
+    pub fn get(&self, name: &str) -> Option<SystemId<In<Vec<String>>>> {
+        self.0.get(name).copied()
+    }
+}
+
+/// Splits `game_mechanic "battle goblin 3"` into the mechanic name and its arguments
+pub fn parse_mechanic(mechanic: &str) -> Option<(String, Vec<String>)> {
+    let mut words = mechanic.split_whitespace().map(String::from);
+    let name = words.next()?;
+
+    Some((name, words.collect()))
+}
+
+/// Hands control to the game on `game_mechanic` statements
+pub fn handle_game_mechanic(
+    mut commands: Commands,
+    mut er_handle_node: MessageReader<EventHandleNode>,
+    mut novel_data: ResMut<NovelData>,
+    mechanics: Res<NovelMechanics>,
+    mut ew_game_mechanic: MessageWriter<EventGameMechanic>,
+    mut ew_switch_next_node: MessageWriter<EventSwitchNextNode>,
+) {
+    for event in er_handle_node.read() {
+        let AST::GameMechanic(_, mechanic) = &event.ast else {
+            continue;
+        };
+
+        let Some((name, args)) = parse_mechanic(mechanic) else {
+            warn!("game_mechanic without a name");
+            ew_switch_next_node.write(EventSwitchNextNode {});
+            continue;
+        };
+
+        match mechanics.get(&name) {
+            Some(system) => {
+                commands.run_system_with(system, args.clone());
+            }
+            // The game may still answer EventGameMechanic itself
+            None => warn!(
+                "game_mechanic {}: no system registered with NovelMechanics::register",
+                name
+            ),
+        }
+
+        novel_data.mechanic = Some(name.clone());
+        ew_game_mechanic.write(EventGameMechanic { name, args });
+    }
+}
+
+pub fn handle_resume_novel(
+    mut er_resume_novel: MessageReader<EventResumeNovel>,
+    mut novel_data: ResMut<NovelData>,
+    mut variables: ResMut<NovelVariables>,
+    mut ew_switch_next_node: MessageWriter<EventSwitchNextNode>,
+) {
+    for event in er_resume_novel.read() {
+        if novel_data.mechanic.take().is_none() {
+            warn!("EventResumeNovel was written with no game mechanic running");
+            continue;
+        }
+
+        match event.result.clone() {
+            Some(result) => variables.set(MECHANIC_RESULT_VARIABLE, result),
+            None => {
+                variables.remove(MECHANIC_RESULT_VARIABLE);
+            }
+        }
+
+        ew_switch_next_node.write(EventSwitchNextNode {});
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::variables::NovelValue;
+
+    #[test]
+    fn parses_mechanics() {
+        assert_eq!(
+            parse_mechanic("battle goblin 3"),
+            Some((
+                "battle".to_string(),
+                vec!["goblin".to_string(), "3".to_string()]
+            ))
+        );
+        assert_eq!(
+            parse_mechanic("  puzzle  "),
+            Some(("puzzle".to_string(), Vec::new()))
+        );
+        assert_eq!(parse_mechanic(""), None);
+        assert_eq!(parse_mechanic("   "), None);
+    }
+
+    fn app() -> App {
+        let mut app = App::new();
+        app.add_message::<EventResumeNovel>()
+            .add_message::<EventSwitchNextNode>()
+            .init_resource::<NovelData>()
+            .init_resource::<NovelVariables>()
+            .add_systems(Update, handle_resume_novel);
+        app
+    }
+
+    fn resume(app: &mut App, result: Option<NovelValue>) -> usize {
+        app.world_mut().write_message(EventResumeNovel { result });
+        app.update();
+
+        let messages = app.world().resource::<Messages<EventSwitchNextNode>>();
+        messages.get_cursor().read(messages).count()
+    }
+
+    #[test]
+    fn stores_mechanic_result() {
+        let mut app = app();
+
+        app.world_mut().resource_mut::<NovelData>().mechanic = Some("battle".to_string());
+        assert_eq!(resume(&mut app, Some(NovelValue::Int(3))), 1);
+        assert_eq!(
+            app.world()
+                .resource::<NovelVariables>()
+                .get(MECHANIC_RESULT_VARIABLE),
+            Some(&NovelValue::Int(3))
+        );
+        assert!(app.world().resource::<NovelData>().mechanic.is_none());
+
+        // Resuming without a result clears the one before
+        app.world_mut().resource_mut::<NovelData>().mechanic = Some("battle".to_string());
+        resume(&mut app, None);
+        assert_eq!(
+            app.world()
+                .resource::<NovelVariables>()
+                .get(MECHANIC_RESULT_VARIABLE),
+            None
+        );
+    }
+
+    #[test]
+    fn ignores_resume_without_mechanic() {
+        let mut app = app();
+
+        assert_eq!(resume(&mut app, Some(NovelValue::Int(3))), 0);
+        assert_eq!(
+            app.world()
+                .resource::<NovelVariables>()
+                .get(MECHANIC_RESULT_VARIABLE),
+            None
+        );
+    }
+}
@@ -0,0 +1,100 @@
+use bevy::prelude::*;
+
+use crate::{NovelData, NovelSettings, messages::*};
+
+const BUTTON_COLOR: Color = Color::srgba(0.1, 0.1, 0.1, 0.85);
+const BUTTON_HOVER_COLOR: Color = Color::srgba(0.25, 0.25, 0.25, 0.9);
+
+/// Container of the built-in menu buttons
+#[derive(Component)]
+pub struct NovelMenu;
+
+/// Built-in menu button, holding the index of the choice it picks
+#[derive(Component)]
+pub struct NovelMenuButton(pub usize);
+
+pub fn spawn_menu(
+    mut commands: Commands,
+    mut er_show_menu: MessageReader<EventShowMenu>,
+    novel_settings: Res<NovelSettings>,
+    menus: Query<Entity, With<NovelMenu>>,
+) {
+    for event in er_show_menu.read() {
+        if novel_settings.custom_menu_ui {
+            continue;
+        }
+
+        for entity in menus.iter() {
+            commands.entity(entity).despawn();
+        }
+
+        commands
+            .spawn((
+                NovelMenu,
+                Name::new("Novel Menu"),
+                Node {
+                    position_type: PositionType::Absolute,
+                    width: percent(100),
+                    bottom: px(80),
+                    flex_direction: FlexDirection::Column,
+                    align_items: AlignItems::Center,
+                    row_gap: px(8),
+                    ..default()
+                },
+            ))
+            .with_children(|p| {
+                for (index, option) in event.options.iter().enumerate() {
+                    p.spawn((
+                        Button,
+                        NovelMenuButton(index),
+                        Name::new(format!("Novel Menu Button {}", index)),
+                        Node {
+                            min_width: px(320),
+                            padding: UiRect::axes(px(16), px(8)),
+                            justify_content: JustifyContent::Center,
+                            ..default()
+                        },
+                        BackgroundColor(BUTTON_COLOR),
+                    ))
+                    .with_child(Text::new(option.clone()));
+                }
+            });
+    }
+}
+
+#[allow(clippy::type_complexity)]
+pub fn handle_menu_buttons(
+    mut buttons: Query<
+        (&Interaction, &NovelMenuButton, &mut BackgroundColor),
+        Changed<Interaction>,
+    >,
+    mut ew_menu_choice: MessageWriter<EventMenuChoice>,
+) {
+    for (interaction, button, mut color) in buttons.iter_mut() {
+        match interaction {
+            Interaction::Pressed => {
+                ew_menu_choice.write(EventMenuChoice { index: button.0 });
+            }
+            Interaction::Hovered => {
+                *color = BackgroundColor(BUTTON_HOVER_COLOR);
+            }
+            Interaction::None => {
+                *color = BackgroundColor(BUTTON_COLOR);
+            }
+        }
+    }
+}
+
+pub fn despawn_menu(
+    mut commands: Commands,
+    novel_data: Res<NovelData>,
+    menus: Query<Entity, With<NovelMenu>>,
+) {
+    if novel_data.menu.is_some() {
+        return;
+    }
+
+    for entity in menus.iter() {
+        commands.entity(entity).despawn();
+    }
+}
@@ -1,5 +1,6 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::Duration;
 
 use bevy::prelude::*;
 use renpy_parser::parsers::AST;
@@ -7,8 +8,28 @@ use renpy_parser::parsers::AST;
 use bevy_kira_audio::prelude::*;
 
 use crate::{
-    MusicHandle, NovelBackground, NovelData, NovelImage, NovelSettings, NovelText, NovelTextWhat,
-    NovelTextWho, find_element_with_index, list_ast_indices,
+    MusicHandle, NovelBackground, NovelData, NovelError, NovelImage, NovelSettings, NovelSideImage,
+    NovelText, NovelTextWho, PendingAudioStops, VoiceHandle,
+    audio::{MUSIC_CHANNEL, NovelAudioSettings, SOUND_CHANNEL, VOICE_CHANNEL},
+    backlog::NovelBacklog,
+    characters::parse_character_define,
+    find_element_with_index,
+    generate::{NovelGenerationEntities, cancel_generations},
+    image_tag,
+    input::{NovelAction, NovelInputs},
+    list_ast_indices, list_defines,
+    rpy_asset_loader::Rpy,
+    save::NovelSaveState,
+    scenario::Scenario,
+    script::{Statement, Statements},
+    skip::NovelSkip,
+    text::NovelTextReveal,
+    transforms::{NovelTransform, NovelTransforms, split_at_clause},
+    transitions::{
+        NovelTransitionNew, NovelTransitionOld, NovelTransitionOverlay, NovelTransitionState,
+        NovelTransitions, resolve_transition, split_with_clause,
+    },
+    variables::{NovelValue, NovelVariables},
 };
 
 #[derive(Clone, Message)]
@@ -16,9 +37,22 @@ pub struct Scene {
     pub image: String,
 }
 
+/// Shows `image`, replacing the image already shown with the same tag
 #[derive(Clone, Message)]
 pub struct EventShow {
     pub image: String,
+    /// Name of a transform registered in [`NovelTransforms`]
+    pub at: Option<String>,
+    /// Name of the transition played, registered in [`NovelTransitions`]
+    pub with: Option<String>,
+}
+
+/// Hides the image shown with the tag of `image`
+#[derive(Clone, Message)]
+pub struct EventHide {
+    pub image: String,
+    /// Name of the transition played, registered in [`NovelTransitions`]
+    pub with: Option<String>,
 }
 
 #[derive(Clone, Message)]
@@ -32,11 +66,51 @@ pub struct EventLabel {
 }
 
 #[derive(Clone, Message)]
-pub struct EventReturn {}
+pub struct EventCall {
+    pub label: String,
+}
 
 #[derive(Clone, Message)]
+pub struct EventReturn {}
+
+#[derive(Clone, Default, Message)]
 pub struct EventStartScenario {
     pub ast: Vec<AST>,
+    pub statements: Statements,
+    /// Files the script comes from, the running scenario is reloaded when one of them
+    /// changes
+    pub files: Vec<AssetId<Rpy>>,
+    /// Label to start at, from the beginning of the script when `None`
+    pub entry_label: Option<String>,
+    /// Variables the story starts with, they take the place of the script's defaults.
+    /// Variables of a previous run are cleared.
+    pub variables: NovelVariables,
+}
+
+impl EventStartScenario {
+    /// Starts the script of an `Rpy` asset, hot-reloading it when the file changes. The
+    /// script is built as `Scenario::merge` builds it, so a reload doesn't change it.
+    pub fn from_asset(id: impl Into<AssetId<Rpy>>, rpy: &Rpy) -> Self {
+        EventStartScenario::from(&Scenario::merge([(id.into(), rpy)]))
+    }
+
+    /// Starts the story with `name` set to `value` instead of its default
+    pub fn with_variable(mut self, name: impl Into<String>, value: impl Into<NovelValue>) -> Self {
+        self.variables.set(name, value);
+        self
+    }
+}
+
+impl From<&Rpy> for EventStartScenario {
+    fn from(rpy: &Rpy) -> Self {
+        EventStartScenario {
+            ast: rpy.ast.clone(),
+            statements: rpy.statements.clone(),
+            files: Vec::new(),
+            entry_label: rpy.entry_label.clone(),
+            variables: NovelVariables::default(),
+        }
+    }
 }
 
 #[derive(Clone, Message)]
@@ -50,6 +124,67 @@ pub struct EventHandleNode {
     pub ast: AST,
 }
 
+#[derive(Clone, Message)]
+pub struct EventHandleStatement {
+    pub statement: Statement,
+}
+
+/// Sent when a `menu:` block is reached, with the captions of its choices
+#[derive(Clone, Message)]
+pub struct EventShowMenu {
+    pub options: Vec<String>,
+}
+
+/// Picks the choice at `index` of the options sent with [`EventShowMenu`]
+#[derive(Clone, Message)]
+pub struct EventMenuChoice {
+    pub index: usize,
+}
+
+/// Sent when a `game_mechanic` statement is reached, the story waits for
+/// [`EventResumeNovel`]
+#[derive(Clone, Message)]
+pub struct EventGameMechanic {
+    pub name: String,
+    pub args: Vec<String>,
+}
+
+/// Continues the story after a game mechanic, storing `result` in the `_return` variable
+#[derive(Clone, Message)]
+pub struct EventResumeNovel {
+    pub result: Option<NovelValue>,
+}
+
+/// Saves the novel state to the numbered slot in [`NovelSettings::save_directory`]
+#[derive(Clone, Message)]
+pub struct EventSaveGame {
+    pub slot: usize,
+}
+
+/// Loads the novel state saved in the numbered slot
+#[derive(Clone, Message)]
+pub struct EventLoadGame {
+    pub slot: usize,
+}
+
+/// Steps back to the previous say line
+#[derive(Clone, Message)]
+pub struct EventRollback {}
+
+/// Steps forward to the line left by the last [`EventRollback`]
+#[derive(Clone, Message)]
+pub struct EventRollForward {}
+
+/// Opens or closes the backlog screen
+#[derive(Clone, Message)]
+pub struct EventToggleBacklog {}
+
+/// Puts the novel back in the state of a snapshot
+#[derive(Clone, Message)]
+pub struct EventRestoreState {
+    pub state: NovelSaveState,
+}
+
 #[derive(Clone, Message)]
 pub struct EventSay {
     pub data: String,
@@ -60,21 +195,45 @@ pub enum AudioMode {
     Sound,
     Music,
     Voice,
+    /// Channel declared with [`NovelAudioSettings::add_channel`](crate::audio::NovelAudioSettings::add_channel),
+    /// played like sound
+    Custom(String),
+}
+
+impl AudioMode {
+    /// Name of the audio channel the mode plays through
+    pub fn channel(&self) -> &str {
+        match self {
+            AudioMode::Sound => SOUND_CHANNEL,
+            AudioMode::Music => MUSIC_CHANNEL,
+            AudioMode::Voice => VOICE_CHANNEL,
+            AudioMode::Custom(name) => name,
+        }
+    }
+
+    /// Mode of the channel a script names, `None` for a channel that isn't built in or
+    /// declared with [`NovelAudioSettings::add_channel`]
+    pub fn from_channel(name: &str, audio_settings: &NovelAudioSettings) -> Option<Self> {
+        match name.parse().ok()? {
+            AudioMode::Custom(name) if audio_settings.channel(&name).is_none() => None,
+            mode => Some(mode),
+        }
+    }
 }
 
-// implement from_string trait for audio mode
+/// Mode of a channel name, without checking that the channel is declared: names other than
+/// `sound`, `music` and `voice` give [`AudioMode::Custom`]
 impl FromStr for AudioMode {
     type Err = ();
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let empty_string = "";
-        let character = "\"";
-        let s = s.replace(character, empty_string);
+        let s = s.replace('"', "");
 
-        match s.as_ref() {
-            "sound" => Ok(AudioMode::Sound),
-            "music" => Ok(AudioMode::Music),
-            "voice" => Ok(AudioMode::Voice),
-            _ => Err(()),
+        match s.as_str() {
+            "" => Err(()),
+            SOUND_CHANNEL => Ok(AudioMode::Sound),
+            MUSIC_CHANNEL => Ok(AudioMode::Music),
+            VOICE_CHANNEL => Ok(AudioMode::Voice),
+            name => Ok(AudioMode::Custom(name.to_string())),
         }
     }
 }
@@ -83,11 +242,106 @@ impl FromStr for AudioMode {
 pub struct EventPlayAudio {
     pub filename: String,
     pub audio_mode: AudioMode,
+    /// Seconds the audio fades in over
+    pub fade_in: Option<f32>,
+    /// Seconds the audio it replaces fades out over
+    pub fade_out: Option<f32>,
+    /// Loops when set, else only music loops
+    pub looped: Option<bool>,
+}
+
+impl EventPlayAudio {
+    pub fn new(filename: impl Into<String>, audio_mode: AudioMode) -> Self {
+        EventPlayAudio {
+            filename: filename.into(),
+            audio_mode,
+            fade_in: None,
+            fade_out: None,
+            looped: None,
+        }
+    }
+}
+
+/// Stops whatever plays in `audio_mode`
+#[derive(Clone, Message)]
+pub struct EventStopAudio {
+    pub audio_mode: AudioMode,
+    /// Seconds the audio fades out over
+    pub fade_out: Option<f32>,
+}
+
+/// Linear tween over `seconds`, or the near instant default one
+fn audio_tween(seconds: Option<f32>) -> AudioTween {
+    let Some(seconds) = seconds else {
+        return AudioTween::default();
+    };
+
+    match Duration::try_from_secs_f32(seconds.max(0.0)) {
+        Ok(duration) => AudioTween::linear(duration),
+        Err(_) => {
+            warn!("Invalid fade of {} seconds, fading instantly", seconds);
+            AudioTween::default()
+        }
+    }
+}
+
+/// Stops the instance behind `handle` if it still plays. Kira only creates the instance once
+/// the audio loaded, until then the play is queued and stopped later by [`stop_pending_audio`].
+fn stop_instance(
+    channels: &DynamicAudioChannels,
+    audio_instances: &mut Assets<AudioInstance>,
+    pending_stops: &mut PendingAudioStops,
+    channel: &str,
+    handle: &Handle<AudioInstance>,
+    tween: AudioTween,
+) {
+    let queued = channels
+        .get_channel(channel)
+        .is_some_and(|channel| matches!(channel.state(handle), PlaybackState::Queued));
+
+    if !queued {
+        if let Some(mut instance) = audio_instances.get_mut(handle) {
+            instance.stop(tween);
+        }
+    } else if !pending_stops
+        .0
+        .iter()
+        .any(|(_, pending, _)| pending == handle)
+    {
+        pending_stops
+            .0
+            .push((channel.to_string(), handle.clone(), tween));
+    }
+}
+
+/// Stops the plays replaced while queued once kira created their instance
+pub fn stop_pending_audio(
+    channels: Res<DynamicAudioChannels>,
+    mut audio_instances: ResMut<Assets<AudioInstance>>,
+    mut pending_stops: ResMut<PendingAudioStops>,
+) {
+    pending_stops.0.retain(|(channel, handle, tween)| {
+        let queued = channels
+            .get_channel(channel)
+            .is_some_and(|channel| matches!(channel.state(handle), PlaybackState::Queued));
+        if queued {
+            return true;
+        }
+
+        if let Some(mut instance) = audio_instances.get_mut(handle) {
+            instance.stop(tween.clone());
+        }
+        false
+    });
 }
 
 #[derive(Message)]
 pub struct EventShowTextNode {}
 
+/// Sent once the dialogue line is fully shown, either typed out or completed by the player
+#[derive(Message)]
+pub struct EventTextRevealed {}
+
 #[derive(Message)]
 pub struct EventHideTextNode {}
 
@@ -97,53 +351,341 @@ pub struct EventShowImageNode {}
 #[derive(Message)]
 pub struct EventHideImageNode {}
 
+#[allow(clippy::too_many_arguments)]
 pub fn handle_play_audio(
     asset_server: Res<AssetServer>,
-    audio: Res<Audio>,
-    music_handle: ResMut<MusicHandle>,
+    channels: Res<DynamicAudioChannels>,
+    mut music_handle: ResMut<MusicHandle>,
+    mut voice_handle: ResMut<VoiceHandle>,
     mut audio_instances: ResMut<Assets<AudioInstance>>,
-    mut commands: Commands,
+    mut pending_stops: ResMut<PendingAudioStops>,
     mut er_play_audio: MessageReader<EventPlayAudio>,
     plugin_settings: Res<NovelSettings>,
+    mut novel_data: ResMut<NovelData>,
 ) {
     let base_path = PathBuf::from(&plugin_settings.assets_path);
+    let events: Vec<&EventPlayAudio> = er_play_audio.read().collect();
+
+    for (index, event) in events.iter().enumerate() {
+        // Kira creates the instance of a play later in the frame, too late for a play on the
+        // same music or voice channel to stop it. A play replaced that way doesn't start.
+        if matches!(event.audio_mode, AudioMode::Music | AudioMode::Voice)
+            && events[index + 1..]
+                .iter()
+                .any(|later| later.audio_mode == event.audio_mode)
+        {
+            continue;
+        }
+
+        let Some(channel) = channels.get_channel(event.audio_mode.channel()) else {
+            warn!(
+                "play {}: unknown audio channel, declare it with NovelAudioSettings::add_channel",
+                event.audio_mode.channel()
+            );
+            continue;
+        };
+
+        let asset_handle = match novel_data.cached_audio.get(&event.filename) {
+            Some(handle) => handle.clone(),
+            None => asset_server.load(base_path.join(event.filename.clone())),
+        };
+        let mut play_event = channel.play(asset_handle);
 
-    for event in er_play_audio.read() {
-        let asset_path = base_path.join(event.filename.clone());
-        let asset_handle = asset_server.load(asset_path);
-        let mut play_event = audio.play(asset_handle);
+        if let Some(fade_in) = event.fade_in {
+            play_event.fade_in(audio_tween(Some(fade_in)));
+        }
+        if event.looped.unwrap_or(event.audio_mode == AudioMode::Music) {
+            play_event.looped();
+        }
 
-        if event.audio_mode == AudioMode::Music {
-            if let Some(handle) = music_handle.clone().0 {
-                if let Some(mut instance) = audio_instances.get_mut(&handle) {
-                    instance.stop(AudioTween::default());
+        let handle = play_event.handle();
+        let fade_out = audio_tween(event.fade_out);
+
+        match event.audio_mode {
+            AudioMode::Music => {
+                if let Some(old_handle) = music_handle.0.as_ref() {
+                    stop_instance(
+                        &channels,
+                        &mut audio_instances,
+                        &mut pending_stops,
+                        event.audio_mode.channel(),
+                        old_handle,
+                        fade_out,
+                    );
                 }
+
+                music_handle.0 = Some(handle);
+                novel_data.music = Some(event.filename.clone());
             }
+            AudioMode::Voice => {
+                // Only one voice line speaks at a time
+                if let Some(old_handle) = voice_handle.0.as_ref() {
+                    stop_instance(
+                        &channels,
+                        &mut audio_instances,
+                        &mut pending_stops,
+                        event.audio_mode.channel(),
+                        old_handle,
+                        fade_out,
+                    );
+                }
 
-            let handle = play_event.looped().handle();
-            commands.insert_resource(MusicHandle(Some(handle)));
+                voice_handle.0 = Some(handle);
+            }
+            // Sounds overlap
+            AudioMode::Sound | AudioMode::Custom(_) => {}
+        }
+    }
+}
+
+pub fn handle_stop_audio(
+    mut er_stop_audio: MessageReader<EventStopAudio>,
+    channels: Res<DynamicAudioChannels>,
+    mut music_handle: ResMut<MusicHandle>,
+    mut voice_handle: ResMut<VoiceHandle>,
+    mut novel_data: ResMut<NovelData>,
+) {
+    for event in er_stop_audio.read() {
+        let Some(channel) = channels.get_channel(event.audio_mode.channel()) else {
+            warn!("stop {}: unknown audio channel", event.audio_mode.channel());
+            continue;
+        };
+
+        channel.stop().fade_out(audio_tween(event.fade_out));
+
+        match event.audio_mode {
+            AudioMode::Music => {
+                music_handle.0 = None;
+                novel_data.music = None;
+            }
+            AudioMode::Voice => voice_handle.0 = None,
+            AudioMode::Sound | AudioMode::Custom(_) => {}
+        }
+    }
+}
+
+/// Plays the voice given with `voice` along with the next say line. Recording it in the
+/// backlog, which clears it, runs after.
+pub fn play_voice(
+    mut er_handle_node: MessageReader<EventHandleNode>,
+    novel_data: Res<NovelData>,
+    voice_handle: Res<VoiceHandle>,
+    channels: Res<DynamicAudioChannels>,
+    mut audio_instances: ResMut<Assets<AudioInstance>>,
+    mut pending_stops: ResMut<PendingAudioStops>,
+    mut ew_play_audio: MessageWriter<EventPlayAudio>,
+) {
+    for event in er_handle_node.read() {
+        if !matches!(event.ast, AST::Say(_, _, _)) {
+            continue;
+        }
+
+        // Lines brought back by rollback don't speak again
+        if novel_data.replaying {
+            continue;
+        }
+
+        // A voice line lasts until the next say line
+        if let Some(handle) = voice_handle.0.as_ref() {
+            stop_instance(
+                &channels,
+                &mut audio_instances,
+                &mut pending_stops,
+                VOICE_CHANNEL,
+                handle,
+                AudioTween::default(),
+            );
+        }
+
+        if let Some(filename) = novel_data.voice.clone() {
+            ew_play_audio.write(EventPlayAudio::new(filename, AudioMode::Voice));
+        }
+    }
+}
+
+/// Runs `stop` nodes built by `renpy_parser`, written lines are lifted into
+/// [`Statement::Stop`] instead
+pub fn handle_stop_node(
+    mut er_handle_node: MessageReader<EventHandleNode>,
+    novel_settings: Res<NovelSettings>,
+    mut ew_stop_audio: MessageWriter<EventStopAudio>,
+    mut ew_switch_next_node: MessageWriter<EventSwitchNextNode>,
+) {
+    for event in er_handle_node.read() {
+        let AST::Stop(_, mode, _, fade_out) = &event.ast else {
+            continue;
+        };
+
+        match AudioMode::from_channel(mode, &novel_settings.audio) {
+            Some(audio_mode) => {
+                ew_stop_audio.write(EventStopAudio {
+                    audio_mode,
+                    fade_out: *fade_out,
+                });
+            }
+            None => error!("stop {}: unknown channel", mode),
+        }
+
+        ew_switch_next_node.write(EventSwitchNextNode {});
+    }
+}
+
+/// Runs the `define`s of the script other than characters, its constants, and sets the
+/// `default`s of variables not set yet
+pub(crate) fn define_variables(
+    variables: &mut NovelVariables,
+    ast: &[AST],
+    statements: &Statements,
+) {
+    for definition in list_defines(ast) {
+        if parse_character_define(&definition).is_none()
+            && let Err(err) = variables.execute(&definition)
+        {
+            error!("define {}: {}", definition, err);
+        }
+    }
+
+    for statement in statements.values() {
+        if let Statement::Default(name, expression) = statement
+            && !variables.contains(name)
+        {
+            match variables.evaluate(expression) {
+                Ok(value) => variables.set(name.clone(), value),
+                Err(err) => error!("default {} = {}: {}", name, expression, err),
+            }
         }
     }
 }
 
 pub fn handle_start_scenario(
+    mut commands: Commands,
     mut er_start_scenario: MessageReader<EventStartScenario>,
+    generations: NovelGenerationEntities,
     mut novel_data: ResMut<NovelData>,
+    mut variables: ResMut<NovelVariables>,
     mut ew_event_switch_next_node: MessageWriter<EventSwitchNextNode>,
 ) {
     for event in er_start_scenario.read() {
-        novel_data.current_index = 0;
-        novel_data.ast = event.ast.clone();
+        cancel_generations(&mut commands, &generations, &mut novel_data);
+        novel_data.set_scenario(event.ast.clone(), event.statements.clone());
+        novel_data.files = event.files.clone();
+        *variables = event.variables.clone();
+        define_variables(&mut variables, &event.ast, &event.statements);
+
+        if let Some(label) = &event.entry_label
+            && let Err(err) = novel_data.jump_to_label(label)
+        {
+            error!("Starting the scenario: {}", err);
+        }
+
         ew_event_switch_next_node.write(EventSwitchNextNode {});
     }
 }
 
+pub fn handle_call(
+    mut er_call: MessageReader<EventCall>,
+    mut novel_data: ResMut<NovelData>,
+    mut ew_jump: MessageWriter<EventJump>,
+    mut ew_novel_end: MessageWriter<EventNovelEnd>,
+) {
+    for event in er_call.read() {
+        // A call that can't jump leaves nothing on the stack for a later return
+        if !novel_data.labels.contains_key(&event.label) {
+            error!("{}", NovelError::LabelNotFound(event.label.clone()));
+            ew_novel_end.write(EventNovelEnd {});
+            continue;
+        }
+
+        let return_index = novel_data.current_index;
+        novel_data.call_stack.push(return_index);
+
+        ew_jump.write(EventJump {
+            label: event.label.clone(),
+        });
+    }
+}
+
+pub fn handle_return(
+    mut er_return: MessageReader<EventReturn>,
+    mut novel_data: ResMut<NovelData>,
+    mut ew_event_switch_next_node: MessageWriter<EventSwitchNextNode>,
+    mut ew_novel_end: MessageWriter<EventNovelEnd>,
+) {
+    for _ in er_return.read() {
+        match novel_data.call_stack.pop() {
+            Some(return_index) => {
+                novel_data.current_index = return_index;
+                ew_event_switch_next_node.write(EventSwitchNextNode {});
+            }
+            None => {
+                ew_novel_end.write(EventNovelEnd {});
+            }
+        }
+    }
+}
+
+pub fn handle_jump(
+    mut er_jump: MessageReader<EventJump>,
+    mut novel_data: ResMut<NovelData>,
+    mut ew_event_switch_next_node: MessageWriter<EventSwitchNextNode>,
+    mut ew_novel_end: MessageWriter<EventNovelEnd>,
+) {
+    for event in er_jump.read() {
+        match novel_data.jump_to_label(&event.label) {
+            Ok(()) => {
+                ew_event_switch_next_node.write(EventSwitchNextNode {});
+            }
+            Err(err) => {
+                error!("{}", err);
+                ew_novel_end.write(EventNovelEnd {});
+            }
+        }
+    }
+}
+
 pub fn handle_switch_next_node(
     mut novel_data: ResMut<NovelData>,
     mut er_event_switch_next_node: MessageReader<EventSwitchNextNode>,
     mut ew_handle_node: MessageWriter<EventHandleNode>,
+    mut ew_handle_statement: MessageWriter<EventHandleStatement>,
     mut ew_novel_end: MessageWriter<EventNovelEnd>,
+    mut transition_state: ResMut<NovelTransitionState>,
+    mut text_reveal: ResMut<NovelTextReveal>,
 ) {
+    if text_reveal.is_running() {
+        // Advancing completes the line being typed out, up to its next `{w}`
+        if er_event_switch_next_node.read().count() > 0 {
+            text_reveal.advance();
+        }
+        return;
+    }
+
+    if transition_state.is_running() {
+        // Advancing during a transition skips it, the story moves on once it has finished
+        if er_event_switch_next_node.read().count() > 0 {
+            transition_state.skip();
+        }
+        return;
+    }
+
+    if novel_data.menu.is_some() {
+        // The story only moves on through EventMenuChoice while a menu is open
+        er_event_switch_next_node.clear();
+        return;
+    }
+
+    if novel_data.mechanic.is_some() {
+        // The game hands control back with EventResumeNovel
+        er_event_switch_next_node.clear();
+        return;
+    }
+
+    if novel_data.generating {
+        // The generated line is switched to once it has been injected
+        er_event_switch_next_node.clear();
+        return;
+    }
+
     let mut switched = false;
 
     for _ in er_event_switch_next_node.read() {
@@ -151,7 +693,8 @@ pub fn handle_switch_next_node(
             let current_index = novel_data.current_index;
 
             let next_index = current_index + 1;
-            let indices = list_ast_indices(novel_data.ast.clone());
+            let mut indices = list_ast_indices(novel_data.ast.clone());
+            indices.extend(novel_data.statements.keys());
             let max_index = *indices.iter().max().unwrap_or(&0);
 
             if next_index > max_index {
@@ -162,15 +705,21 @@ pub fn handle_switch_next_node(
 
             novel_data.current_index = next_index;
 
-            let next_element = find_element_with_index(novel_data.ast.clone(), next_index);
-            if next_element.is_some() {
-                ew_handle_node.write(EventHandleNode {
-                    ast: next_element.unwrap().clone(),
+            if let Some(statement) = novel_data.statements.get(&next_index) {
+                ew_handle_statement.write(EventHandleStatement {
+                    statement: statement.clone(),
                 });
                 switched = true;
                 continue;
             }
 
+            let next_element = find_element_with_index(novel_data.ast.clone(), next_index);
+            if let Some(next_element) = next_element {
+                ew_handle_node.write(EventHandleNode { ast: next_element });
+                switched = true;
+                continue;
+            }
+
             for node in novel_data.ast.clone() {
                 let next_element: Option<AST> = match node {
                     AST::Label(_, _, label_ast, _) => {
@@ -179,10 +728,8 @@ pub fn handle_switch_next_node(
                     _ => None,
                 };
 
-                if next_element.is_some() {
-                    ew_handle_node.write(EventHandleNode {
-                        ast: next_element.unwrap().clone(),
-                    });
+                if let Some(next_element) = next_element {
+                    ew_handle_node.write(EventHandleNode { ast: next_element });
                     switched = true;
                     continue;
                 }
@@ -198,93 +745,215 @@ pub fn handle_new_node(
     plugin_settings: Res<NovelSettings>,
     mut er_handle_node: MessageReader<EventHandleNode>,
     mut ew_event_switch_next_node: MessageWriter<EventSwitchNextNode>,
+    mut ew_jump: MessageWriter<EventJump>,
+    mut ew_return: MessageWriter<EventReturn>,
     mut ew_play_audio: MessageWriter<EventPlayAudio>,
     mut ew_show_text_node: MessageWriter<EventShowTextNode>,
-    mut ew_hide_image_node: MessageWriter<EventHideImageNode>,
+    mut ew_show: MessageWriter<EventShow>,
+    mut ew_hide: MessageWriter<EventHide>,
+    mut commands: Commands,
     mut queries: ParamSet<(
-        Query<(Entity, &mut Visibility, &mut Sprite, &mut NovelBackground)>,
-        Query<(Entity, &mut Visibility, &mut Sprite, &mut NovelImage)>,
-        Query<(Entity, &mut Visibility, &mut TextSpan, &NovelTextWhat)>,
-        Query<(Entity, &mut Visibility, &mut TextSpan, &NovelTextWho)>,
+        Query<(
+            Entity,
+            &mut Visibility,
+            &mut Sprite,
+            &Transform,
+            &mut NovelBackground,
+        )>,
+        Query<(
+            Entity,
+            &mut Visibility,
+            &mut Sprite,
+            &Transform,
+            &mut NovelImage,
+        )>,
+        Query<(
+            Entity,
+            &mut Visibility,
+            &mut TextSpan,
+            &mut TextColor,
+            &NovelTextWho,
+        )>,
+        Query<(&mut Visibility, &mut ImageNode), With<NovelSideImage>>,
     )>,
     assets: Res<AssetServer>,
-    novel_data: Res<NovelData>,
+    mut novel_data: ResMut<NovelData>,
+    transitions: Res<NovelTransitions>,
+    mut transition_state: ResMut<NovelTransitionState>,
+    mut text_reveal: ResMut<NovelTextReveal>,
 ) {
     let base_path = PathBuf::from(&plugin_settings.assets_path);
 
     for event in er_handle_node.read() {
         match event.ast.clone() {
-            AST::Return(_, _) => {}
-            AST::Jump(_, _, _) => {
-                ew_event_switch_next_node.write(EventSwitchNextNode {});
+            AST::Return(_, _) => {
+                ew_return.write(EventReturn {});
+            }
+            AST::Jump(_, label, _) => {
+                ew_jump.write(EventJump { label });
             }
             AST::Scene(_, image, _layer) => {
+                let (image, with) = match image {
+                    Some(image) => {
+                        let (image, with) = split_with_clause(&image);
+                        (Some(image), with)
+                    }
+                    None => (None, None),
+                };
+                let transition = resolve_transition(&transitions, with.as_deref());
+
+                let background = image.clone().or(novel_data.background.clone());
                 if let Some(img) = image {
-                    for (_, mut v, mut sprite, _) in queries.p0().iter_mut() {
-                        if let Some(spr) = novel_data.cached_images.get(&img) {
-                            *sprite = spr.clone();
-                        } else {
-                            let mut image_name = img.clone();
-                            if !(img.ends_with(".png") || img.ends_with(".jpeg")) {
-                                image_name = format!("{}.png", img);
-                            }
-                            let image_path = base_path.join(image_name);
-                            *sprite = Sprite::from_image(assets.load(image_path));
+                    for (entity, mut v, mut sprite, transform, _) in queries.p0().iter_mut() {
+                        if transition.is_some() {
+                            commands.spawn((
+                                Name::new("Background Image Old"),
+                                sprite.clone(),
+                                *transform,
+                                *v,
+                                NovelTransitionOld {
+                                    translation: transform.translation - Vec3::Z * 0.5,
+                                    image: sprite.image.clone(),
+                                },
+                            ));
                         }
+
+                        *sprite = image_sprite(&novel_data, &assets, &base_path, &img);
                         *v = Visibility::Visible;
+
+                        if transition.is_some() {
+                            commands.entity(entity).insert(NovelTransitionNew {
+                                image: sprite.image.clone(),
+                            });
+                        }
                     }
                 }
 
-                ew_hide_image_node.write(EventHideImageNode {});
-                ew_event_switch_next_node.write(EventSwitchNextNode {});
-            }
-            AST::Show(_, img) => {
-                for (_, mut v, mut sprite, _) in queries.p1().iter_mut() {
-                    if let Some(spr) = novel_data.cached_images.get(&img) {
-                        *sprite = spr.clone();
+                novel_data.background = background;
+
+                // A new scene clears every image shown on top of the background
+                for (entity, _, sprite, transform, _) in queries.p1().iter_mut() {
+                    if transition.is_some() {
+                        commands
+                            .entity(entity)
+                            .remove::<NovelImage>()
+                            .insert(NovelTransitionOld {
+                                translation: transform.translation,
+                                image: sprite.image.clone(),
+                            });
                     } else {
-                        let image_name = format!("{}.png", img);
-                        let image_path = base_path.join(image_name);
-                        *sprite = Sprite::from_image(assets.load(image_path));
+                        commands.entity(entity).despawn();
                     }
+                }
 
-                    *v = Visibility::Visible;
+                match transition {
+                    Some(transition) => transition_state.start(transition),
+                    None => {
+                        ew_event_switch_next_node.write(EventSwitchNextNode {});
+                    }
                 }
+            }
+            AST::Show(_, image) => {
+                let (image, with) = split_with_clause(&image);
+                let (image, at) = split_at_clause(&image);
+                let transition = resolve_transition(&transitions, with.as_deref());
 
-                ew_event_switch_next_node.write(EventSwitchNextNode {});
+                ew_show.write(EventShow {
+                    image,
+                    at,
+                    with: transition.is_some().then_some(with).flatten(),
+                });
+
+                match transition {
+                    Some(transition) => transition_state.start(transition),
+                    None => {
+                        ew_event_switch_next_node.write(EventSwitchNextNode {});
+                    }
+                }
             }
-            AST::Hide(_, _) => {
-                ew_hide_image_node.write(EventHideImageNode {});
-                ew_event_switch_next_node.write(EventSwitchNextNode {});
+            AST::Hide(_, image) => {
+                let (image, with) = split_with_clause(&image);
+                let transition = resolve_transition(&transitions, with.as_deref());
+
+                ew_hide.write(EventHide {
+                    image,
+                    with: transition.is_some().then_some(with).flatten(),
+                });
+
+                match transition {
+                    Some(transition) => transition_state.start(transition),
+                    None => {
+                        ew_event_switch_next_node.write(EventSwitchNextNode {});
+                    }
+                }
             }
             AST::Label(_, _, _, _) => {
                 ew_event_switch_next_node.write(EventSwitchNextNode {});
             }
             AST::GameMechanic(_, _) => {
-                // ew_event_switch_next_node.send(EventSwitchNextNode {});
+                // Handed to the game by handle_game_mechanic
             }
             AST::LLMGenerate(_, _, _) => {
-                // ew_event_switch_next_node.send(EventSwitchNextNode {});
+                // Handed to the generator by handle_generate_text
+            }
+            AST::SceneGenerate(_, _) => {
+                // Handed to the generator by handle_generate_scene
+            }
+            AST::Stop(_, _, _, _) => {
+                // Handled by handle_stop_node
+            }
+            AST::MusicGenerate(_, _) => {
+                // Generated in the background by handle_generate_music
+                ew_event_switch_next_node.write(EventSwitchNextNode {});
             }
             AST::Play(_, mode, filename) => {
-                let audio_mode = AudioMode::from_str(&mode).unwrap();
-
-                ew_play_audio.write(EventPlayAudio {
-                    filename,
-                    audio_mode,
-                });
+                match AudioMode::from_channel(&mode, &plugin_settings.audio) {
+                    Some(audio_mode) => {
+                        ew_play_audio.write(EventPlayAudio::new(filename, audio_mode));
+                    }
+                    None => error!("play {}: unknown channel", mode),
+                }
 
                 ew_event_switch_next_node.write(EventSwitchNextNode {});
             }
             AST::Say(_, who, what) => {
-                for (_, _, mut text, _) in queries.p2().iter_mut() {
-                    *text = TextSpan::new(what.clone());
+                // Unnamed lines are spoken by `narrator` if the script defines one
+                let character = novel_data
+                    .characters
+                    .get(who.as_deref().unwrap_or("narrator"));
+
+                let (who, what) = match character {
+                    Some(character) => (character.display_name(), character.display_what(&what)),
+                    None => (who.unwrap_or_default(), what),
+                };
+
+                for (_, _, mut text, mut color, _) in queries.p2().iter_mut() {
+                    *text = TextSpan::new(who.clone());
+                    *color = character
+                        .and_then(|c| c.color)
+                        .map(TextColor)
+                        .unwrap_or_default();
                 }
 
-                for (_, _, mut text, _) in queries.p3().iter_mut() {
-                    *text = TextSpan::new(who.clone().unwrap_or_default());
+                let side_image = character.and_then(|c| c.image.clone()).map(|tag| {
+                    let image_name = format!("side {}", tag);
+                    match novel_data.cached_images.get(&image_name) {
+                        Some(sprite) => sprite.image.clone(),
+                        None => assets.load(base_path.join(format!("{}.png", image_name))),
+                    }
+                });
+
+                for (mut visibility, mut image_node) in queries.p3().iter_mut() {
+                    match side_image.clone() {
+                        Some(image) => {
+                            image_node.image = image;
+                            *visibility = Visibility::Inherited;
+                        }
+                        None => *visibility = Visibility::Hidden,
+                    }
                 }
 
+                text_reveal.start(&what, character.and_then(|c| c.what_color));
                 ew_show_text_node.write(EventShowTextNode {});
             }
             _ => {
@@ -294,16 +963,399 @@ pub fn handle_new_node(
     }
 }
 
+#[allow(clippy::too_many_arguments)]
+pub fn handle_new_statement(
+    mut er_handle_statement: MessageReader<EventHandleStatement>,
+    novel_settings: Res<NovelSettings>,
+    mut novel_data: ResMut<NovelData>,
+    mut variables: ResMut<NovelVariables>,
+    mut ew_call: MessageWriter<EventCall>,
+    mut ew_handle_node: MessageWriter<EventHandleNode>,
+    mut ew_show_menu: MessageWriter<EventShowMenu>,
+    mut ew_play_audio: MessageWriter<EventPlayAudio>,
+    mut ew_stop_audio: MessageWriter<EventStopAudio>,
+    mut ew_event_switch_next_node: MessageWriter<EventSwitchNextNode>,
+) {
+    for event in er_handle_statement.read() {
+        match event.statement.clone() {
+            Statement::Call(label) => {
+                ew_call.write(EventCall { label });
+            }
+            Statement::Menu(mut menu) => {
+                menu.choices
+                    .retain(|choice| check_condition(&variables, choice.condition.as_deref()));
+
+                if menu.choices.is_empty() {
+                    novel_data.current_index = menu.end;
+                    ew_event_switch_next_node.write(EventSwitchNextNode {});
+                    continue;
+                }
+
+                if let Some(prompt) = menu.prompt.clone() {
+                    ew_handle_node.write(EventHandleNode { ast: prompt });
+                }
+
+                ew_show_menu.write(EventShowMenu {
+                    options: menu.choices.iter().map(|c| c.caption.clone()).collect(),
+                });
+                novel_data.menu = Some(menu);
+            }
+            Statement::If(conditional) => {
+                let branch = conditional
+                    .branches
+                    .iter()
+                    .find(|branch| check_condition(&variables, branch.condition.as_deref()));
+
+                novel_data.current_index = match branch {
+                    Some(branch) => branch.index,
+                    None => conditional.end,
+                };
+                ew_event_switch_next_node.write(EventSwitchNextNode {});
+            }
+            Statement::Python(code) => {
+                if let Err(err) = variables.execute(&code) {
+                    error!("$ {}: {}", code, err);
+                }
+                ew_event_switch_next_node.write(EventSwitchNextNode {});
+            }
+            Statement::Default(_, _) => {
+                ew_event_switch_next_node.write(EventSwitchNextNode {});
+            }
+            Statement::Voice(filename) => {
+                novel_data.voice = Some(filename);
+                ew_event_switch_next_node.write(EventSwitchNextNode {});
+            }
+            Statement::Play(play) => {
+                match AudioMode::from_channel(&play.channel, &novel_settings.audio) {
+                    Some(audio_mode) => {
+                        ew_play_audio.write(EventPlayAudio {
+                            filename: play.filename,
+                            audio_mode,
+                            fade_in: play.fade_in,
+                            fade_out: play.fade_out,
+                            looped: play.looped,
+                        });
+                    }
+                    None => error!("play {}: unknown channel", play.channel),
+                }
+                ew_event_switch_next_node.write(EventSwitchNextNode {});
+            }
+            Statement::Stop(channel, fade_out) => {
+                match AudioMode::from_channel(&channel, &novel_settings.audio) {
+                    Some(audio_mode) => {
+                        ew_stop_audio.write(EventStopAudio {
+                            audio_mode,
+                            fade_out,
+                        });
+                    }
+                    None => error!("stop {}: unknown channel", channel),
+                }
+                ew_event_switch_next_node.write(EventSwitchNextNode {});
+            }
+            Statement::Goto(index) => {
+                novel_data.current_index = index;
+                ew_event_switch_next_node.write(EventSwitchNextNode {});
+            }
+        }
+    }
+}
+
+/// A missing condition always holds, one that fails to evaluate never does
+fn check_condition(variables: &NovelVariables, condition: Option<&str>) -> bool {
+    let Some(condition) = condition else {
+        return true;
+    };
+
+    match variables.evaluate(condition) {
+        Ok(value) => value.is_truthy(),
+        Err(err) => {
+            error!("if {}: {}", condition, err);
+            false
+        }
+    }
+}
+
+pub fn handle_menu_choice(
+    mut er_menu_choice: MessageReader<EventMenuChoice>,
+    mut novel_data: ResMut<NovelData>,
+    mut text_reveal: ResMut<NovelTextReveal>,
+    mut ew_event_switch_next_node: MessageWriter<EventSwitchNextNode>,
+) {
+    for event in er_menu_choice.read() {
+        let Some(menu) = novel_data.menu.take() else {
+            warn!("Menu choice {} was made with no menu open", event.index);
+            continue;
+        };
+
+        let Some(choice) = menu.choices.get(event.index) else {
+            warn!("Menu has no choice {}", event.index);
+            novel_data.menu = Some(menu);
+            continue;
+        };
+
+        novel_data.current_index = choice.index;
+        // The lines rolled back past came from the choice made before
+        novel_data.rolled_back.clear();
+        // A prompt still being typed out would take the switch to the branch as a press
+        text_reveal.stop();
+        ew_event_switch_next_node.write(EventSwitchNextNode {});
+    }
+}
+
+/// Depth between an image and the one shown before it, the first one is at 1.0
+const IMAGE_Z_STEP: f32 = 0.01;
+
+pub fn handle_show(
+    mut commands: Commands,
+    mut er_show: MessageReader<EventShow>,
+    mut images: Query<(
+        Entity,
+        &mut Sprite,
+        &mut Visibility,
+        &Transform,
+        &mut NovelImage,
+    )>,
+    novel_data: Res<NovelData>,
+    transforms: Res<NovelTransforms>,
+    plugin_settings: Res<NovelSettings>,
+    assets: Res<AssetServer>,
+) {
+    let base_path = PathBuf::from(&plugin_settings.assets_path);
+    // Images shown later are drawn over the ones on screen. Images spawned by this pass are
+    // not in the query until the commands are applied, so the top is kept here.
+    let mut top_z = images
+        .iter()
+        .map(|(_, _, _, transform, _)| transform.translation.z)
+        .fold(1.0 - IMAGE_Z_STEP, f32::max);
+
+    for event in er_show.read() {
+        let sprite = image_sprite(&novel_data, &assets, &base_path, &event.image);
+
+        let transform = event.at.as_ref().and_then(|name| {
+            let transform = transforms.get(name);
+            if transform.is_none() {
+                warn!("Transform `{}` is not registered", name);
+            }
+            transform
+        });
+
+        let tag = image_tag(&event.image);
+
+        let transition = event.with.as_ref().map(|_| NovelTransitionNew {
+            image: sprite.image.clone(),
+        });
+
+        if let Some((entity, mut shown_sprite, mut visibility, shown_transform, mut image)) = images
+            .iter_mut()
+            .find(|(_, _, _, _, image)| image.tag == tag)
+        {
+            if let Some(transition) = transition {
+                commands.spawn((
+                    Name::new(format!("Character Image {} Old", tag)),
+                    shown_sprite.clone(),
+                    *shown_transform,
+                    *visibility,
+                    NovelTransitionOld {
+                        translation: shown_transform.translation,
+                        image: shown_sprite.image.clone(),
+                    },
+                ));
+                commands.entity(entity).insert(transition);
+            }
+
+            *shown_sprite = sprite;
+            *visibility = Visibility::Visible;
+            image.image = event.image.clone();
+            if let Some(transform) = transform {
+                image.transform = transform;
+            }
+            continue;
+        }
+
+        top_z += IMAGE_Z_STEP;
+        let z = top_z;
+
+        let mut entity = commands.spawn(novel_image_bundle(
+            &event.image,
+            sprite,
+            transform.unwrap_or_default(),
+            z,
+        ));
+
+        if let Some(transition) = transition {
+            entity.insert(transition);
+        }
+    }
+}
+
+#[allow(clippy::type_complexity)]
+#[allow(clippy::too_many_arguments)]
+pub fn handle_restore_state(
+    mut commands: Commands,
+    mut er_restore_state: MessageReader<EventRestoreState>,
+    mut novel_data: ResMut<NovelData>,
+    mut variables: ResMut<NovelVariables>,
+    plugin_settings: Res<NovelSettings>,
+    assets: Res<AssetServer>,
+    music_handle: Res<MusicHandle>,
+    mut audio_instances: ResMut<Assets<AudioInstance>>,
+    mut backgrounds: Query<(Entity, &mut Sprite, &mut Visibility), With<NovelBackground>>,
+    images: Query<Entity, With<NovelImage>>,
+    transition_entities: Query<
+        Entity,
+        Or<(With<NovelTransitionOld>, With<NovelTransitionOverlay>)>,
+    >,
+    generations: NovelGenerationEntities,
+    mut transition_state: ResMut<NovelTransitionState>,
+    mut text_reveal: ResMut<NovelTextReveal>,
+    mut ew_play_audio: MessageWriter<EventPlayAudio>,
+    mut ew_switch_next_node: MessageWriter<EventSwitchNextNode>,
+) {
+    let base_path = PathBuf::from(&plugin_settings.assets_path);
+
+    for event in er_restore_state.read() {
+        let state = &event.state;
+
+        transition_state.cancel();
+        text_reveal.stop();
+        for entity in transition_entities.iter() {
+            commands.entity(entity).despawn();
+        }
+        cancel_generations(&mut commands, &generations, &mut novel_data);
+
+        novel_data.menu = None;
+        novel_data.mechanic = None;
+        novel_data.call_stack = state.call_stack.clone();
+        *variables = state.variables.clone();
+
+        for (entity, mut sprite, mut visibility) in backgrounds.iter_mut() {
+            commands.entity(entity).remove::<NovelTransitionNew>();
+            match &state.background {
+                Some(image) => {
+                    *sprite = image_sprite(&novel_data, &assets, &base_path, image);
+                    *visibility = Visibility::Visible;
+                }
+                None => *visibility = Visibility::Hidden,
+            }
+        }
+        novel_data.background = state.background.clone();
+
+        for entity in images.iter() {
+            commands.entity(entity).despawn();
+        }
+        for saved in state.images.iter() {
+            let sprite = image_sprite(&novel_data, &assets, &base_path, &saved.image);
+            commands.spawn(novel_image_bundle(
+                &saved.image,
+                sprite,
+                saved.transform,
+                saved.z,
+            ));
+        }
+
+        if state.music != novel_data.music {
+            match &state.music {
+                Some(filename) => {
+                    ew_play_audio.write(EventPlayAudio::new(filename.clone(), AudioMode::Music));
+                }
+                None => {
+                    if let Some(handle) = music_handle.0.clone()
+                        && let Some(mut instance) = audio_instances.get_mut(&handle)
+                    {
+                        instance.stop(AudioTween::default());
+                    }
+                    novel_data.music = None;
+                }
+            }
+        }
+
+        // Handle the node the snapshot was taken at again, bringing its text back
+        novel_data.current_index = state.current_index.saturating_sub(1);
+        ew_switch_next_node.write(EventSwitchNextNode {});
+    }
+}
+
+/// Sprite of `image`, taken from the image cache or loaded from the assets path
+pub fn image_sprite(
+    novel_data: &NovelData,
+    assets: &AssetServer,
+    base_path: &Path,
+    image: &str,
+) -> Sprite {
+    if let Some(sprite) = novel_data.cached_images.get(image) {
+        return sprite.clone();
+    }
+
+    let mut image_name = image.to_string();
+    if !(image.ends_with(".png") || image.ends_with(".jpeg")) {
+        image_name = format!("{}.png", image);
+    }
+    Sprite::from_image(assets.load(base_path.join(image_name)))
+}
+
+fn novel_image_bundle(
+    image: &str,
+    sprite: Sprite,
+    transform: NovelTransform,
+    z: f32,
+) -> impl Bundle {
+    let tag = image_tag(image);
+
+    (
+        Name::new(format!("Character Image {}", tag)),
+        sprite,
+        NovelImage {
+            tag: tag.to_string(),
+            image: image.to_string(),
+            transform,
+        },
+        Node {
+            position_type: PositionType::Absolute,
+            width: Val::Auto,
+            height: Val::Auto,
+            ..default()
+        },
+        Transform::from_xyz(0.0, 0.0, z),
+        Visibility::Visible,
+    )
+}
+
+pub fn handle_hide(
+    mut commands: Commands,
+    mut er_hide: MessageReader<EventHide>,
+    images: Query<(Entity, &Sprite, &Transform, &NovelImage)>,
+) {
+    for event in er_hide.read() {
+        let tag = image_tag(&event.image);
+
+        for (entity, sprite, transform, _) in images.iter().filter(|(.., image)| image.tag == tag) {
+            if event.with.is_some() {
+                // Left on screen until the transition is over
+                commands
+                    .entity(entity)
+                    .remove::<NovelImage>()
+                    .insert(NovelTransitionOld {
+                        translation: transform.translation,
+                        image: sprite.image.clone(),
+                    });
+            } else {
+                commands.entity(entity).despawn();
+            }
+        }
+    }
+}
+
+#[allow(clippy::type_complexity)]
 pub fn scale_images(
     mut commands: Commands,
     mut queries: ParamSet<(
         Query<(Entity, &mut Sprite, &mut NovelBackground)>,
-        Query<(Entity, &mut Sprite, &mut Node, &mut NovelImage)>,
+        Query<(Entity, &mut Sprite, &Transform, &mut NovelImage)>,
     )>,
     images: Res<Assets<Image>>,
     windows: Query<&Window>,
 ) {
-    for (entity, sprite, mut node, _) in queries.p1().iter_mut() {
+    for (entity, sprite, transform, novel_image) in queries.p1().iter_mut() {
         // Manually scaling image width and height in proportion to window
         let image_handle = sprite.image.clone();
 
@@ -317,12 +1369,16 @@ pub fn scale_images(
             let window = window.unwrap();
             let window_height = window.height();
 
-            let image_new_height = 0.75 * window_height;
-            let image_scale = image_new_height as f32 / (sprite_height as f32);
+            let placement = novel_image.transform;
+            let image_new_height = 0.75 * window_height * placement.zoom;
+            let image_scale = image_new_height / (sprite_height as f32);
 
-            let image_transform = Transform::from_scale(Vec3::ONE * image_scale);
+            let image_size = image.size().as_vec2() * image_scale;
+            let translation = placement.translation(image_size, window.size());
+
+            let image_transform = Transform::from_scale(Vec3::ONE * image_scale)
+                .with_translation(translation.extend(transform.translation.z));
 
-            node.margin.top = Val::Px(-(window_height - image_new_height) / 2.0);
             commands.entity(entity).insert(image_transform);
         }
     }
@@ -342,7 +1398,7 @@ pub fn scale_images(
             let window_height = window.height();
 
             let image_new_height = 1.0 * window_height;
-            let image_scale = image_new_height as f32 / (sprite_height as f32);
+            let image_scale = image_new_height / (sprite_height as f32);
 
             let image_transform = Transform::from_scale(Vec3::ONE * image_scale);
 
@@ -354,16 +1410,101 @@ pub fn scale_images(
 #[allow(clippy::type_complexity)]
 #[allow(clippy::too_many_arguments)]
 pub fn handle_press_key(
-    novel_settings: Res<NovelSettings>,
-    keys: Res<ButtonInput<KeyCode>>,
+    mut ui_hidden: Local<bool>,
+    mut novel_settings: ResMut<NovelSettings>,
+    mut novel_data: ResMut<NovelData>,
+    backlog: Res<NovelBacklog>,
+    mut skip: ResMut<NovelSkip>,
+    inputs: NovelInputs,
     mut ew_switch_next_node: MessageWriter<EventSwitchNextNode>,
+    mut ew_rollback: MessageWriter<EventRollback>,
+    mut ew_roll_forward: MessageWriter<EventRollForward>,
+    mut ew_toggle_backlog: MessageWriter<EventToggleBacklog>,
+    mut ew_save_game: MessageWriter<EventSaveGame>,
+    mut ew_hide_text_node: MessageWriter<EventHideTextNode>,
+    mut ew_show_text_node: MessageWriter<EventShowTextNode>,
+    mut er_menu_choice: MessageReader<EventMenuChoice>,
 ) {
+    // The click choosing a menu option doesn't advance past the line after it
+    let menu_chosen = er_menu_choice.read().count() > 0;
+
     if novel_settings.pause_handle_switch_node {
         return;
     }
 
-    if keys.just_pressed(KeyCode::Space) {
-        ew_switch_next_node.write(EventSwitchNextNode {});
+    // Any advance input brings back a hidden dialogue box, without advancing
+    if *ui_hidden {
+        if inputs.just_pressed(NovelAction::HideUi) || inputs.just_pressed(NovelAction::Advance) {
+            *ui_hidden = false;
+            ew_show_text_node.write(EventShowTextNode {});
+        }
+        return;
+    }
+
+    if inputs.just_pressed(NovelAction::Backlog) {
+        ew_toggle_backlog.write(EventToggleBacklog {});
+    }
+
+    if backlog.open {
+        if skip.is_skipping() {
+            skip.active = false;
+            skip.held = false;
+        }
+        return;
+    }
+
+    if inputs.just_pressed(NovelAction::HideUi) {
+        *ui_hidden = true;
+        ew_hide_text_node.write(EventHideTextNode {});
+        return;
+    }
+
+    if inputs.just_pressed(NovelAction::QuickSave) {
+        ew_save_game.write(EventSaveGame {
+            slot: novel_settings.quick_save_slot,
+        });
+    }
+
+    let held = inputs.pressed(NovelAction::Skip);
+    if skip.held != held {
+        skip.held = held;
+    }
+
+    if inputs.just_pressed(NovelAction::ToggleSkip) {
+        skip.active = !skip.active;
+    }
+
+    if inputs.just_pressed(NovelAction::AutoForward) {
+        novel_settings.auto_forward = !novel_settings.auto_forward;
+    }
+
+    if inputs.just_pressed(NovelAction::Rollback) {
+        ew_rollback.write(EventRollback {});
+    }
+
+    if inputs.just_pressed(NovelAction::RollForward) {
+        ew_roll_forward.write(EventRollForward {});
+    }
+
+    if novel_data.menu.is_some() || menu_chosen {
+        return;
+    }
+
+    if inputs.just_pressed(NovelAction::Advance) {
+        // Lines left by rollback are brought back rather than played again, up to a menu
+        // which runs again so that another choice can be made
+        match novel_data.rolled_back.last() {
+            Some(next) if !next.menu => {
+                ew_roll_forward.write(EventRollForward {});
+            }
+            Some(_) => {
+                novel_data.rolled_back.clear();
+                ew_switch_next_node.write(EventSwitchNextNode {});
+            }
+            None => {
+                ew_switch_next_node.write(EventSwitchNextNode {});
+            }
+        }
     }
 }
 
@@ -418,3 +1559,76 @@ pub fn handle_hide_image_node(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn app() -> App {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, AssetPlugin::default()))
+            .init_asset::<AudioSource>()
+            .init_asset::<AudioInstance>()
+            .add_message::<EventPlayAudio>()
+            .init_resource::<NovelData>()
+            .init_resource::<NovelSettings>()
+            .init_resource::<DynamicAudioChannels>()
+            .init_resource::<PendingAudioStops>()
+            .insert_resource(MusicHandle(None))
+            .insert_resource(VoiceHandle(None))
+            .add_systems(Update, (stop_pending_audio, handle_play_audio).chain());
+
+        app.world_mut()
+            .resource_mut::<DynamicAudioChannels>()
+            .create_channel(MUSIC_CHANNEL);
+        app
+    }
+
+    fn play_music(app: &mut App, filename: &str) -> Handle<AudioInstance> {
+        app.world_mut()
+            .write_message(EventPlayAudio::new(filename.to_string(), AudioMode::Music));
+        app.update();
+        app.world().resource::<MusicHandle>().0.clone().unwrap()
+    }
+
+    fn pending_stops(app: &App) -> Vec<Handle<AudioInstance>> {
+        let pending_stops = app.world().resource::<PendingAudioStops>();
+        pending_stops
+            .0
+            .iter()
+            .map(|(_, handle, _)| handle.clone())
+            .collect()
+    }
+
+    #[test]
+    fn stops_queued_music_later() {
+        let mut app = app();
+
+        // Neither play loads, kira keeps them queued
+        let first = play_music(&mut app, "first.ogg");
+        let second = play_music(&mut app, "second.ogg");
+        assert_ne!(first, second);
+        assert_eq!(pending_stops(&app), vec![first.clone()]);
+
+        // Still queued, the first music waits for its instance to be stopped
+        app.update();
+        assert_eq!(pending_stops(&app), vec![first]);
+        assert_eq!(
+            app.world().resource::<NovelData>().music.as_deref(),
+            Some("second.ogg")
+        );
+    }
+
+    #[test]
+    fn drops_stops_no_longer_queued() {
+        let mut app = app();
+        app.world_mut().resource_mut::<PendingAudioStops>().0.push((
+            MUSIC_CHANNEL.to_string(),
+            Handle::default(),
+            AudioTween::default(),
+        ));
+
+        app.update();
+        assert!(pending_stops(&app).is_empty());
+    }
+}
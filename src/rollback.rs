@@ -0,0 +1,214 @@
+use bevy::prelude::*;
+use renpy_parser::parsers::AST;
+
+use crate::{
+    NovelData, NovelImage, NovelSettings,
+    messages::{
+        EventHandleNode, EventRestoreState, EventRollForward, EventRollback, EventShowMenu,
+    },
+    save::NovelSaveState,
+    variables::NovelVariables,
+};
+
+/// Takes a snapshot every time a say line or a menu is shown, the last one being the current
+/// line
+pub fn record_history(
+    mut er_handle_node: MessageReader<EventHandleNode>,
+    mut er_show_menu: MessageReader<EventShowMenu>,
+    mut novel_data: ResMut<NovelData>,
+    novel_settings: Res<NovelSettings>,
+    variables: Res<NovelVariables>,
+    images: Query<(&NovelImage, &Transform)>,
+) {
+    let says = er_handle_node
+        .read()
+        .filter(|event| matches!(event.ast, AST::Say(_, _, _)))
+        .count();
+
+    // A menu with a prompt is already recorded by the say line of its prompt
+    let menus = er_show_menu
+        .read()
+        .filter(|_| {
+            novel_data
+                .menu
+                .as_ref()
+                .is_some_and(|menu| menu.prompt.is_none())
+        })
+        .count();
+
+    for _ in 0..says + menus {
+        let state = NovelSaveState::capture(&novel_data, &variables, images.iter());
+        novel_data.push_history(state, novel_settings.rollback_limit);
+    }
+}
+
+pub fn handle_rollback(
+    mut er_rollback: MessageReader<EventRollback>,
+    mut novel_data: ResMut<NovelData>,
+    mut ew_restore_state: MessageWriter<EventRestoreState>,
+) {
+    for _ in er_rollback.read() {
+        if let Some(state) = novel_data.rollback() {
+            ew_restore_state.write(EventRestoreState { state });
+        }
+    }
+}
+
+/// Brings back the lines left by rollback from their snapshots, so that statements between
+/// them (sounds, variable changes) don't run again
+pub fn handle_roll_forward(
+    mut er_roll_forward: MessageReader<EventRollForward>,
+    mut novel_data: ResMut<NovelData>,
+    mut ew_restore_state: MessageWriter<EventRestoreState>,
+) {
+    for _ in er_roll_forward.read() {
+        if let Some(state) = novel_data.roll_forward() {
+            ew_restore_state.write(EventRestoreState { state });
+        }
+    }
+}
+
+impl NovelData {
+    /// Adds the snapshot of a line just shown, keeping the latest `limit` ones
+    pub fn push_history(&mut self, state: NovelSaveState, limit: usize) {
+        // The line is shown again by a rollback, its snapshot is already in the history
+        if self.replaying {
+            self.replaying = false;
+            return;
+        }
+
+        self.history.push_back(state);
+        while self.history.len() > limit.max(1) {
+            self.history.pop_front();
+        }
+
+        // Going on from a rolled back line starts a new future
+        self.rolled_back.clear();
+    }
+
+    /// Steps back from the current line, returning the snapshot of the line before it
+    pub fn rollback(&mut self) -> Option<NovelSaveState> {
+        if self.history.len() < 2 {
+            return None;
+        }
+
+        if let Some(current) = self.history.pop_back() {
+            self.rolled_back.push(current);
+        }
+
+        let previous = self.history.back().cloned()?;
+        self.replaying = true;
+        Some(previous)
+    }
+
+    /// Returns the snapshot of the line last stepped back from
+    pub fn roll_forward(&mut self) -> Option<NovelSaveState> {
+        let next = self.rolled_back.pop()?;
+
+        self.history.push_back(next.clone());
+        self.replaying = true;
+        Some(next)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(index: usize) -> NovelSaveState {
+        NovelSaveState {
+            current_index: index,
+            ..default()
+        }
+    }
+
+    fn indices<'a>(states: impl IntoIterator<Item = &'a NovelSaveState>) -> Vec<usize> {
+        states
+            .into_iter()
+            .map(|state| state.current_index)
+            .collect()
+    }
+
+    #[test]
+    fn caps_history() {
+        let mut novel_data = NovelData::default();
+        for index in 1..=5 {
+            novel_data.push_history(line(index), 3);
+        }
+        assert_eq!(indices(&novel_data.history), vec![3, 4, 5]);
+
+        // A limit of 0 still keeps the current line
+        novel_data.push_history(line(6), 0);
+        assert_eq!(indices(&novel_data.history), vec![6]);
+    }
+
+    #[test]
+    fn rolls_back_and_forward() {
+        let mut novel_data = NovelData::default();
+        for index in 1..=3 {
+            novel_data.push_history(line(index), 10);
+        }
+
+        assert_eq!(
+            novel_data.rollback().map(|state| state.current_index),
+            Some(2)
+        );
+        // Restoring the line shows it again, which doesn't add it twice
+        novel_data.push_history(line(2), 10);
+        assert_eq!(
+            novel_data.rollback().map(|state| state.current_index),
+            Some(1)
+        );
+        novel_data.push_history(line(1), 10);
+        assert_eq!(indices(&novel_data.history), vec![1]);
+        assert_eq!(indices(&novel_data.rolled_back), vec![3, 2]);
+
+        // The first line has nothing before it
+        assert!(novel_data.rollback().is_none());
+        assert!(!novel_data.replaying);
+
+        assert_eq!(
+            novel_data.roll_forward().map(|state| state.current_index),
+            Some(2)
+        );
+        novel_data.push_history(line(2), 10);
+        assert_eq!(
+            novel_data.roll_forward().map(|state| state.current_index),
+            Some(3)
+        );
+        novel_data.push_history(line(3), 10);
+        assert_eq!(indices(&novel_data.history), vec![1, 2, 3]);
+        assert!(novel_data.roll_forward().is_none());
+    }
+
+    #[test]
+    fn new_lines_drop_the_rolled_back_ones() {
+        let mut novel_data = NovelData::default();
+        for index in 1..=3 {
+            novel_data.push_history(line(index), 10);
+        }
+        novel_data.rollback();
+        novel_data.push_history(line(2), 10);
+        assert_eq!(indices(&novel_data.rolled_back), vec![3]);
+
+        // Going on from line 2 by other means than rolling forward
+        novel_data.push_history(line(4), 10);
+        assert_eq!(indices(&novel_data.history), vec![1, 2, 4]);
+        assert!(novel_data.rolled_back.is_empty());
+        assert!(novel_data.roll_forward().is_none());
+    }
+
+    #[test]
+    fn replaying_records_nothing() {
+        let mut novel_data = NovelData::default();
+        novel_data.push_history(line(1), 10);
+
+        novel_data.replaying = true;
+        novel_data.push_history(line(2), 10);
+        assert_eq!(indices(&novel_data.history), vec![1]);
+        assert!(!novel_data.replaying);
+
+        novel_data.push_history(line(2), 10);
+        assert_eq!(indices(&novel_data.history), vec![1, 2]);
+    }
+}
@@ -1,15 +1,56 @@
+use std::path::{Path, PathBuf};
+
 use bevy::{
     asset::{AssetLoader, LoadContext, io::Reader},
     prelude::*,
 };
-use renpy_parser::{parse_scenario_from_string, parsers::AST};
+use renpy_parser::{
+    parse_scenario_from_string,
+    parsers::{AST, ParseError},
+};
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+use crate::script::{ScriptError, Statements, preprocess};
+
 #[derive(Default, TypePath)]
 pub struct RpyAssetLoader;
 
 #[derive(Asset, TypePath, Debug, Deref, DerefMut)]
-pub struct Rpy(pub Vec<AST>);
+pub struct Rpy {
+    #[deref]
+    pub ast: Vec<AST>,
+    /// Statements `renpy_parser` doesn't handle, see [`crate::script`]
+    pub statements: Statements,
+    /// Label the scenario starts at, from the beginning of the script when `None`
+    pub entry_label: Option<String>,
+}
+
+/// Text encoding of a script file
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RpyEncoding {
+    /// UTF-16 when the file starts with a UTF-16 byte order mark, UTF-8 otherwise
+    #[default]
+    Auto,
+    Utf8,
+    /// UTF-8 with invalid sequences replaced by `U+FFFD`
+    Utf8Lossy,
+    Latin1,
+}
+
+/// Settings of [`RpyAssetLoader`], set per script in its `.meta` file
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct RpyLoaderSettings {
+    /// Name of the script in diagnostics, the asset path when `None`
+    pub script_name: Option<String>,
+    /// Label the scenario starts at, see [`Rpy::entry_label`]
+    pub entry_label: Option<String>,
+    /// A UTF-8 byte order mark is dropped with any encoding
+    pub encoding: RpyEncoding,
+    /// Columns between tab stops tabs are expanded to, tabs are an error when `None` as in
+    /// Ren'Py
+    pub tab_width: Option<usize>,
+}
 
 /// Possible errors that can be produced by [`RpyAssetLoader`]
 #[non_exhaustive]
@@ -18,25 +59,281 @@ pub enum RpyAssetLoaderError {
     /// An [IO](std::io) Error
     #[error("Could not load file: {0}")]
     Io(#[from] std::io::Error),
+    /// The script isn't valid UTF-8
+    #[error("{}:{line}: invalid UTF-8", path.display())]
+    InvalidUtf8 { path: PathBuf, line: usize },
+    /// `renpy_parser` rejected the script, `line` is 0 when it didn't tell which line
+    #[error("{}:{line}: {message}", path.display())]
+    Parse {
+        path: PathBuf,
+        line: usize,
+        message: String,
+    },
 }
 
 impl AssetLoader for RpyAssetLoader {
     type Asset = Rpy;
-    type Settings = ();
+    type Settings = RpyLoaderSettings;
     type Error = RpyAssetLoaderError;
 
     async fn load(
         &self,
         reader: &mut dyn Reader,
-        _settings: &(),
-        _load_context: &mut LoadContext<'_>,
+        settings: &RpyLoaderSettings,
+        load_context: &mut LoadContext<'_>,
     ) -> Result<Self::Asset, Self::Error> {
         let mut bytes = Vec::new();
         reader.read_to_end(&mut bytes).await?;
 
-        let content = std::str::from_utf8(&bytes).unwrap();
-        let (ast, _) = parse_scenario_from_string(content, "_").unwrap();
+        let path = match &settings.script_name {
+            Some(script_name) => PathBuf::from(script_name),
+            None => load_context.path().path().to_path_buf(),
+        };
+
+        let content =
+            decode(&bytes, settings.encoding).map_err(|line| RpyAssetLoaderError::InvalidUtf8 {
+                path: path.clone(),
+                line,
+            })?;
+        let content = match settings.tab_width {
+            Some(tab_width) => expand_tabs(&content, tab_width),
+            None => content,
+        };
+        let (ast, statements) = parse_script(&content, &path)?;
+
+        Ok(Rpy {
+            ast,
+            statements,
+            entry_label: settings.entry_label.clone(),
+        })
+    }
+}
+
+/// Preprocesses and parses the decoded source of a script
+pub(crate) fn parse_script(
+    content: &str,
+    path: &Path,
+) -> Result<(Vec<AST>, Statements), RpyAssetLoaderError> {
+    let (content, statements) = preprocess(content).map_err(|err| match err {
+        ScriptError::Indentation { line } => RpyAssetLoaderError::Parse {
+            path: path.to_path_buf(),
+            line,
+            message: err.to_string(),
+        },
+    })?;
+    let filename = path.to_string_lossy();
+
+    let (ast, errors) = parse_scenario_from_string(&content, &filename).map_err(|err| {
+        match err.downcast_ref::<ParseError>() {
+            Some(parse_error) => RpyAssetLoaderError::Parse {
+                path: path.to_path_buf(),
+                line: parse_error.line_number,
+                message: parse_error.message.clone(),
+            },
+            None => RpyAssetLoaderError::Parse {
+                path: path.to_path_buf(),
+                line: 0,
+                message: err.to_string(),
+            },
+        }
+    })?;
+
+    // Statements renpy_parser couldn't read are collected rather than returned as an error,
+    // a bad line inside a label drops the whole label
+    if let Some(error) = errors.first() {
+        let (line, message) = split_parse_error(error, &filename);
+        return Err(RpyAssetLoaderError::Parse {
+            path: path.to_path_buf(),
+            line,
+            message,
+        });
+    }
+
+    Ok((ast, statements))
+}
+
+/// Splits an `On line N of file: message` error of `renpy_parser` into its line and message.
+/// Errors inside a label are wrapped in the error of the label, the innermost line is kept.
+fn split_parse_error(error: &str, filename: &str) -> (usize, String) {
+    let separator = format!(" of {}: ", filename);
+    let mut line = 0;
+    let mut message = error;
+
+    while let Some(rest) = message.strip_prefix("On line ") {
+        let Some((number, rest)) = rest.split_once(separator.as_str()) else {
+            break;
+        };
+        let Ok(number) = number.parse() else {
+            break;
+        };
+
+        line = number;
+        message = rest;
+    }
+
+    (line, message.to_string())
+}
+
+/// Decodes a script file, the error is the line of the first invalid sequence
+fn decode(bytes: &[u8], encoding: RpyEncoding) -> Result<String, usize> {
+    let utf16 = |bytes: &[u8], from_bytes: fn([u8; 2]) -> u16| {
+        let units: Vec<u16> = bytes
+            .chunks(2)
+            .map(|chunk| from_bytes([chunk[0], chunk.get(1).copied().unwrap_or_default()]))
+            .collect();
+        String::from_utf16_lossy(&units)
+    };
+
+    let content = match encoding {
+        RpyEncoding::Auto => match bytes {
+            [0xff, 0xfe, rest @ ..] => utf16(rest, u16::from_le_bytes),
+            [0xfe, 0xff, rest @ ..] => utf16(rest, u16::from_be_bytes),
+            _ => return decode(bytes, RpyEncoding::Utf8),
+        },
+        RpyEncoding::Utf8 => std::str::from_utf8(bytes)
+            .map_err(|err| {
+                bytes[..err.valid_up_to()]
+                    .iter()
+                    .filter(|byte| **byte == b'\n')
+                    .count()
+                    + 1
+            })?
+            .to_string(),
+        RpyEncoding::Utf8Lossy => String::from_utf8_lossy(bytes).into_owned(),
+        RpyEncoding::Latin1 => bytes.iter().map(|byte| *byte as char).collect(),
+    };
+
+    Ok(content
+        .strip_prefix('\u{feff}')
+        .map(String::from)
+        .unwrap_or(content))
+}
+
+/// Replaces tabs with spaces up to the next tab stop
+fn expand_tabs(content: &str, tab_width: usize) -> String {
+    let tab_width = tab_width.max(1);
+
+    content
+        .split('\n')
+        .map(|line| {
+            let mut expanded = String::with_capacity(line.len());
+            let mut column = 0;
+            for c in line.chars() {
+                if c == '\t' {
+                    let spaces = tab_width - column % tab_width;
+                    expanded.extend(std::iter::repeat_n(' ', spaces));
+                    column += spaces;
+                } else {
+                    expanded.push(c);
+                    column += 1;
+                }
+            }
+            expanded
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_valid_script() {
+        let content = "label start:\n    \"Hello\"\n    return\n";
+        let (ast, _) = parse_script(content, Path::new("script.rpy")).unwrap();
+
+        assert!(matches!(&ast[0], AST::Label(1, label, _, _) if label == "start"));
+    }
+
+    #[test]
+    fn rejects_bad_statement_in_label() {
+        let content = "label start:\n    \"Hello\"\n    @@@\n    return\n";
+        let err = parse_script(content, Path::new("script.rpy")).unwrap_err();
+
+        match err {
+            RpyAssetLoaderError::Parse { path, line, .. } => {
+                assert_eq!(path, PathBuf::from("script.rpy"));
+                assert_eq!(line, 3);
+            }
+            err => panic!("unexpected error {}", err),
+        }
+    }
+
+    #[test]
+    fn splits_nested_parse_error() {
+        let error = "On line 1 of a: b.rpy: On line 3 of a: b.rpy: expected statement.";
+
+        assert_eq!(
+            split_parse_error(error, "a: b.rpy"),
+            (3, "expected statement.".to_string())
+        );
+    }
+
+    #[test]
+    fn keeps_unknown_error_format() {
+        assert_eq!(
+            split_parse_error("unexpected", "script.rpy"),
+            (0, "unexpected".to_string())
+        );
+    }
+
+    #[test]
+    fn strips_utf8_byte_order_mark() {
+        let bytes = b"\xef\xbb\xbflabel start:";
+
+        for encoding in [RpyEncoding::Auto, RpyEncoding::Utf8, RpyEncoding::Utf8Lossy] {
+            assert_eq!(decode(bytes, encoding).unwrap(), "label start:");
+        }
+        assert_eq!(decode(b"label", RpyEncoding::Utf8).unwrap(), "label");
+    }
+
+    #[test]
+    fn reports_line_of_invalid_utf8() {
+        let bytes = b"label start:\n    \"Caf\xe9\"\n";
+
+        assert_eq!(decode(bytes, RpyEncoding::Utf8), Err(2));
+        assert_eq!(decode(b"\xff", RpyEncoding::Utf8), Err(1));
+        assert_eq!(
+            decode(bytes, RpyEncoding::Utf8Lossy).unwrap(),
+            "label start:\n    \"Caf\u{fffd}\"\n"
+        );
+        assert_eq!(
+            decode(bytes, RpyEncoding::Latin1).unwrap(),
+            "label start:\n    \"Caf\u{e9}\"\n"
+        );
+    }
+
+    #[test]
+    fn detects_utf16() {
+        let text = "label café:";
+        let little: Vec<u8> = [0xff, 0xfe]
+            .into_iter()
+            .chain(text.encode_utf16().flat_map(u16::to_le_bytes))
+            .collect();
+        let big: Vec<u8> = [0xfe, 0xff]
+            .into_iter()
+            .chain(text.encode_utf16().flat_map(u16::to_be_bytes))
+            .collect();
+
+        assert_eq!(decode(&little, RpyEncoding::Auto).unwrap(), text);
+        assert_eq!(decode(&big, RpyEncoding::Auto).unwrap(), text);
+        // Without a byte order mark the file is read as UTF-8
+        assert_eq!(
+            decode("café".as_bytes(), RpyEncoding::Auto).unwrap(),
+            "café"
+        );
+        assert_eq!(decode(b"caf\xe9", RpyEncoding::Auto), Err(1));
+    }
 
-        Ok(Rpy(ast))
+    #[test]
+    fn expands_tabs_to_tab_stops() {
+        assert_eq!(expand_tabs("\tsay", 4), "    say");
+        assert_eq!(expand_tabs("ab\tc", 4), "ab  c");
+        assert_eq!(expand_tabs("abcd\te", 4), "abcd    e");
+        assert_eq!(expand_tabs("a\t\tb", 4), "a       b");
+        // Columns start over on every line
+        assert_eq!(expand_tabs("abc\n\tx", 8), "abc\n        x");
+        assert_eq!(expand_tabs("\tx", 0), " x");
     }
 }
@@ -0,0 +1,700 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bevy::prelude::*;
+use bevy_kira_audio::AudioSource;
+use renpy_parser::parsers::AST;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    NovelData, NovelImage, NovelSettings, build_label_table,
+    generate::{GeneratedNode, load_generated_assets, shift_generated},
+    hot_reload::{collect_nodes, find_line},
+    list_ast_indices,
+    messages::{EventLoadGame, EventRestoreState, EventSaveGame},
+    script::Statements,
+    transforms::NovelTransform,
+    variables::NovelVariables,
+};
+
+/// Possible errors that can be produced by saving or loading a game
+#[non_exhaustive]
+#[derive(Debug, Error)]
+pub enum NovelSaveError {
+    /// An [IO](std::io) Error
+    #[error("Could not read or write the save file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Could not serialize the novel state: {0}")]
+    Serialize(#[from] ron::Error),
+    #[error("Could not parse the save file: {0}")]
+    Deserialize(#[from] ron::error::SpannedError),
+    /// The save was made with another version of the script and doesn't record where its
+    /// indices point in it, they would land on unrelated lines
+    #[error("The save was made with another version of the script")]
+    ScriptChanged,
+    /// The save points into a label the running script no longer has
+    #[error("The save points into label {0}, which the script no longer has")]
+    MissingLabel(String),
+}
+
+/// Hash of a scenario, stable across sessions, telling apart saves made from another version
+/// of the script
+pub fn script_hash(ast: &[AST], statements: &Statements) -> u64 {
+    fnv1a(format!("{:?}{:?}", ast, statements).as_bytes())
+}
+
+/// FNV-1a hash of `bytes`, unlike the std hashers the same in every session
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Image shown with `show` at the time of a snapshot
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SavedImage {
+    pub image: String,
+    pub transform: NovelTransform,
+    /// Drawing order among the shown images
+    pub z: f32,
+}
+
+/// Where a saved index points in the script, to find it again in another version of it
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SavedPosition {
+    /// Label the node is in, `None` before the first label
+    pub label: Option<String>,
+    /// Offset of the node from the label as written in the script, generated nodes left out
+    pub offset: usize,
+    /// Speaker and text of the node when it is a say line
+    pub line: Option<(Option<String>, String)>,
+}
+
+impl SavedPosition {
+    fn capture(novel_data: &NovelData, nodes: &HashMap<usize, AST>, index: usize) -> Self {
+        let (label, offset) = novel_data.script_position(index);
+
+        SavedPosition {
+            label: label.map(str::to_string),
+            offset,
+            line: match nodes.get(&index) {
+                Some(AST::Say(_, who, what)) => Some((who.clone(), what.clone())),
+                _ => None,
+            },
+        }
+    }
+
+    /// Index of the position in a script with `labels` and `nodes`: the same say line
+    /// nearest to it, or the node at the same offset from the label
+    fn find(
+        &self,
+        labels: &HashMap<String, usize>,
+        nodes: &HashMap<usize, AST>,
+        max_index: usize,
+    ) -> Result<usize, NovelSaveError> {
+        let label_index = match &self.label {
+            Some(label) => *labels
+                .get(label)
+                .ok_or_else(|| NovelSaveError::MissingLabel(label.clone()))?,
+            None => 0,
+        };
+        let expected = (label_index + self.offset).min(max_index);
+
+        if let Some((who, what)) = &self.line
+            && let Some(index) = find_line(nodes, who, what, expected)
+        {
+            return Ok(index);
+        }
+
+        Ok(expected)
+    }
+}
+
+/// Snapshot of everything needed to put the novel back where it was, for the scenario it was
+/// taken from
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct NovelSaveState {
+    /// Seconds since the Unix epoch when the snapshot was taken
+    pub timestamp: u64,
+    /// Index of the node the player was at, it is handled again on restore
+    pub current_index: usize,
+    pub call_stack: Vec<usize>,
+    pub variables: NovelVariables,
+    pub background: Option<String>,
+    pub images: Vec<SavedImage>,
+    pub music: Option<String>,
+    /// [`script_hash`] of the scenario the snapshot was taken from, `None` in saves from before
+    /// it was recorded
+    #[serde(default)]
+    pub script_hash: Option<u64>,
+    /// The snapshot was taken at a menu, which is offered again rather than skipped over
+    #[serde(default)]
+    pub menu: bool,
+    /// Nodes injected by generators at the time of the snapshot, injected again on load
+    #[serde(default)]
+    pub generated: BTreeMap<usize, GeneratedNode>,
+    /// Where `current_index` points in the script, recorded for saves written to disk.
+    /// `None` in saves from before it was recorded
+    #[serde(default)]
+    pub position: Option<SavedPosition>,
+    /// Where each index of `call_stack` points in the script
+    #[serde(default)]
+    pub call_stack_positions: Vec<SavedPosition>,
+}
+
+impl NovelSaveState {
+    pub fn capture<'a>(
+        novel_data: &NovelData,
+        variables: &NovelVariables,
+        images: impl Iterator<Item = (&'a NovelImage, &'a Transform)>,
+    ) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default();
+
+        let mut images: Vec<SavedImage> = images
+            .map(|(image, transform)| SavedImage {
+                image: image.image.clone(),
+                transform: image.transform,
+                z: transform.translation.z,
+            })
+            .collect();
+        images.sort_by(|a, b| a.z.total_cmp(&b.z));
+
+        NovelSaveState {
+            timestamp,
+            current_index: novel_data.current_index,
+            call_stack: novel_data.call_stack.clone(),
+            variables: variables.clone(),
+            background: novel_data.background.clone(),
+            images,
+            music: novel_data.music.clone(),
+            script_hash: Some(novel_data.script_hash),
+            menu: novel_data.menu.is_some(),
+            generated: novel_data.generated.clone(),
+            position: None,
+            call_stack_positions: Vec::new(),
+        }
+    }
+
+    /// Records where the indices of the snapshot point in the script, for a save to load
+    /// once the script changed
+    pub fn record_positions(&mut self, novel_data: &NovelData) {
+        let mut nodes = HashMap::new();
+        collect_nodes(&novel_data.ast, &mut nodes);
+
+        self.position = Some(SavedPosition::capture(
+            novel_data,
+            &nodes,
+            self.current_index,
+        ));
+        self.call_stack_positions = self
+            .call_stack
+            .iter()
+            .map(|index| SavedPosition::capture(novel_data, &nodes, *index))
+            .collect();
+    }
+
+    /// Moves the indices of the snapshot along with a node injected at `index`
+    pub fn shift_indices(&mut self, index: usize) {
+        for node_index in std::iter::once(&mut self.current_index).chain(self.call_stack.iter_mut())
+        {
+            if *node_index >= index {
+                *node_index += 1;
+            }
+        }
+
+        self.generated = shift_generated(&self.generated, index);
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), NovelSaveError> {
+        let content = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())?;
+        write_atomic(path, content)
+    }
+
+    pub fn read(path: &Path) -> Result<Self, NovelSaveError> {
+        let content = fs::read_to_string(path)?;
+        Ok(ron::from_str(&content)?)
+    }
+
+    /// Moves the indices of a snapshot taken from another version of the script onto the
+    /// running one, by the say line or the label and offset each points at. The generated
+    /// nodes of the snapshot are dropped, they were injected into the old script.
+    pub fn remap_script(&mut self, novel_data: &NovelData) -> Result<(), NovelSaveError> {
+        match self.script_hash {
+            Some(hash) if hash == novel_data.script_hash => return Ok(()),
+            Some(_) => {}
+            None => {
+                warn!("The save doesn't tell which version of the script it was made with");
+                return Ok(());
+            }
+        }
+
+        let Some(position) = &self.position else {
+            return Err(NovelSaveError::ScriptChanged);
+        };
+        if self.call_stack_positions.len() != self.call_stack.len() {
+            return Err(NovelSaveError::ScriptChanged);
+        }
+
+        let mut nodes = HashMap::new();
+        collect_nodes(&novel_data.source_ast, &mut nodes);
+        let labels = build_label_table(&novel_data.source_ast);
+
+        let mut indices = list_ast_indices(novel_data.source_ast.clone());
+        indices.extend(novel_data.source_statements.keys());
+        let max_index = indices.into_iter().max().unwrap_or_default();
+
+        self.current_index = position.find(&labels, &nodes, max_index)?;
+        self.call_stack = self
+            .call_stack_positions
+            .iter()
+            .map(|position| position.find(&labels, &nodes, max_index))
+            .collect::<Result<_, _>>()?;
+        self.script_hash = Some(novel_data.script_hash);
+        self.generated.clear();
+        Ok(())
+    }
+}
+
+/// Writes `content` next to `path` and renames it over `path`, so that a crash mid-write
+/// leaves the previous file intact
+pub fn write_atomic(path: &Path, content: impl AsRef<[u8]>) -> Result<(), NovelSaveError> {
+    if let Some(directory) = path.parent() {
+        fs::create_dir_all(directory)?;
+    }
+
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let temporary = PathBuf::from(temporary);
+
+    fs::write(&temporary, content)?;
+    fs::rename(&temporary, path)?;
+
+    Ok(())
+}
+
+/// Seconds a file waits after a change before being written, so that skipping through a
+/// scene or dragging a volume slider doesn't write it every frame
+const WRITE_DELAY: f32 = 1.0;
+
+/// Countdown to writing a file a moment after it changed, and right away on exit
+#[derive(Default)]
+pub struct DelayedWrite(Option<f32>);
+
+impl DelayedWrite {
+    /// Starts the countdown, unless it is running already
+    pub fn schedule(&mut self) {
+        self.0.get_or_insert(WRITE_DELAY);
+    }
+
+    /// Whether the file is to be written now, once the countdown ran out or the app exits
+    pub fn due(&mut self, time: &Time, er_app_exit: &mut MessageReader<AppExit>) -> bool {
+        self.0.is_some() && self.tick(time.delta_secs(), er_app_exit.read().count() > 0)
+    }
+
+    fn tick(&mut self, delta: f32, exiting: bool) -> bool {
+        let Some(seconds) = self.0 else {
+            return false;
+        };
+
+        let seconds = seconds - delta;
+        if seconds > 0.0 && !exiting {
+            self.0 = Some(seconds);
+            return false;
+        }
+
+        self.0 = None;
+        true
+    }
+}
+
+/// Empty folder `name` of the temporary directory, for tests that write files
+#[cfg(test)]
+pub(crate) fn test_directory(name: &str) -> PathBuf {
+    let directory = std::env::temp_dir().join(format!("bevy_novel_{}", name));
+    let _ = fs::remove_dir_all(&directory);
+    fs::create_dir_all(&directory).unwrap();
+    directory
+}
+
+/// Save found in the save directory
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SaveSlot {
+    pub slot: usize,
+    /// Seconds since the Unix epoch when the game was saved
+    pub timestamp: u64,
+}
+
+pub fn save_slot_path(directory: &Path, slot: usize) -> PathBuf {
+    directory.join(format!("slot_{}.ron", slot))
+}
+
+/// Saves in `directory`, ordered by slot
+pub fn list_save_slots(directory: &Path) -> Vec<SaveSlot> {
+    let Ok(entries) = fs::read_dir(directory) else {
+        return Vec::new();
+    };
+
+    let mut slots: Vec<SaveSlot> = entries
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            let slot = path
+                .file_name()?
+                .to_str()?
+                .strip_prefix("slot_")?
+                .strip_suffix(".ron")?
+                .parse()
+                .ok()?;
+            let state = NovelSaveState::read(&path).ok()?;
+
+            Some(SaveSlot {
+                slot,
+                timestamp: state.timestamp,
+            })
+        })
+        .collect();
+    slots.sort_by_key(|slot| slot.slot);
+
+    slots
+}
+
+pub fn handle_save_game(
+    mut er_save_game: MessageReader<EventSaveGame>,
+    novel_data: Res<NovelData>,
+    novel_settings: Res<NovelSettings>,
+    variables: Res<NovelVariables>,
+    images: Query<(&NovelImage, &Transform)>,
+) {
+    for event in er_save_game.read() {
+        let mut state = NovelSaveState::capture(&novel_data, &variables, images.iter());
+        state.record_positions(&novel_data);
+        let path = save_slot_path(&novel_settings.save_directory, event.slot);
+
+        if let Err(err) = state.write(&path) {
+            error!("Saving slot {}: {}", event.slot, err);
+        }
+    }
+}
+
+pub fn handle_load_game(
+    mut er_load_game: MessageReader<EventLoadGame>,
+    mut novel_data: ResMut<NovelData>,
+    novel_settings: Res<NovelSettings>,
+    mut images: ResMut<Assets<Image>>,
+    mut audio_sources: ResMut<Assets<AudioSource>>,
+    mut ew_restore_state: MessageWriter<EventRestoreState>,
+) {
+    for event in er_load_game.read() {
+        let path = save_slot_path(&novel_settings.save_directory, event.slot);
+
+        let state = NovelSaveState::read(&path).and_then(|mut state| {
+            state.remap_script(&novel_data)?;
+            Ok(state)
+        });
+
+        match state {
+            Ok(state) => {
+                // Rollback doesn't reach past a loaded game
+                novel_data.history.clear();
+                novel_data.rolled_back.clear();
+
+                // The indices of the save count the generated nodes it was made with
+                if state.generated != novel_data.generated {
+                    novel_data.restore_generated(&state.generated);
+                }
+                load_generated_assets(
+                    &state,
+                    &mut novel_data,
+                    &novel_settings,
+                    &mut images,
+                    &mut audio_sources,
+                );
+
+                ew_restore_state.write(EventRestoreState { state });
+            }
+            Err(err) => error!("Loading slot {}: {}", event.slot, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpy_asset_loader::parse_script;
+
+    fn state() -> NovelSaveState {
+        let mut variables = NovelVariables::default();
+        // A single variable, the order of several would differ between maps
+        variables.execute("points = 3").unwrap();
+
+        NovelSaveState {
+            timestamp: 1_700_000_000,
+            current_index: 7,
+            call_stack: vec![3, 12],
+            variables,
+            background: Some("bg room".to_string()),
+            images: vec![SavedImage {
+                image: "eileen happy".to_string(),
+                transform: NovelTransform::align(0.0, 1.0),
+                z: 1.01,
+            }],
+            music: Some("theme.ogg".to_string()),
+            script_hash: Some(42),
+            menu: true,
+            generated: BTreeMap::from([(
+                8,
+                GeneratedNode::Line {
+                    character: "e".to_string(),
+                    text: "Hi".to_string(),
+                },
+            )]),
+            position: Some(SavedPosition {
+                label: Some("start".to_string()),
+                offset: 2,
+                line: Some((Some("e".to_string()), "Hello".to_string())),
+            }),
+            call_stack_positions: vec![SavedPosition::default(); 2],
+        }
+    }
+
+    #[test]
+    fn round_trips_through_ron() {
+        let path = test_directory("round_trips_through_ron").join("slot_0.ron");
+        let state = state();
+        state.write(&path).unwrap();
+
+        let read = NovelSaveState::read(&path).unwrap();
+        assert_eq!(format!("{:?}", read), format!("{:?}", state));
+        assert_eq!(read.variables.get_int("points"), Some(3));
+    }
+
+    #[test]
+    fn reads_saves_without_newer_fields() {
+        let path = test_directory("reads_saves_without_newer_fields").join("slot_0.ron");
+        fs::write(
+            &path,
+            "(timestamp: 1, current_index: 2, call_stack: [], variables: (values: {}), \
+             background: None, images: [], music: None)",
+        )
+        .unwrap();
+
+        let read = NovelSaveState::read(&path).unwrap();
+        assert_eq!(read.current_index, 2);
+        assert_eq!(read.script_hash, None);
+        assert!(!read.menu);
+        assert!(read.generated.is_empty());
+    }
+
+    #[test]
+    fn shifts_indices_past_an_injected_node() {
+        let mut state = state();
+        state.shift_indices(8);
+
+        // Indices before the injected node stay
+        assert_eq!(state.current_index, 7);
+        assert_eq!(state.call_stack, vec![3, 13]);
+        assert_eq!(state.generated.keys().collect::<Vec<_>>(), vec![&9]);
+
+        state.shift_indices(7);
+        assert_eq!(state.current_index, 8);
+        assert_eq!(state.call_stack, vec![3, 14]);
+        assert_eq!(state.generated.keys().collect::<Vec<_>>(), vec![&10]);
+    }
+
+    const SCRIPT: &str = r#"label start:
+    "One"
+    show eileen happy
+    call side
+    "Two"
+
+label side:
+    "Side"
+    return
+"#;
+
+    const EDITED_SCRIPT: &str = r#"label intro:
+    "New"
+
+label start:
+    "One, edited"
+    show eileen happy
+    call side
+    "Zero"
+    "Two"
+
+label side:
+    "Side, edited"
+    return
+"#;
+
+    fn novel_data(script: &str) -> NovelData {
+        let (ast, statements) = parse_script(script, Path::new("script.rpy")).unwrap();
+        let mut novel_data = NovelData::default();
+        novel_data.set_scenario(ast, statements);
+        novel_data
+    }
+
+    /// Index of the first node of `novel_data` matching `matches`
+    fn index_of(novel_data: &NovelData, matches: impl Fn(&AST) -> bool) -> usize {
+        let mut nodes = HashMap::new();
+        collect_nodes(&novel_data.ast, &mut nodes);
+        nodes
+            .into_iter()
+            .filter(|(_, node)| matches(node))
+            .map(|(index, _)| index)
+            .min()
+            .unwrap()
+    }
+
+    fn say_index(novel_data: &NovelData, text: &str) -> usize {
+        index_of(
+            novel_data,
+            |node| matches!(node, AST::Say(_, _, t) if t == text),
+        )
+    }
+
+    /// Save of `SCRIPT` at `current_index`, returning from `side` to "Two"
+    fn saved_at(novel_data: &NovelData, current_index: usize) -> NovelSaveState {
+        let mut state = NovelSaveState {
+            current_index,
+            call_stack: vec![say_index(novel_data, "Two")],
+            script_hash: Some(novel_data.script_hash),
+            generated: BTreeMap::from([(
+                1,
+                GeneratedNode::Line {
+                    character: "e".to_string(),
+                    text: "Hi".to_string(),
+                },
+            )]),
+            ..default()
+        };
+        state.record_positions(novel_data);
+        state
+    }
+
+    #[test]
+    fn keeps_saves_of_the_same_script() {
+        let novel_data = novel_data(SCRIPT);
+        let mut state = saved_at(&novel_data, say_index(&novel_data, "Side"));
+        let saved = state.clone();
+
+        state.remap_script(&novel_data).unwrap();
+        assert_eq!(format!("{:?}", state), format!("{:?}", saved));
+
+        // Saves from before the hash was recorded are let through
+        state.script_hash = None;
+        state.current_index = 100;
+        state.remap_script(&novel_data).unwrap();
+        assert_eq!(state.current_index, 100);
+    }
+
+    #[test]
+    fn remaps_saves_of_an_edited_script() {
+        let old = novel_data(SCRIPT);
+        let new = novel_data(EDITED_SCRIPT);
+
+        // The edited line keeps its offset in the label, the call returns to the same line
+        let mut state = saved_at(&old, say_index(&old, "Side"));
+        state.remap_script(&new).unwrap();
+        assert_eq!(state.current_index, say_index(&new, "Side, edited"));
+        assert_eq!(state.call_stack, vec![say_index(&new, "Two")]);
+        assert_eq!(state.script_hash, Some(new.script_hash));
+        assert!(state.generated.is_empty());
+
+        let show = |node: &AST| matches!(node, AST::Show(_, _));
+        let mut state = saved_at(&old, index_of(&old, show));
+        state.remap_script(&new).unwrap();
+        assert_eq!(state.current_index, index_of(&new, show));
+    }
+
+    #[test]
+    fn refuses_saves_of_a_missing_label() {
+        let old = novel_data(SCRIPT);
+        let new = novel_data(&SCRIPT.replace("side", "aside"));
+
+        let mut state = saved_at(&old, say_index(&old, "Side"));
+        assert!(matches!(
+            state.remap_script(&new),
+            Err(NovelSaveError::MissingLabel(label)) if label == "side"
+        ));
+
+        // Saves from before positions were recorded can't be moved onto another script
+        let mut state = saved_at(&old, say_index(&old, "One"));
+        state.position = None;
+        assert!(matches!(
+            state.remap_script(&new),
+            Err(NovelSaveError::ScriptChanged)
+        ));
+    }
+
+    #[test]
+    fn writes_atomically() {
+        let directory = test_directory("writes_atomically");
+        let path = directory.join("nested").join("file.ron");
+
+        write_atomic(&path, "first").unwrap();
+        write_atomic(&path, "second").unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "second");
+        assert!(!directory.join("nested").join("file.ron.tmp").exists());
+    }
+
+    #[test]
+    fn delays_writes() {
+        let mut write = DelayedWrite::default();
+        assert!(!write.tick(5.0, false));
+
+        write.schedule();
+        assert!(!write.tick(0.6, false));
+        // Changes while waiting don't push the write back
+        write.schedule();
+        assert!(write.tick(0.6, false));
+        assert!(!write.tick(5.0, false));
+
+        write.schedule();
+        assert!(write.tick(0.0, true));
+    }
+
+    #[test]
+    fn lists_save_slots() {
+        let directory = test_directory("lists_save_slots");
+        for (slot, timestamp) in [(10, 3), (2, 1), (0, 2)] {
+            NovelSaveState {
+                timestamp,
+                ..default()
+            }
+            .write(&save_slot_path(&directory, slot))
+            .unwrap();
+        }
+        fs::write(directory.join("slot_5.ron"), "not a save").unwrap();
+        fs::write(directory.join("slot_x.ron"), "").unwrap();
+        fs::write(directory.join("read_lines.ron"), "").unwrap();
+
+        assert_eq!(
+            list_save_slots(&directory),
+            vec![
+                SaveSlot {
+                    slot: 0,
+                    timestamp: 2
+                },
+                SaveSlot {
+                    slot: 2,
+                    timestamp: 1
+                },
+                SaveSlot {
+                    slot: 10,
+                    timestamp: 3
+                },
+            ]
+        );
+        assert!(list_save_slots(&directory.join("missing")).is_empty());
+    }
+}
@@ -0,0 +1,237 @@
+use std::collections::HashSet;
+
+use bevy::{asset::LoadedFolder, prelude::*};
+use renpy_parser::parsers::AST;
+
+use crate::{
+    build_label_table, list_ast_indices,
+    messages::EventStartScenario,
+    rpy_asset_loader::Rpy,
+    script::{Statements, rebase_statements},
+    variables::NovelVariables,
+};
+
+/// Several `.rpy` files run as one script. The nodes of every file are moved past the ones
+/// of the files before it, so labels and defines of all of them share one namespace and
+/// `jump` and `call` cross files.
+#[derive(Clone, Debug, Default)]
+pub struct Scenario {
+    pub ast: Vec<AST>,
+    pub statements: Statements,
+    /// Entry label of the first file that has one
+    pub entry_label: Option<String>,
+    /// Files the scenario was merged from, in order
+    pub files: Vec<AssetId<Rpy>>,
+}
+
+/// Moves every node of `ast` `offset` lines down
+fn rebase_ast(ast: &[AST], offset: usize) -> Vec<AST> {
+    ast.iter()
+        .filter(|node| !matches!(node, AST::Error))
+        .map(|node| {
+            let mut node = match node {
+                AST::Label(index, label, label_ast, opts) => AST::Label(
+                    *index,
+                    label.clone(),
+                    rebase_ast(label_ast, offset),
+                    opts.clone(),
+                ),
+                _ => node.clone(),
+            };
+            node.set_index(node.index() + offset);
+            node
+        })
+        .collect()
+}
+
+/// Labels of `ast` that are in `labels` already, adding the others to it
+fn repeated_labels(labels: &mut HashSet<String>, ast: &[AST]) -> Vec<String> {
+    let mut repeated: Vec<String> = build_label_table(ast)
+        .into_keys()
+        .filter(|label| !labels.insert(label.clone()))
+        .collect();
+    repeated.sort();
+    repeated
+}
+
+impl Scenario {
+    /// Merges `files` in order. Running off the end of a file ends the story, as in Ren'Py,
+    /// rather than going on into the next file.
+    pub fn merge<'a>(files: impl IntoIterator<Item = (AssetId<Rpy>, &'a Rpy)>) -> Self {
+        let mut scenario = Scenario::default();
+        let mut labels = HashSet::new();
+        let mut offset = 0;
+
+        for (id, rpy) in files {
+            for label in repeated_labels(&mut labels, &rpy.ast) {
+                warn!("Label `{}` is defined in more than one file", label);
+            }
+
+            let mut indices = list_ast_indices(rpy.ast.clone());
+            indices.extend(rpy.statements.keys());
+            let end = offset + indices.into_iter().max().unwrap_or_default() + 1;
+
+            scenario.ast.extend(rebase_ast(&rpy.ast, offset));
+            scenario.ast.push(AST::Return(end, None));
+            scenario
+                .statements
+                .extend(rebase_statements(&rpy.statements, offset));
+
+            if scenario.entry_label.is_none() {
+                scenario.entry_label = rpy.entry_label.clone();
+            }
+            scenario.files.push(id);
+
+            offset = end + 1;
+        }
+
+        scenario
+    }
+
+    /// Merges the `.rpy` files of `handles`, `None` until all of them are loaded
+    pub fn from_handles(handles: &[Handle<Rpy>], rpy_assets: &Assets<Rpy>) -> Option<Self> {
+        let files = handles
+            .iter()
+            .map(|handle| Some((handle.id(), rpy_assets.get(handle)?)))
+            .collect::<Option<Vec<_>>>()?;
+
+        Some(Scenario::merge(files))
+    }
+
+    /// Merges the `.rpy` files of a folder loaded with `AssetServer::load_folder`, ordered by
+    /// path
+    pub fn from_folder(folder: &LoadedFolder, rpy_assets: &Assets<Rpy>) -> Option<Self> {
+        let mut handles: Vec<Handle<Rpy>> = folder
+            .handles
+            .iter()
+            .filter_map(|handle| handle.clone().try_typed::<Rpy>().ok())
+            .collect();
+        handles.sort_by_key(|handle| handle.path().map(|path| path.to_string()));
+
+        Scenario::from_handles(&handles, rpy_assets)
+    }
+}
+
+impl From<&Scenario> for EventStartScenario {
+    fn from(scenario: &Scenario) -> Self {
+        EventStartScenario {
+            ast: scenario.ast.clone(),
+            statements: scenario.statements.clone(),
+            files: scenario.files.clone(),
+            entry_label: scenario.entry_label.clone(),
+            variables: NovelVariables::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::path::Path;
+
+    use bevy::asset::uuid::Uuid;
+
+    use super::*;
+    use crate::{find_element_with_index, rpy_asset_loader::parse_script, script::Statement};
+
+    const FIRST: &str = r#"label start:
+    e "Hello"
+    call other
+    jump other
+"#;
+
+    const SECOND: &str = r#"label other:
+    $ points += 1
+    "From the second file"
+    return
+"#;
+
+    fn rpy(content: &str, entry_label: Option<&str>) -> Rpy {
+        let (ast, statements) = parse_script(content, Path::new("script.rpy")).unwrap();
+        Rpy {
+            ast,
+            statements,
+            entry_label: entry_label.map(str::to_string),
+        }
+    }
+
+    fn id(index: u128) -> AssetId<Rpy> {
+        AssetId::Uuid {
+            uuid: Uuid::from_u128(index),
+        }
+    }
+
+    /// Node at `index`, inside labels or not
+    fn node(ast: &[AST], index: usize) -> Option<AST> {
+        find_element_with_index(ast.to_vec(), index).or_else(|| {
+            ast.iter().find_map(|label| match label {
+                AST::Label(_, _, label_ast, _) => node(label_ast, index),
+                _ => None,
+            })
+        })
+    }
+
+    fn merge() -> Scenario {
+        let first = rpy(FIRST, None);
+        let second = rpy(SECOND, Some("other"));
+        Scenario::merge([(id(0), &first), (id(1), &second)])
+    }
+
+    #[test]
+    fn rebases_later_files() {
+        let scenario = merge();
+
+        // The first file runs up to line 4, its end is at 5 and the second file starts at 6
+        assert_eq!(
+            build_label_table(&scenario.ast),
+            HashMap::from([("start".to_string(), 1), ("other".to_string(), 7)])
+        );
+        assert!(matches!(node(&scenario.ast, 2), Some(AST::Say(2, _, what)) if what == "Hello"));
+        assert!(matches!(node(&scenario.ast, 4), Some(AST::Jump(4, label, _)) if label == "other"));
+        assert!(matches!(
+            node(&scenario.ast, 9),
+            Some(AST::Say(9, None, what)) if what == "From the second file"
+        ));
+        assert!(matches!(node(&scenario.ast, 10), Some(AST::Return(10, _))));
+
+        assert_eq!(scenario.statements.keys().collect::<Vec<_>>(), vec![&3, &8]);
+        assert!(matches!(
+            scenario.statements.get(&3),
+            Some(Statement::Call(label)) if label == "other"
+        ));
+        assert!(matches!(
+            scenario.statements.get(&8),
+            Some(Statement::Python(code)) if code == "points += 1"
+        ));
+    }
+
+    #[test]
+    fn ends_every_file() {
+        let scenario = merge();
+
+        let returns: Vec<usize> = scenario
+            .ast
+            .iter()
+            .filter_map(|node| match node {
+                AST::Return(index, None) => Some(*index),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(returns, vec![5, 11]);
+        assert!(matches!(scenario.ast.last(), Some(AST::Return(11, None))));
+
+        assert_eq!(scenario.files, vec![id(0), id(1)]);
+        assert_eq!(scenario.entry_label.as_deref(), Some("other"));
+    }
+
+    #[test]
+    fn finds_repeated_labels() {
+        let mut labels = HashSet::new();
+        assert!(repeated_labels(&mut labels, &rpy(FIRST, None).ast).is_empty());
+        assert!(repeated_labels(&mut labels, &rpy(SECOND, None).ast).is_empty());
+        assert_eq!(
+            repeated_labels(&mut labels, &rpy(FIRST, None).ast),
+            vec!["start".to_string()]
+        );
+    }
+}
@@ -0,0 +1,800 @@
+//! Statements bevy_novel runs on top of what `renpy_parser` understands.
+//!
+//! [`preprocess`] lifts these statements out of the script source before it is handed to
+//! `renpy_parser`. Their lines are blanked rather than removed, so every other node keeps its
+//! line number as its index, and the lifted statements are returned keyed by that same index.
+
+use std::collections::BTreeMap;
+use std::sync::LazyLock;
+
+use regex::Regex;
+use renpy_parser::{parse_scenario_from_string, parsers::AST};
+use thiserror::Error;
+
+/// A statement lifted out of the script by [`preprocess`]
+#[derive(Clone, Debug)]
+pub enum Statement {
+    /// `call label`: jump to `label` and come back here on `return`
+    Call(String),
+    /// `menu:` block offering the player a choice
+    Menu(Menu),
+    /// `if`/`elif`/`else` chain
+    If(Conditional),
+    /// `$ name = expression` line
+    Python(String),
+    /// `default name = expression`, applied when the scenario starts
+    Default(String, String),
+    /// `voice "file"`: voice line played along with the next say line
+    Voice(String),
+    /// `play channel "file"` with its `fadein`, `fadeout` and `loop`/`noloop` clauses
+    Play(AudioPlay),
+    /// `stop channel`, faded out over the given seconds
+    Stop(String, Option<f32>),
+    /// Continue after the given index. Left on branch headers (such as menu captions) so that
+    /// running off the end of one branch skips the others.
+    Goto(usize),
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct AudioPlay {
+    /// `music`, `sound`, `voice` or a custom channel
+    pub channel: String,
+    pub filename: String,
+    /// Seconds the new audio fades in over
+    pub fade_in: Option<f32>,
+    /// Seconds the audio already playing on the channel fades out over
+    pub fade_out: Option<f32>,
+    /// `loop` or `noloop`, music loops by default and sound and voice don't
+    pub looped: Option<bool>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Menu {
+    /// Say line written inside the menu block, shown along with the choices
+    pub prompt: Option<AST>,
+    pub choices: Vec<MenuChoice>,
+    /// Index of the last line of the menu block
+    pub end: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct MenuChoice {
+    pub caption: String,
+    /// Expression that has to hold for the choice to be offered
+    pub condition: Option<String>,
+    /// Index of the caption line, the branch starts right after it
+    pub index: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct Conditional {
+    pub branches: Vec<Branch>,
+    /// Index of the last line of the last branch
+    pub end: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct Branch {
+    /// `None` for the `else:` branch
+    pub condition: Option<String>,
+    /// Index of the branch header, the branch starts right after it
+    pub index: usize,
+}
+
+impl Statement {
+    fn shift(&mut self, index: usize) {
+        self.map_indices(|i| if i >= index { i + 1 } else { i });
+    }
+
+    /// Applies `f` to every index the statement refers to
+    fn map_indices(&mut self, f: impl Fn(usize) -> usize) {
+        match self {
+            Statement::Call(_)
+            | Statement::Python(_)
+            | Statement::Default(_, _)
+            | Statement::Voice(_)
+            | Statement::Play(_)
+            | Statement::Stop(_, _) => {}
+            Statement::If(conditional) => {
+                conditional.end = f(conditional.end);
+                for branch in conditional.branches.iter_mut() {
+                    branch.index = f(branch.index);
+                }
+            }
+            Statement::Menu(menu) => {
+                menu.end = f(menu.end);
+                for choice in menu.choices.iter_mut() {
+                    choice.index = f(choice.index);
+                }
+                if let Some(prompt) = menu.prompt.as_mut() {
+                    prompt.set_index(f(prompt.index()));
+                }
+            }
+            Statement::Goto(i) => *i = f(*i),
+        }
+    }
+}
+
+/// Errors raised by [`preprocess`]
+#[derive(Debug, Error)]
+pub enum ScriptError {
+    /// A line of a lifted block is indented less than the first line of its block, `line` is
+    /// 1-based
+    #[error("Line is indented less than the block it belongs to")]
+    Indentation { line: usize },
+}
+
+/// Lifted statements keyed by the index (line number) they were written at
+pub type Statements = BTreeMap<usize, Statement>;
+
+static CALL: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^call\s+([A-Za-z_][\w.]*)(\s+from\s+[A-Za-z_]\w*)?$").unwrap());
+static MENU: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^menu(\s+[A-Za-z_]\w*)?\s*:$").unwrap());
+static PYTHON: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^\$\s*(.+)$").unwrap());
+static DEFAULT: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^default\s+([A-Za-z_][\w.]*)\s*=\s*(.+)$").unwrap());
+static VOICE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"^voice\s+("(?:[^"\\]|\\.)*"|'(?:[^'\\]|\\.)*')$"#).unwrap());
+static PLAY: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"^play\s+([A-Za-z_]\w*)\s+("(?:[^"\\]|\\.)*"|'(?:[^'\\]|\\.)*')((?:\s+\S+)*)$"#)
+        .unwrap()
+});
+static STOP: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^stop\s+([A-Za-z_]\w*)(?:\s+fadeout\s+(\d+(?:\.\d+)?))?$").unwrap()
+});
+static IF: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^if\s+(.+):$").unwrap());
+static ELIF: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^elif\s+(.+):$").unwrap());
+static ELSE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^else\s*:$").unwrap());
+static CAPTION: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"^("(?:[^"\\]|\\.)*"|'(?:[^'\\]|\\.)*')(\s+if\s+(.+))?\s*:$"#).unwrap()
+});
+
+/// A logical line of the source along with the lines indented under it
+struct SourceNode {
+    /// 0-based index of the first physical line
+    line: usize,
+    /// Number of physical lines the logical line spans
+    len: usize,
+    indent: usize,
+    text: String,
+    children: Vec<SourceNode>,
+}
+
+/// Splits `content` into the source `renpy_parser` should see and the statements lifted out
+/// of it.
+pub fn preprocess(content: &str) -> Result<(String, Statements), ScriptError> {
+    let content = content
+        .strip_prefix('\u{feff}')
+        .unwrap_or(content)
+        .replace("\r\n", "\n");
+
+    let mut lines: Vec<String> = content.split('\n').map(String::from).collect();
+    let logical_lines = list_logical_lines(&lines);
+
+    let mut position = 0;
+    let nodes = group_source_nodes(&lines, &logical_lines, &mut position, 0);
+
+    let mut statements = Statements::new();
+    lift_statements(&nodes, 0, &mut lines, &mut statements)?;
+
+    Ok((lines.join("\n"), statements))
+}
+
+/// Shifts every statement at or after `index` one line down, mirroring `inject_node`
+pub fn shift_statements(statements: &Statements, index: usize) -> Statements {
+    statements
+        .iter()
+        .map(|(i, statement)| {
+            let mut statement = statement.clone();
+            statement.shift(index);
+
+            if *i >= index {
+                (i + 1, statement)
+            } else {
+                (*i, statement)
+            }
+        })
+        .collect()
+}
+
+/// Moves every statement `offset` lines down, to follow the lines of another file
+pub fn rebase_statements(statements: &Statements, offset: usize) -> Statements {
+    statements
+        .iter()
+        .map(|(i, statement)| {
+            let mut statement = statement.clone();
+            statement.map_indices(|i| i + offset);
+            (i + offset, statement)
+        })
+        .collect()
+}
+
+fn lift_statements(
+    nodes: &[SourceNode],
+    dedent: usize,
+    lines: &mut [String],
+    statements: &mut Statements,
+) -> Result<(), ScriptError> {
+    let mut position = 0;
+    while position < nodes.len() {
+        let node = &nodes[position];
+        position += 1;
+
+        if let Some(captures) = CALL.captures(&node.text)
+            && node.children.is_empty()
+        {
+            blank_lines(node, lines);
+            statements.insert(node.line + 1, Statement::Call(captures[1].to_string()));
+            continue;
+        }
+
+        if let Some(captures) = PYTHON.captures(&node.text)
+            && node.children.is_empty()
+        {
+            blank_lines(node, lines);
+            statements.insert(node.line + 1, Statement::Python(captures[1].to_string()));
+            continue;
+        }
+
+        if let Some(captures) = DEFAULT.captures(&node.text)
+            && node.children.is_empty()
+        {
+            blank_lines(node, lines);
+            statements.insert(
+                node.line + 1,
+                Statement::Default(captures[1].to_string(), captures[2].to_string()),
+            );
+            continue;
+        }
+
+        if let Some(captures) = VOICE.captures(&node.text)
+            && node.children.is_empty()
+        {
+            blank_lines(node, lines);
+            statements.insert(node.line + 1, Statement::Voice(unquote(&captures[1])));
+            continue;
+        }
+
+        if let Some(captures) = PLAY.captures(&node.text)
+            && node.children.is_empty()
+            && let Some(clauses) = parse_audio_clauses(&captures[3])
+        {
+            blank_lines(node, lines);
+            statements.insert(
+                node.line + 1,
+                Statement::Play(AudioPlay {
+                    channel: captures[1].to_string(),
+                    filename: unquote(&captures[2]),
+                    ..clauses
+                }),
+            );
+            continue;
+        }
+
+        if let Some(captures) = STOP.captures(&node.text)
+            && node.children.is_empty()
+        {
+            blank_lines(node, lines);
+            let fade_out = captures
+                .get(2)
+                .and_then(|length| length.as_str().parse().ok());
+            statements.insert(
+                node.line + 1,
+                Statement::Stop(captures[1].to_string(), fade_out),
+            );
+            continue;
+        }
+
+        if let Some(len) = lift_conditional(&nodes[position - 1..], dedent, lines, statements)? {
+            position += len - 1;
+            continue;
+        }
+
+        if MENU.is_match(&node.text)
+            && let Some(statement) = lift_menu(node, dedent, lines, statements)?
+        {
+            blank_lines(node, lines);
+            statements.insert(node.line + 1, statement);
+            continue;
+        }
+
+        lines[node.line] =
+            dedent_line(&lines[node.line], dedent).ok_or(ScriptError::Indentation {
+                line: node.line + 1,
+            })?;
+        lift_statements(&node.children, dedent, lines, statements)?;
+    }
+
+    Ok(())
+}
+
+/// `line` without its first `dedent` columns, `None` if they aren't all spaces
+fn dedent_line(line: &str, dedent: usize) -> Option<String> {
+    let prefix = line.get(..dedent)?;
+    if !prefix.bytes().all(|byte| byte == b' ') {
+        return None;
+    }
+
+    Some(line[dedent..].to_string())
+}
+
+/// Lifts an `if` chain starting at `nodes[0]`, flattening the branches to the indentation of
+/// the `if` line. Returns the number of nodes making up the chain.
+fn lift_conditional(
+    nodes: &[SourceNode],
+    dedent: usize,
+    lines: &mut [String],
+    statements: &mut Statements,
+) -> Result<Option<usize>, ScriptError> {
+    let Some(head) = nodes.first() else {
+        return Ok(None);
+    };
+    let Some(captures) = IF.captures(&head.text) else {
+        return Ok(None);
+    };
+    if head.children.is_empty() {
+        return Ok(None);
+    }
+
+    let mut branches = vec![(head, Some(captures[1].trim().to_string()))];
+    for node in nodes[1..].iter() {
+        if node.children.is_empty() {
+            break;
+        }
+
+        if let Some(captures) = ELIF.captures(&node.text) {
+            branches.push((node, Some(captures[1].trim().to_string())));
+        } else if ELSE.is_match(&node.text) {
+            branches.push((node, None));
+            break;
+        } else {
+            break;
+        }
+    }
+
+    let end = branches
+        .last()
+        .map(|(node, _)| last_index(node))
+        .unwrap_or_default();
+
+    for (i, (node, _)) in branches.iter().enumerate() {
+        blank_lines(node, lines);
+        if i > 0 {
+            statements.insert(node.line + 1, Statement::Goto(end));
+        }
+
+        let branch_dedent = dedent + node.children[0].indent - node.indent;
+        lift_statements(&node.children, branch_dedent, lines, statements)?;
+    }
+
+    let len = branches.len();
+    statements.insert(
+        head.line + 1,
+        Statement::If(Conditional {
+            branches: branches
+                .into_iter()
+                .map(|(node, condition)| Branch {
+                    condition,
+                    index: node.line + 1,
+                })
+                .collect(),
+            end,
+        }),
+    );
+
+    Ok(Some(len))
+}
+
+/// Lifts the captions and prompt of a menu, flattening the branches to the indentation of
+/// the `menu:` line. Returns `None`, leaving the block untouched, if it isn't a valid menu.
+fn lift_menu(
+    node: &SourceNode,
+    dedent: usize,
+    lines: &mut [String],
+    statements: &mut Statements,
+) -> Result<Option<Statement>, ScriptError> {
+    let mut prompt = None;
+    let mut choices = Vec::new();
+
+    for child in node.children.iter() {
+        if let Some(captures) = CAPTION.captures(&child.text)
+            && !child.children.is_empty()
+        {
+            let condition = captures.get(3).map(|c| c.as_str().trim().to_string());
+            choices.push((child, unquote(&captures[1]), condition));
+        } else if prompt.is_none() && choices.is_empty() && child.children.is_empty() {
+            let Ok((ast, errors)) = parse_scenario_from_string(&child.text, "_") else {
+                return Ok(None);
+            };
+            match ast.first() {
+                Some(AST::Say(_, who, what)) if errors.is_empty() => {
+                    prompt = Some((child, AST::Say(child.line + 1, who.clone(), what.clone())));
+                }
+                _ => return Ok(None),
+            }
+        } else {
+            return Ok(None);
+        }
+    }
+
+    if choices.is_empty() {
+        return Ok(None);
+    }
+
+    let end = last_index(node);
+
+    if let Some((child, _)) = prompt {
+        blank_lines(child, lines);
+    }
+
+    for (child, _, _) in choices.iter() {
+        blank_lines(child, lines);
+        statements.insert(child.line + 1, Statement::Goto(end));
+
+        let branch_dedent = dedent + child.children[0].indent - node.indent;
+        lift_statements(&child.children, branch_dedent, lines, statements)?;
+    }
+
+    Ok(Some(Statement::Menu(Menu {
+        prompt: prompt.map(|(_, ast)| ast),
+        choices: choices
+            .into_iter()
+            .map(|(child, caption, condition)| MenuChoice {
+                caption,
+                condition,
+                index: child.line + 1,
+            })
+            .collect(),
+        end,
+    })))
+}
+
+/// Reads the clauses written after the file of a `play` line, `None` if one isn't known
+fn parse_audio_clauses(clauses: &str) -> Option<AudioPlay> {
+    let mut play = AudioPlay::default();
+
+    let mut words = clauses.split_whitespace();
+    while let Some(word) = words.next() {
+        match word {
+            "fadein" => play.fade_in = Some(words.next()?.parse().ok()?),
+            "fadeout" => play.fade_out = Some(words.next()?.parse().ok()?),
+            "loop" => play.looped = Some(true),
+            "noloop" => play.looped = Some(false),
+            _ => return None,
+        }
+    }
+
+    Some(play)
+}
+
+fn blank_lines(node: &SourceNode, lines: &mut [String]) {
+    for line in lines.iter_mut().skip(node.line).take(node.len) {
+        line.clear();
+    }
+}
+
+/// Index of the last line belonging to `node`, including its block
+fn last_index(node: &SourceNode) -> usize {
+    node.children
+        .last()
+        .map(last_index)
+        .unwrap_or(node.line + node.len)
+}
+
+fn unquote(text: &str) -> String {
+    text[1..text.len() - 1]
+        .replace("\\n", "\n")
+        .replace("\\\"", "\"")
+        .replace("\\'", "'")
+}
+
+fn group_source_nodes(
+    lines: &[String],
+    logical_lines: &[(usize, usize)],
+    position: &mut usize,
+    min_indent: usize,
+) -> Vec<SourceNode> {
+    let mut nodes = Vec::new();
+
+    while *position < logical_lines.len() {
+        let (line, len) = logical_lines[*position];
+        let indent = indentation(&lines[line]);
+
+        if indent < min_indent {
+            break;
+        }
+
+        *position += 1;
+        let children = group_source_nodes(lines, logical_lines, position, indent + 1);
+
+        nodes.push(SourceNode {
+            line,
+            len,
+            indent,
+            text: lines[line..line + len].join("\n").trim().to_string(),
+            children,
+        });
+    }
+
+    nodes
+}
+
+/// Groups physical lines into logical lines the same way `renpy_parser` does: open
+/// brackets, strings and trailing backslashes carry a logical line over to the next one.
+fn list_logical_lines(lines: &[String]) -> Vec<(usize, usize)> {
+    let mut logical_lines = Vec::new();
+    let mut start = 0;
+    let mut depth = 0;
+    let mut delimiter: Option<char> = None;
+
+    for (i, line) in lines.iter().enumerate() {
+        let mut escape = false;
+
+        for c in line.chars() {
+            if let Some(d) = delimiter {
+                if escape {
+                    escape = false;
+                } else if c == '\\' {
+                    escape = true;
+                } else if c == d {
+                    delimiter = None;
+                }
+                continue;
+            }
+
+            match c {
+                '"' | '\'' | '`' => delimiter = Some(c),
+                '(' | '[' | '{' => depth += 1,
+                ')' | ']' | '}' if depth > 0 => depth -= 1,
+                _ => {}
+            }
+        }
+
+        if depth > 0 || delimiter.is_some() || line.ends_with('\\') {
+            continue;
+        }
+
+        if lines[start..=i].iter().any(|l| !l.trim().is_empty()) {
+            logical_lines.push((start, i - start + 1));
+        }
+        start = i + 1;
+    }
+
+    if start < lines.len() && lines[start..].iter().any(|l| !l.trim().is_empty()) {
+        logical_lines.push((start, lines.len() - start));
+    }
+
+    logical_lines
+}
+
+fn indentation(line: &str) -> usize {
+    line.chars().take_while(|c| *c == ' ').count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCRIPT: &str = r#"default points = 0
+
+label start:
+    $ points += 1
+    voice "line1.ogg"
+    e "Hello"
+    call chapter
+    if points > 0:
+        "Positive"
+    else:
+        "Not positive"
+    menu:
+        "Pick one"
+        "Left":
+            jump left
+        "Right" if points > 1:
+            "Right"
+    return
+"#;
+
+    fn lines(content: &str) -> Vec<&str> {
+        content.split('\n').collect()
+    }
+
+    #[test]
+    fn lifts_statements() {
+        let (_, statements) = preprocess(SCRIPT).unwrap();
+
+        assert!(matches!(
+            statements.get(&1),
+            Some(Statement::Default(name, value)) if name == "points" && value == "0"
+        ));
+        assert!(matches!(
+            statements.get(&4),
+            Some(Statement::Python(code)) if code == "points += 1"
+        ));
+        assert!(matches!(
+            statements.get(&5),
+            Some(Statement::Voice(file)) if file == "line1.ogg"
+        ));
+        assert!(matches!(
+            statements.get(&7),
+            Some(Statement::Call(label)) if label == "chapter"
+        ));
+    }
+
+    #[test]
+    fn lifts_conditional() {
+        let (content, statements) = preprocess(SCRIPT).unwrap();
+
+        let Some(Statement::If(conditional)) = statements.get(&8) else {
+            panic!("no if at line 8");
+        };
+        assert_eq!(conditional.branches.len(), 2);
+        assert_eq!(
+            conditional.branches[0].condition.as_deref(),
+            Some("points > 0")
+        );
+        assert_eq!(conditional.branches[0].index, 8);
+        assert_eq!(conditional.branches[1].condition, None);
+        assert_eq!(conditional.branches[1].index, 10);
+        assert_eq!(conditional.end, 11);
+        assert!(matches!(statements.get(&10), Some(Statement::Goto(11))));
+
+        // Branches are flattened to the indentation of the `if`
+        let lines = lines(&content);
+        assert_eq!(lines[8], "    \"Positive\"");
+        assert_eq!(lines[10], "    \"Not positive\"");
+    }
+
+    #[test]
+    fn lifts_menu() {
+        let (content, statements) = preprocess(SCRIPT).unwrap();
+
+        let Some(Statement::Menu(menu)) = statements.get(&12) else {
+            panic!("no menu at line 12");
+        };
+        assert!(matches!(&menu.prompt, Some(AST::Say(13, None, what)) if what == "Pick one"));
+        assert_eq!(menu.choices.len(), 2);
+        assert_eq!(menu.choices[0].caption, "Left");
+        assert_eq!(menu.choices[0].index, 14);
+        assert_eq!(menu.choices[1].caption, "Right");
+        assert_eq!(menu.choices[1].condition.as_deref(), Some("points > 1"));
+        assert_eq!(menu.choices[1].index, 16);
+        assert_eq!(menu.end, 17);
+        assert!(matches!(statements.get(&14), Some(Statement::Goto(17))));
+        assert!(matches!(statements.get(&16), Some(Statement::Goto(17))));
+
+        let lines = lines(&content);
+        assert_eq!(lines[14], "    jump left");
+        assert_eq!(lines[16], "    \"Right\"");
+    }
+
+    #[test]
+    fn shifts_menu_prompt() {
+        let (_, statements) = preprocess(SCRIPT).unwrap();
+
+        let shifted = shift_statements(&statements, 13);
+        let Some(Statement::Menu(menu)) = shifted.get(&12) else {
+            panic!("no menu at line 12");
+        };
+        assert!(matches!(&menu.prompt, Some(AST::Say(14, None, _))));
+        assert_eq!(menu.choices[0].index, 15);
+
+        let rebased = rebase_statements(&statements, 100);
+        let Some(Statement::Menu(menu)) = rebased.get(&112) else {
+            panic!("no menu at line 112");
+        };
+        assert!(matches!(&menu.prompt, Some(AST::Say(113, None, _))));
+        assert_eq!(menu.end, 117);
+    }
+
+    #[test]
+    fn keeps_indices() {
+        let (content, statements) = preprocess(SCRIPT).unwrap();
+        let lines = lines(&content);
+
+        assert_eq!(lines.len(), SCRIPT.split('\n').count());
+        // Lifted lines are blanked rather than removed
+        for index in statements.keys() {
+            assert_eq!(lines[index - 1], "", "line {}", index);
+        }
+        assert_eq!(lines[5], "    e \"Hello\"");
+        assert_eq!(lines[17], "    return");
+
+        let (ast, errors) = parse_scenario_from_string(&content, "script.rpy").unwrap();
+        assert!(errors.is_empty(), "{:?}", errors);
+        let AST::Label(3, _, label_ast, _) = &ast[0] else {
+            panic!("no label at line 3");
+        };
+        assert!(matches!(&label_ast[0], AST::Say(6, Some(who), _) if who == "e"));
+        assert!(matches!(label_ast.last(), Some(AST::Return(18, _))));
+    }
+
+    #[test]
+    fn rejects_shallower_sibling() {
+        // The choice is flattened by the indentation of its first line, 12 spaces, which the
+        // second line doesn't have
+        let content = "menu:\n  \"Left\":\n            \"a\"\n      \"x\"\n";
+
+        assert!(matches!(
+            preprocess(content),
+            Err(ScriptError::Indentation { line: 4 })
+        ));
+
+        let content = "menu:\n  \"Left\":\n      \"a\"\n    \"x\" \"a long line\"\n";
+
+        assert!(matches!(
+            preprocess(content),
+            Err(ScriptError::Indentation { line: 4 })
+        ));
+    }
+
+    const AUDIO: &str = r#"label start:
+    play music "theme.ogg" fadein 1.5 fadeout 0.5
+    play sound "door.ogg" loop
+    play music 'calm.ogg' noloop
+    play ambience "rain.ogg"
+    stop music fadeout 2
+    stop sound
+    play music "theme.ogg" fadein soon
+    play music "theme.ogg" crossfade 1
+    stop music fadeout
+"#;
+
+    fn play(statements: &Statements, index: usize) -> &AudioPlay {
+        match statements.get(&index) {
+            Some(Statement::Play(play)) => play,
+            statement => panic!("no play at line {}: {:?}", index, statement),
+        }
+    }
+
+    #[test]
+    fn lifts_play_clauses() {
+        let (_, statements) = preprocess(AUDIO).unwrap();
+
+        let theme = play(&statements, 2);
+        assert_eq!(theme.channel, "music");
+        assert_eq!(theme.filename, "theme.ogg");
+        assert_eq!(theme.fade_in, Some(1.5));
+        assert_eq!(theme.fade_out, Some(0.5));
+        assert_eq!(theme.looped, None);
+
+        let door = play(&statements, 3);
+        assert_eq!(door.channel, "sound");
+        assert_eq!(door.looped, Some(true));
+        assert_eq!(door.fade_in, None);
+
+        let calm = play(&statements, 4);
+        assert_eq!(calm.filename, "calm.ogg");
+        assert_eq!(calm.looped, Some(false));
+
+        assert_eq!(play(&statements, 5).channel, "ambience");
+    }
+
+    #[test]
+    fn lifts_stop_fadeout() {
+        let (_, statements) = preprocess(AUDIO).unwrap();
+
+        assert!(matches!(
+            statements.get(&6),
+            Some(Statement::Stop(channel, Some(2.0))) if channel == "music"
+        ));
+        assert!(matches!(
+            statements.get(&7),
+            Some(Statement::Stop(channel, None)) if channel == "sound"
+        ));
+    }
+
+    #[test]
+    fn leaves_bad_audio_clauses() {
+        let (content, statements) = preprocess(AUDIO).unwrap();
+        let lines = lines(&content);
+
+        // renpy_parser gets these lines as they were written
+        for index in 8..=10 {
+            assert!(!statements.contains_key(&index), "line {}", index);
+            assert_eq!(lines[index - 1], AUDIO.split('\n').nth(index - 1).unwrap());
+        }
+        assert_eq!(lines[1], "");
+    }
+}
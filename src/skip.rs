@@ -0,0 +1,270 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use bevy::prelude::*;
+use renpy_parser::parsers::AST;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    NovelData, NovelSettings,
+    messages::{EventHandleNode, EventSwitchNextNode},
+    save::{DelayedWrite, NovelSaveError, fnv1a, write_atomic},
+};
+
+/// "Skip" label shown while skipping
+#[derive(Component)]
+pub struct NovelSkipIndicator;
+
+/// Skip mode, fast-forwarding through lines the player has read before
+#[derive(Resource, Default)]
+pub struct NovelSkip {
+    /// Toggled skip mode
+    pub active: bool,
+    /// Skipping while the skip key is held
+    pub held: bool,
+    /// The current line had been read before it was shown this time
+    pub current_line_read: bool,
+}
+
+impl NovelSkip {
+    pub fn is_skipping(&self) -> bool {
+        self.active || self.held
+    }
+}
+
+/// Say lines the player has seen in any session, keyed by [`NovelReadLines::line_key`]
+#[derive(Resource, Default, Clone, Debug, Serialize, Deserialize)]
+pub struct NovelReadLines(HashSet<u64>);
+
+impl NovelReadLines {
+    /// Hash of the label a line is in, its offset from the label, its speaker and its text.
+    /// A line such as `"..."` written in several places is read separately in each. Edits
+    /// above a line in its label make it unread again, edits elsewhere don't.
+    pub fn line_key(label: Option<&str>, offset: usize, who: Option<&str>, what: &str) -> u64 {
+        let bytes: Vec<u8> = label
+            .unwrap_or_default()
+            .bytes()
+            .chain([0])
+            .chain((offset as u64).to_le_bytes())
+            .chain(who.unwrap_or_default().bytes())
+            .chain([0])
+            .chain(what.bytes())
+            .collect();
+        fnv1a(&bytes)
+    }
+
+    pub fn contains(&self, key: u64) -> bool {
+        self.0.contains(&key)
+    }
+
+    /// Marks a line read, returns whether it had been read before
+    pub fn insert(&mut self, key: u64) -> bool {
+        !self.0.insert(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), NovelSaveError> {
+        write_atomic(path, &ron::to_string(self)?)
+    }
+
+    pub fn read(path: &Path) -> Result<Self, NovelSaveError> {
+        let content = fs::read_to_string(path)?;
+        Ok(ron::from_str(&content)?)
+    }
+}
+
+pub fn read_lines_path(novel_settings: &NovelSettings) -> PathBuf {
+    novel_settings.save_directory.join("read_lines.ron")
+}
+
+pub fn load_read_lines(mut read_lines: ResMut<NovelReadLines>, novel_settings: Res<NovelSettings>) {
+    let path = read_lines_path(&novel_settings);
+    if !path.exists() {
+        return;
+    }
+
+    match NovelReadLines::read(&path) {
+        Ok(loaded) => *read_lines = loaded,
+        Err(err) => error!("Loading read lines: {}", err),
+    }
+}
+
+/// Writes the read lines a moment after new ones were marked read, and right away on exit
+pub fn write_read_lines(
+    mut written: Local<Option<usize>>,
+    mut write: Local<DelayedWrite>,
+    mut er_app_exit: MessageReader<AppExit>,
+    read_lines: Res<NovelReadLines>,
+    novel_settings: Res<NovelSettings>,
+    time: Res<Time>,
+) {
+    // Lines only ever get added, the count tells whether there is anything new
+    let written = written.get_or_insert(read_lines.len());
+    if read_lines.len() != *written {
+        write.schedule();
+    }
+
+    if !write.due(&time, &mut er_app_exit) {
+        return;
+    }
+
+    *written = read_lines.len();
+
+    if let Err(err) = read_lines.write(&read_lines_path(&novel_settings)) {
+        error!("Writing read lines: {}", err);
+    }
+}
+
+pub fn record_read_lines(
+    mut er_handle_node: MessageReader<EventHandleNode>,
+    novel_data: Res<NovelData>,
+    mut read_lines: ResMut<NovelReadLines>,
+    mut skip: ResMut<NovelSkip>,
+) {
+    for event in er_handle_node.read() {
+        if let AST::Say(index, who, what) = &event.ast {
+            let (label, offset) = novel_data.script_position(*index);
+            let key = NovelReadLines::line_key(label, offset, who.as_deref(), what);
+            skip.current_line_read = read_lines.insert(key);
+        }
+    }
+}
+
+/// Advances every frame while skipping, stopping at menus, game mechanics and, unless
+/// [`NovelSettings::skip_unread`] is set, at lines that haven't been read before. Waits while
+/// [`NovelSettings::pause_handle_switch_node`] is set.
+pub fn skip_lines(
+    mut skip: ResMut<NovelSkip>,
+    novel_data: Res<NovelData>,
+    novel_settings: Res<NovelSettings>,
+    mut ew_switch_next_node: MessageWriter<EventSwitchNextNode>,
+) {
+    if !skip.is_skipping() || novel_settings.pause_handle_switch_node {
+        return;
+    }
+
+    if novel_data.menu.is_some()
+        || novel_data.mechanic.is_some()
+        || !(skip.current_line_read || novel_settings.skip_unread)
+    {
+        skip.active = false;
+        return;
+    }
+
+    ew_switch_next_node.write(EventSwitchNextNode {});
+}
+
+pub fn update_skip_indicator(
+    skip: Res<NovelSkip>,
+    mut indicators: Query<&mut Visibility, With<NovelSkipIndicator>>,
+) {
+    if !skip.is_changed() {
+        return;
+    }
+
+    for mut visibility in indicators.iter_mut() {
+        *visibility = if skip.is_skipping() {
+            Visibility::Visible
+        } else {
+            Visibility::Hidden
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{generate::GeneratedNode, rpy_asset_loader::parse_script, save::test_directory};
+
+    const SCRIPT: &str = r#"label start:
+    "..."
+    llm_generate e "a greeting"
+    e "Yes."
+    jump other
+
+label other:
+    "..."
+    e "Yes."
+"#;
+
+    fn key(label: &str, offset: usize, who: Option<&str>, what: &str) -> u64 {
+        NovelReadLines::line_key(Some(label), offset, who, what)
+    }
+
+    #[test]
+    fn line_keys() {
+        let hello = key("start", 1, Some("e"), "Hello");
+        assert_eq!(hello, key("start", 1, Some("e"), "Hello"));
+        assert_ne!(hello, key("start", 1, Some("m"), "Hello"));
+        assert_ne!(hello, key("start", 1, Some("e"), "Hello!"));
+        assert_ne!(hello, key("start", 2, Some("e"), "Hello"));
+        assert_ne!(hello, key("other", 1, Some("e"), "Hello"));
+        // The label, the speaker and the text don't run into each other
+        assert_ne!(key("ab", 1, None, "c"), key("a", 1, Some("b"), "c"));
+        assert_ne!(key("s", 1, Some("ab"), "c"), key("s", 1, Some("a"), "bc"));
+        // Read lines are kept across sessions, the keys must not change
+        assert_eq!(
+            NovelReadLines::line_key(None, 0, None, ""),
+            fnv1a(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0])
+        );
+    }
+
+    #[test]
+    fn keys_lines_by_position() {
+        let (ast, statements) = parse_script(SCRIPT, Path::new("script.rpy")).unwrap();
+        let mut novel_data = NovelData::default();
+        novel_data.set_scenario(ast, statements);
+
+        assert_eq!(novel_data.script_position(2), (Some("start"), 1));
+        assert_eq!(novel_data.script_position(8), (Some("other"), 1));
+        assert_eq!(novel_data.script_position(4), (Some("start"), 3));
+
+        // A generated line doesn't move the lines after it in the script
+        novel_data.push_text_node(Some("e".to_string()), "Hi".to_string(), 4);
+        novel_data.generated.insert(
+            4,
+            GeneratedNode::Line {
+                character: "e".to_string(),
+                text: "Hi".to_string(),
+            },
+        );
+        assert_eq!(novel_data.script_position(5), (Some("start"), 3));
+        assert_eq!(novel_data.script_position(9), (Some("other"), 1));
+    }
+
+    #[test]
+    fn marks_lines_read() {
+        let mut read_lines = NovelReadLines::default();
+        let hello = key("start", 1, Some("e"), "Hello");
+        let bye = key("start", 2, Some("e"), "Bye");
+
+        assert!(!read_lines.insert(hello));
+        assert!(read_lines.insert(hello));
+        assert!(read_lines.contains(hello));
+        assert!(!read_lines.contains(bye));
+        assert_eq!(read_lines.len(), 1);
+    }
+
+    #[test]
+    fn writes_read_lines() {
+        let directory = test_directory("writes_read_lines");
+        let path = directory.join("read_lines.ron");
+
+        let mut read_lines = NovelReadLines::default();
+        read_lines.insert(key("start", 1, Some("e"), "Hello"));
+        read_lines.insert(NovelReadLines::line_key(None, 0, None, "Narration"));
+        read_lines.write(&path).unwrap();
+
+        let loaded = NovelReadLines::read(&path).unwrap();
+        assert_eq!(loaded.0, read_lines.0);
+        assert!(NovelReadLines::read(&directory.join("missing.ron")).is_err());
+    }
+}
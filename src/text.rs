@@ -0,0 +1,571 @@
+use bevy::prelude::*;
+
+use crate::{
+    NovelSettings, NovelText, NovelTextWhat,
+    messages::{EventSwitchNextNode, EventTextRevealed},
+};
+
+/// Font size of dialogue without a `{size}` tag
+pub const DEFAULT_FONT_SIZE: f32 = 20.0;
+
+/// Style set by the text tags around a run of text
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TagStyle {
+    /// `{b}`, drawn with a bold weight of the font
+    pub bold: bool,
+    /// `{i}`, drawn with the italic style of the font
+    pub italic: bool,
+    /// `{color=#rrggbb}`, the colour of the speaker is used when `None`
+    pub color: Option<Color>,
+    /// `{size=30}`, `{size=+10}`, `{size=-10}` or `{size=*1.5}`
+    pub size: Option<f32>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TextRun {
+    pub text: String,
+    pub style: TagStyle,
+}
+
+/// Point of the line where the reveal stops
+#[derive(Clone, Debug, PartialEq)]
+pub struct TextWait {
+    /// Number of characters shown before the wait
+    pub position: usize,
+    /// `{w=1.5}` waits that many seconds, `{w}` and `{p}` wait for the player
+    pub seconds: Option<f32>,
+}
+
+/// Dialogue line with its Ren'Py text tags parsed, see [`parse_text_tags`]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TaggedText {
+    pub runs: Vec<TextRun>,
+    pub waits: Vec<TextWait>,
+    /// `{nw}`: the story moves on as soon as the line is shown
+    pub no_wait: bool,
+}
+
+impl TaggedText {
+    /// The line without any tags
+    pub fn plain_text(&self) -> String {
+        self.runs.iter().map(|run| run.text.as_str()).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.runs.iter().map(|run| run.text.chars().count()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Parses the text tags bevy_novel supports: `{b}`, `{i}`, `{color}`, `{size}` and their
+/// closing tags, along with the `{w}`, `{p}` and `{nw}` timing tags. `{{` is a literal brace,
+/// and so is a `{` that is never closed. Other tags are dropped with a warning.
+pub fn parse_text_tags(text: &str) -> TaggedText {
+    let mut tagged = TaggedText::default();
+    let mut styles: Vec<(String, TagStyle)> = Vec::new();
+    let mut current = String::new();
+    let mut position = 0;
+
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '{' {
+            current.push(c);
+            position += 1;
+            continue;
+        }
+
+        if chars.peek() == Some(&'{') {
+            chars.next();
+            current.push('{');
+            position += 1;
+            continue;
+        }
+
+        // A brace that is never closed is kept as text rather than eating the line
+        if !chars.clone().any(|c| c == '}') {
+            current.push('{');
+            position += 1;
+            continue;
+        }
+
+        let tag: String = chars.by_ref().take_while(|c| *c != '}').collect();
+        let (name, value) = match tag.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(value.trim())),
+            None => (tag.trim(), None),
+        };
+
+        let style = styles
+            .last()
+            .map(|(_, style)| style.clone())
+            .unwrap_or_default();
+
+        // Every tag ends the run of text before it
+        if !current.is_empty() {
+            tagged.runs.push(TextRun {
+                text: std::mem::take(&mut current),
+                style: style.clone(),
+            });
+        }
+
+        match name {
+            "b" => styles.push((
+                name.to_string(),
+                TagStyle {
+                    bold: true,
+                    ..style
+                },
+            )),
+            "i" => styles.push((
+                name.to_string(),
+                TagStyle {
+                    italic: true,
+                    ..style
+                },
+            )),
+            "color" => {
+                let color = value.and_then(|value| match Srgba::hex(value) {
+                    Ok(color) => Some(Color::from(color)),
+                    Err(err) => {
+                        warn!("Invalid colour {}: {}", value, err);
+                        None
+                    }
+                });
+                styles.push((
+                    name.to_string(),
+                    TagStyle {
+                        color: color.or(style.color),
+                        ..style
+                    },
+                ));
+            }
+            "size" => {
+                let size = style.size.unwrap_or(DEFAULT_FONT_SIZE);
+                let size = value.and_then(|value| parse_size(value, size));
+                styles.push((
+                    name.to_string(),
+                    TagStyle {
+                        size: size.or(style.size),
+                        ..style
+                    },
+                ));
+            }
+            "w" | "p" => {
+                tagged.waits.push(TextWait {
+                    position,
+                    seconds: value.and_then(|value| value.parse().ok()),
+                });
+                if name == "p" {
+                    current.push('\n');
+                    position += 1;
+                }
+            }
+            "nw" => tagged.no_wait = true,
+            _ => match name.strip_prefix('/') {
+                Some(closed) => {
+                    if let Some(index) = styles.iter().rposition(|(name, _)| name == closed) {
+                        styles.truncate(index);
+                    }
+                }
+                None => warn!("Unsupported text tag `{{{}}}`", tag),
+            },
+        }
+    }
+
+    if !current.is_empty() {
+        tagged.runs.push(TextRun {
+            text: current,
+            style: styles
+                .last()
+                .map(|(_, style)| style.clone())
+                .unwrap_or_default(),
+        });
+    }
+
+    tagged
+}
+
+fn parse_size(value: &str, size: f32) -> Option<f32> {
+    let parsed = if let Some(value) = value.strip_prefix('+') {
+        value.parse::<f32>().map(|value| size + value)
+    } else if let Some(value) = value.strip_prefix('-') {
+        value.parse::<f32>().map(|value| size - value)
+    } else if let Some(value) = value.strip_prefix('*') {
+        value.parse::<f32>().map(|value| size * value)
+    } else {
+        value.parse::<f32>()
+    };
+
+    parsed.ok()
+}
+
+enum Pause {
+    Click,
+    Timer(f32),
+}
+
+/// Line of dialogue being typed out by [`reveal_text`]
+#[derive(Resource, Default)]
+pub struct NovelTextReveal {
+    text: TaggedText,
+    color: Option<Color>,
+    /// Characters shown so far, fractional between frames
+    shown: f32,
+    /// Index of the next wait of `text` to stop at
+    next_wait: usize,
+    pause: Option<Pause>,
+    running: bool,
+    /// Spans of the line need to be spawned
+    respawn: bool,
+    spans: Vec<Entity>,
+}
+
+impl NovelTextReveal {
+    /// Starts typing out a dialogue line, `color` is used for text without a `{color}` tag
+    pub fn start(&mut self, text: &str, color: Option<Color>) {
+        self.text = parse_text_tags(text);
+        self.color = color;
+        self.shown = 0.0;
+        self.next_wait = 0;
+        self.pause = None;
+        self.running = true;
+        self.respawn = true;
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Drops the line without finishing it
+    pub fn stop(&mut self) {
+        self.running = false;
+        self.pause = None;
+    }
+
+    /// Answers a press of the player: resumes a `{w}` wait, or shows the rest of the line up
+    /// to the next wait
+    pub fn advance(&mut self) {
+        match self.pause.take() {
+            Some(Pause::Click) => {}
+            _ => self.shown = f32::INFINITY,
+        }
+    }
+
+    /// Stopped at a `{w}` or `{p}` until [`NovelTextReveal::advance`]
+    pub fn is_waiting_for_click(&self) -> bool {
+        matches!(self.pause, Some(Pause::Click))
+    }
+
+    /// Number of waits of the line reached so far, telling one stop of the line from the next
+    pub fn waits_reached(&self) -> usize {
+        self.next_wait
+    }
+
+    /// Characters shown since the last `{w}` or `{p}` the player went on from, the ones read
+    /// at the current stop
+    pub fn read_len(&self) -> usize {
+        let passed = if self.is_waiting_for_click() {
+            self.next_wait.checked_sub(2)
+        } else {
+            self.next_wait.checked_sub(1)
+        };
+        let start = passed
+            .and_then(|index| self.text.waits.get(index))
+            .map_or(0, |wait| wait.position);
+
+        (self.shown.min(self.text.len() as f32) as usize).saturating_sub(start)
+    }
+
+    /// Line being shown, with its tags parsed
+    pub fn text(&self) -> &TaggedText {
+        &self.text
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn reveal_text(
+    mut commands: Commands,
+    mut reveal: ResMut<NovelTextReveal>,
+    novel_settings: Res<NovelSettings>,
+    time: Res<Time>,
+    novel_texts: Query<Entity, With<NovelText>>,
+    what_spans: Query<Entity, With<NovelTextWhat>>,
+    mut spans: Query<&mut TextSpan>,
+    mut ew_text_revealed: MessageWriter<EventTextRevealed>,
+    mut ew_switch_next_node: MessageWriter<EventSwitchNextNode>,
+) {
+    if !reveal.running {
+        return;
+    }
+
+    if reveal.respawn {
+        reveal.respawn = false;
+
+        for entity in what_spans.iter() {
+            commands.entity(entity).despawn();
+        }
+
+        let Ok(novel_text) = novel_texts.single() else {
+            return;
+        };
+
+        let color = reveal.color;
+        reveal.spans = reveal
+            .text
+            .runs
+            .iter()
+            .map(|run| {
+                let span = commands
+                    .spawn((
+                        TextSpan::new(""),
+                        text_font(&run.style),
+                        run.style.color.or(color).map(TextColor).unwrap_or_default(),
+                        NovelTextWhat {},
+                        Name::new("Novel Text What"),
+                    ))
+                    .id();
+                commands.entity(novel_text).add_child(span);
+                span
+            })
+            .collect();
+
+        // The spans are filled in once they exist
+        return;
+    }
+
+    match reveal.pause {
+        Some(Pause::Click) => return,
+        Some(Pause::Timer(seconds)) => {
+            let seconds = seconds - time.delta_secs();
+            reveal.pause = (seconds > 0.0).then_some(Pause::Timer(seconds));
+            return;
+        }
+        None if novel_settings.text_speed > 0.0 => {
+            reveal.shown += novel_settings.text_speed * time.delta_secs();
+        }
+        None => reveal.shown = f32::INFINITY,
+    }
+
+    let length = reveal.text.len();
+    reveal.shown = reveal.shown.min(length as f32);
+
+    if let Some(wait) = reveal.text.waits.get(reveal.next_wait).cloned()
+        && reveal.shown >= wait.position as f32
+    {
+        reveal.shown = wait.position as f32;
+        reveal.next_wait += 1;
+        reveal.pause = Some(match wait.seconds {
+            Some(seconds) => Pause::Timer(seconds),
+            None => Pause::Click,
+        });
+    }
+
+    let mut start = 0;
+    for (run, entity) in reveal.text.runs.iter().zip(reveal.spans.iter()) {
+        let run_length = run.text.chars().count();
+        let shown = (reveal.shown as usize)
+            .saturating_sub(start)
+            .min(run_length);
+        start += run_length;
+
+        if let Ok(mut span) = spans.get_mut(*entity) {
+            *span = TextSpan::new(run.text.chars().take(shown).collect::<String>());
+        }
+    }
+
+    if reveal.shown as usize == length
+        && reveal.pause.is_none()
+        && reveal.next_wait >= reveal.text.waits.len()
+    {
+        reveal.running = false;
+        ew_text_revealed.write(EventTextRevealed {});
+
+        if reveal.text.no_wait {
+            ew_switch_next_node.write(EventSwitchNextNode {});
+        }
+    }
+}
+
+fn text_font(style: &TagStyle) -> TextFont {
+    TextFont {
+        font_size: FontSize::Px(style.size.unwrap_or(DEFAULT_FONT_SIZE)),
+        weight: if style.bold {
+            FontWeight::BOLD
+        } else {
+            FontWeight::NORMAL
+        },
+        style: if style.italic {
+            FontStyle::Italic
+        } else {
+            FontStyle::Normal
+        },
+        ..default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(text: &str, style: TagStyle) -> TextRun {
+        TextRun {
+            text: text.to_string(),
+            style,
+        }
+    }
+
+    #[test]
+    fn escaped_braces() {
+        let tagged = parse_text_tags("Use {{b} for bold");
+
+        assert_eq!(tagged.runs, vec![run("Use {b} for bold", default())]);
+        assert_eq!(tagged.len(), 16);
+    }
+
+    #[test]
+    fn unclosed_brace() {
+        let tagged = parse_text_tags("Hello {b world");
+
+        assert_eq!(tagged.plain_text(), "Hello {b world");
+        assert!(
+            tagged
+                .runs
+                .iter()
+                .all(|run| run.style == TagStyle::default())
+        );
+    }
+
+    #[test]
+    fn nested_tags() {
+        let tagged = parse_text_tags("{b}{i}both{/i} bold{/b} plain");
+        let bold = TagStyle {
+            bold: true,
+            ..default()
+        };
+
+        assert_eq!(
+            tagged.runs,
+            vec![
+                run(
+                    "both",
+                    TagStyle {
+                        italic: true,
+                        ..bold.clone()
+                    }
+                ),
+                run(" bold", bold),
+                run(" plain", default()),
+            ]
+        );
+    }
+
+    #[test]
+    fn stray_closing_tag() {
+        let tagged = parse_text_tags("{b}one{/color} two{/b}");
+        let bold = TagStyle {
+            bold: true,
+            ..default()
+        };
+
+        assert_eq!(
+            tagged.runs,
+            vec![run("one", bold.clone()), run(" two", bold)]
+        );
+
+        let tagged = parse_text_tags("one{/color}two");
+        assert_eq!(
+            tagged.runs,
+            vec![run("one", default()), run("two", default())]
+        );
+    }
+
+    #[test]
+    fn colors() {
+        let red = Color::from(Srgba::hex("#ff0000").unwrap());
+        let blue = Color::from(Srgba::hex("#0000ff").unwrap());
+        let tagged = parse_text_tags("{color=#ff0000}red {color=#0000ff}blue{/color} red{/color}");
+
+        assert_eq!(
+            tagged.runs,
+            vec![
+                run(
+                    "red ",
+                    TagStyle {
+                        color: Some(red),
+                        ..default()
+                    }
+                ),
+                run(
+                    "blue",
+                    TagStyle {
+                        color: Some(blue),
+                        ..default()
+                    }
+                ),
+                run(
+                    " red",
+                    TagStyle {
+                        color: Some(red),
+                        ..default()
+                    }
+                ),
+            ]
+        );
+
+        // A bad colour keeps the one around it
+        let tagged = parse_text_tags("{color=#ff0000}{color=nope}still red{/color}");
+        assert_eq!(tagged.runs[0].style.color, Some(red));
+        assert_eq!(parse_text_tags("{color}plain").runs[0].style.color, None);
+    }
+
+    #[test]
+    fn sizes() {
+        let size = |text: &str| parse_text_tags(text).runs[0].style.size;
+
+        assert_eq!(size("{size=30}big"), Some(30.0));
+        assert_eq!(size("{size=+10}big"), Some(DEFAULT_FONT_SIZE + 10.0));
+        assert_eq!(size("{size=-5}small"), Some(DEFAULT_FONT_SIZE - 5.0));
+        assert_eq!(size("{size=*1.5}big"), Some(DEFAULT_FONT_SIZE * 1.5));
+        assert_eq!(size("{size=huge}plain"), None);
+        assert_eq!(size("{size=+}plain"), None);
+
+        // Relative sizes build on the size around them
+        let tagged = parse_text_tags("{size=30}big {size=*2}bigger{/size} big{/size} plain");
+        let sizes: Vec<Option<f32>> = tagged.runs.iter().map(|run| run.style.size).collect();
+        assert_eq!(sizes, vec![Some(30.0), Some(60.0), Some(30.0), None]);
+
+        let tagged = parse_text_tags("{size=30}{size=bad}still big{/size}");
+        assert_eq!(tagged.runs[0].style.size, Some(30.0));
+    }
+
+    #[test]
+    fn wait_at_end_of_line() {
+        let tagged = parse_text_tags("Wait for it{w}");
+
+        assert_eq!(tagged.plain_text(), "Wait for it");
+        assert_eq!(
+            tagged.waits,
+            vec![TextWait {
+                position: 11,
+                seconds: None
+            }]
+        );
+
+        let tagged = parse_text_tags("Wait{w=1.5} more{p}");
+        assert_eq!(tagged.plain_text(), "Wait more\n");
+        assert_eq!(tagged.waits[0].seconds, Some(1.5));
+        assert_eq!(tagged.waits[1].position, 9);
+    }
+
+    #[test]
+    fn no_wait() {
+        let tagged = parse_text_tags("Going on{nw}");
+
+        assert!(tagged.no_wait);
+        assert_eq!(tagged.plain_text(), "Going on");
+        assert!(tagged.waits.is_empty());
+        assert!(!parse_text_tags("Stopping").no_wait);
+    }
+}
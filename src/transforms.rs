@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// Placement of a shown image, following Ren'Py's position properties.
+///
+/// Positions and anchors are fractions: `xpos` 0.0 is the left edge of the screen and 1.0 the
+/// right one, `ypos` 0.0 is the top and 1.0 the bottom. The anchor is the point of the image,
+/// as a fraction of its size, that is put at the position.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NovelTransform {
+    pub xpos: f32,
+    pub ypos: f32,
+    pub xanchor: f32,
+    pub yanchor: f32,
+    /// Scale applied on top of the default character height
+    pub zoom: f32,
+}
+
+impl NovelTransform {
+    /// Same position and anchor on both axes, like Ren'Py's `xalign` and `yalign`
+    pub fn align(xalign: f32, yalign: f32) -> Self {
+        NovelTransform {
+            xpos: xalign,
+            ypos: yalign,
+            xanchor: xalign,
+            yanchor: yalign,
+            zoom: 1.0,
+        }
+    }
+
+    pub fn with_zoom(mut self, zoom: f32) -> Self {
+        self.zoom = zoom;
+        self
+    }
+
+    /// Translation of the centre of an image of `size` on a `window` with the camera at its
+    /// centre
+    pub fn translation(&self, size: Vec2, window: Vec2) -> Vec2 {
+        let top_left = Vec2::new(
+            self.xpos * window.x - self.xanchor * size.x,
+            self.ypos * window.y - self.yanchor * size.y,
+        );
+        let center = top_left + size / 2.0;
+
+        Vec2::new(center.x - window.x / 2.0, window.y / 2.0 - center.y)
+    }
+}
+
+impl Default for NovelTransform {
+    /// Ren'Py's `center`: horizontally centred, standing on the bottom of the screen
+    fn default() -> Self {
+        NovelTransform::align(0.5, 1.0)
+    }
+}
+
+/// Transforms that can be named in `show ... at <name>`.
+///
+/// Comes with Ren'Py's `left`, `center`, `right`, `truecenter`, `offscreenleft` and
+/// `offscreenright` (also `offscreen`), more can be added with [`NovelTransforms::register`].
+#[derive(Resource, Clone, Debug)]
+pub struct NovelTransforms(HashMap<String, NovelTransform>);
+
+impl NovelTransforms {
+    pub fn register(&mut self, name: impl Into<String>, transform: NovelTransform) {
+        self.0.insert(name.into(), transform);
+    }
+
+    pub fn get(&self, name: &str) -> Option<NovelTransform> {
+        self.0.get(name).copied()
+    }
+}
+
+impl Default for NovelTransforms {
+    fn default() -> Self {
+        let offscreen_right = NovelTransform {
+            xpos: 1.0,
+            xanchor: 0.0,
+            ..NovelTransform::align(0.0, 1.0)
+        };
+
+        let transforms = [
+            ("left", NovelTransform::align(0.0, 1.0)),
+            ("center", NovelTransform::align(0.5, 1.0)),
+            ("right", NovelTransform::align(1.0, 1.0)),
+            ("truecenter", NovelTransform::align(0.5, 0.5)),
+            (
+                "offscreenleft",
+                NovelTransform {
+                    xpos: 0.0,
+                    xanchor: 1.0,
+                    ..NovelTransform::align(0.0, 1.0)
+                },
+            ),
+            ("offscreenright", offscreen_right),
+            ("offscreen", offscreen_right),
+        ];
+
+        NovelTransforms(
+            transforms
+                .into_iter()
+                .map(|(name, transform)| (name.to_string(), transform))
+                .collect(),
+        )
+    }
+}
+
+/// Splits the image name of a `show` statement from its `at` clause, as `renpy_parser` keeps
+/// both in the image name: `eileen happy at left` gives `("eileen happy", Some("left"))`.
+pub fn split_at_clause(image: &str) -> (String, Option<String>) {
+    let words: Vec<&str> = image.split_whitespace().collect();
+
+    match words.iter().position(|word| *word == "at") {
+        Some(position) => (
+            words[..position].join(" "),
+            words.get(position + 1).map(|name| name.to_string()),
+        ),
+        None => (words.join(" "), None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transitions::split_with_clause;
+
+    #[test]
+    fn at_clause() {
+        assert_eq!(
+            split_at_clause("eileen happy at left"),
+            ("eileen happy".to_string(), Some("left".to_string()))
+        );
+        assert_eq!(
+            split_at_clause("eileen happy"),
+            ("eileen happy".to_string(), None)
+        );
+        assert_eq!(split_at_clause("eileen at"), ("eileen".to_string(), None));
+    }
+
+    #[test]
+    fn at_clause_with_transition() {
+        // `show` splits the `with` clause off first
+        let (image, with) = split_with_clause("eileen happy at left with dissolve");
+        assert_eq!(with.as_deref(), Some("dissolve"));
+        assert_eq!(
+            split_at_clause(&image),
+            ("eileen happy".to_string(), Some("left".to_string()))
+        );
+    }
+
+    #[test]
+    fn named_transforms() {
+        let mut transforms = NovelTransforms::default();
+        assert_eq!(
+            transforms.get("left"),
+            Some(NovelTransform::align(0.0, 1.0))
+        );
+        assert_eq!(transforms.get("center"), Some(NovelTransform::default()));
+
+        let (_, at) = split_at_clause("eileen at nowhere");
+        assert_eq!(transforms.get(at.as_deref().unwrap()), None);
+
+        let nowhere = NovelTransform::align(0.25, 0.5).with_zoom(2.0);
+        transforms.register("nowhere", nowhere);
+        assert_eq!(transforms.get("nowhere"), Some(nowhere));
+    }
+}
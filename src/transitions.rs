@@ -0,0 +1,715 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use bevy::prelude::*;
+
+use crate::messages::EventSwitchNextNode;
+
+/// A timed effect played between the old and new images of a `scene`, `show` or `hide`
+/// written with `with <name>`.
+///
+/// Transitions don't touch the images themselves, they describe how both should look at any
+/// point of their progress and bevy_novel applies that to the sprites.
+pub trait NovelTransition: Send + Sync + 'static {
+    /// Length of the transition in seconds
+    fn duration(&self) -> f32;
+
+    /// Looks of the old and new images at `progress`, going from 0.0 to 1.0
+    fn frame(&self, progress: f32) -> TransitionFrame;
+}
+
+/// How the images on one side of a transition look at some point of it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LayerFrame {
+    pub alpha: f32,
+    /// Offset as a fraction of the window size, positive `x` to the right and `y` up
+    pub offset: Vec2,
+    /// Part of the image that is drawn, as fractions of its size from the top-left corner
+    pub visible: Rect,
+    /// Side of the blocks the image is pixellated into, 1 leaves it untouched
+    pub pixel_size: u32,
+}
+
+impl Default for LayerFrame {
+    fn default() -> Self {
+        LayerFrame {
+            alpha: 1.0,
+            offset: Vec2::ZERO,
+            visible: Rect::new(0.0, 0.0, 1.0, 1.0),
+            pixel_size: 1,
+        }
+    }
+}
+
+impl LayerFrame {
+    pub fn with_alpha(alpha: f32) -> Self {
+        LayerFrame { alpha, ..default() }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TransitionFrame {
+    pub old: LayerFrame,
+    pub new: LayerFrame,
+    /// Colour drawn over the whole screen, `Color::NONE` for none
+    pub overlay: Color,
+}
+
+impl Default for TransitionFrame {
+    fn default() -> Self {
+        TransitionFrame {
+            old: LayerFrame::default(),
+            new: LayerFrame::default(),
+            overlay: Color::NONE,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransitionDirection {
+    Left,
+    Right,
+    Up,
+    Down,
+}
+
+/// Crossfades from the old images to the new ones
+pub struct Dissolve {
+    pub duration: f32,
+}
+
+impl NovelTransition for Dissolve {
+    fn duration(&self) -> f32 {
+        self.duration
+    }
+
+    fn frame(&self, progress: f32) -> TransitionFrame {
+        TransitionFrame {
+            old: LayerFrame::with_alpha(1.0 - progress),
+            new: LayerFrame::with_alpha(progress),
+            ..default()
+        }
+    }
+}
+
+/// Fades the old images out to `color`, holds it and fades the new images in
+pub struct Fade {
+    pub out_time: f32,
+    pub hold_time: f32,
+    pub in_time: f32,
+    pub color: Color,
+}
+
+impl NovelTransition for Fade {
+    fn duration(&self) -> f32 {
+        self.out_time + self.hold_time + self.in_time
+    }
+
+    fn frame(&self, progress: f32) -> TransitionFrame {
+        let time = progress * self.duration();
+
+        let (coverage, old_visible) = if time < self.out_time {
+            (time / self.out_time, true)
+        } else if time < self.out_time + self.hold_time {
+            (1.0, false)
+        } else if self.in_time > 0.0 {
+            (
+                1.0 - (time - self.out_time - self.hold_time) / self.in_time,
+                false,
+            )
+        } else {
+            (0.0, false)
+        };
+
+        TransitionFrame {
+            old: LayerFrame::with_alpha(if old_visible { 1.0 } else { 0.0 }),
+            new: LayerFrame::with_alpha(if old_visible { 0.0 } else { 1.0 }),
+            overlay: self.color.with_alpha(self.color.alpha() * coverage),
+        }
+    }
+}
+
+/// Pixellates the old images into blocks of up to `2^steps` pixels, then unpixellates the new
+/// ones
+pub struct Pixellate {
+    pub duration: f32,
+    pub steps: u32,
+}
+
+impl NovelTransition for Pixellate {
+    fn duration(&self) -> f32 {
+        self.duration
+    }
+
+    fn frame(&self, progress: f32) -> TransitionFrame {
+        let step = (self.steps as f32 * (1.0 - (2.0 * progress - 1.0).abs())).round() as u32;
+        // Blocks are capped at the image size when applied, `steps` may go past what a u32
+        // can shift
+        let pixel_size = 1u32.checked_shl(step).unwrap_or(u32::MAX);
+
+        let (old_alpha, new_alpha) = if progress < 0.5 {
+            (1.0, 0.0)
+        } else {
+            (0.0, 1.0)
+        };
+
+        TransitionFrame {
+            old: LayerFrame {
+                pixel_size,
+                ..LayerFrame::with_alpha(old_alpha)
+            },
+            new: LayerFrame {
+                pixel_size,
+                ..LayerFrame::with_alpha(new_alpha)
+            },
+            ..default()
+        }
+    }
+}
+
+/// Reveals the new images with an edge moving towards `direction`
+pub struct Wipe {
+    pub duration: f32,
+    pub direction: TransitionDirection,
+}
+
+impl NovelTransition for Wipe {
+    fn duration(&self) -> f32 {
+        self.duration
+    }
+
+    fn frame(&self, progress: f32) -> TransitionFrame {
+        let p = progress;
+        let (old, new) = match self.direction {
+            TransitionDirection::Left => (
+                Rect::new(0.0, 0.0, 1.0 - p, 1.0),
+                Rect::new(1.0 - p, 0.0, 1.0, 1.0),
+            ),
+            TransitionDirection::Right => {
+                (Rect::new(p, 0.0, 1.0, 1.0), Rect::new(0.0, 0.0, p, 1.0))
+            }
+            TransitionDirection::Up => (
+                Rect::new(0.0, 0.0, 1.0, 1.0 - p),
+                Rect::new(0.0, 1.0 - p, 1.0, 1.0),
+            ),
+            TransitionDirection::Down => (Rect::new(0.0, p, 1.0, 1.0), Rect::new(0.0, 0.0, 1.0, p)),
+        };
+
+        TransitionFrame {
+            old: LayerFrame {
+                visible: old,
+                ..default()
+            },
+            new: LayerFrame {
+                visible: new,
+                ..default()
+            },
+            ..default()
+        }
+    }
+}
+
+/// Slides the new images in, moving towards `direction`, over the old ones
+pub struct Slide {
+    pub duration: f32,
+    pub direction: TransitionDirection,
+}
+
+impl NovelTransition for Slide {
+    fn duration(&self) -> f32 {
+        self.duration
+    }
+
+    fn frame(&self, progress: f32) -> TransitionFrame {
+        let remaining = 1.0 - progress;
+        let offset = match self.direction {
+            TransitionDirection::Left => Vec2::new(remaining, 0.0),
+            TransitionDirection::Right => Vec2::new(-remaining, 0.0),
+            TransitionDirection::Up => Vec2::new(0.0, -remaining),
+            TransitionDirection::Down => Vec2::new(0.0, remaining),
+        };
+
+        TransitionFrame {
+            new: LayerFrame {
+                offset,
+                ..default()
+            },
+            ..default()
+        }
+    }
+}
+
+/// Transitions that can be named in `with <name>`.
+///
+/// Comes with `dissolve`, `fade`, `pixellate`, `wipeleft`, `wiperight`, `wipeup`, `wipedown`,
+/// `slideleft`, `slideright`, `slideup` and `slidedown`, more can be added with
+/// [`NovelTransitions::register`].
+#[derive(Resource, Clone)]
+pub struct NovelTransitions(HashMap<String, Arc<dyn NovelTransition>>);
+
+impl NovelTransitions {
+    pub fn register(&mut self, name: impl Into<String>, transition: impl NovelTransition) {
+        self.0.insert(name.into(), Arc::new(transition));
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<dyn NovelTransition>> {
+        self.0.get(name).cloned()
+    }
+}
+
+impl Default for NovelTransitions {
+    fn default() -> Self {
+        let mut transitions = NovelTransitions(HashMap::new());
+
+        transitions.register("dissolve", Dissolve { duration: 0.5 });
+        transitions.register(
+            "fade",
+            Fade {
+                out_time: 0.5,
+                hold_time: 0.0,
+                in_time: 0.5,
+                color: Color::BLACK,
+            },
+        );
+        transitions.register(
+            "pixellate",
+            Pixellate {
+                duration: 1.0,
+                steps: 5,
+            },
+        );
+
+        for (suffix, direction) in [
+            ("left", TransitionDirection::Left),
+            ("right", TransitionDirection::Right),
+            ("up", TransitionDirection::Up),
+            ("down", TransitionDirection::Down),
+        ] {
+            transitions.register(
+                format!("wipe{}", suffix),
+                Wipe {
+                    duration: 1.0,
+                    direction,
+                },
+            );
+            transitions.register(
+                format!("slide{}", suffix),
+                Slide {
+                    duration: 1.0,
+                    direction,
+                },
+            );
+        }
+
+        transitions
+    }
+}
+
+/// Transition being played, the story waits for it before moving on
+#[derive(Resource, Default)]
+pub struct NovelTransitionState {
+    transition: Option<Arc<dyn NovelTransition>>,
+    elapsed: f32,
+    /// Pixellated copies of the images, by image and block size
+    pixellated: HashMap<(AssetId<Image>, u32), Handle<Image>>,
+}
+
+impl NovelTransitionState {
+    pub fn start(&mut self, transition: Arc<dyn NovelTransition>) {
+        self.transition = Some(transition);
+        self.elapsed = 0.0;
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.transition.is_some()
+    }
+
+    /// Makes the transition finish on the next update
+    pub fn skip(&mut self) {
+        self.elapsed = f32::INFINITY;
+    }
+
+    /// Drops the transition without finishing it, leaving its entities to the caller
+    pub fn cancel(&mut self) {
+        self.transition = None;
+        self.pixellated.clear();
+    }
+}
+
+/// Image leaving the screen, despawned once the transition finishes
+#[derive(Component)]
+pub struct NovelTransitionOld {
+    pub translation: Vec3,
+    pub image: Handle<Image>,
+}
+
+/// Image entering the screen
+#[derive(Component)]
+pub struct NovelTransitionNew {
+    pub image: Handle<Image>,
+}
+
+#[derive(Component)]
+pub struct NovelTransitionOverlay;
+
+/// Splits the `with` clause off an image name, `renpy_parser` keeps it in the name:
+/// `bg room with fade` gives `("bg room", Some("fade"))`.
+pub fn split_with_clause(image: &str) -> (String, Option<String>) {
+    let words: Vec<&str> = image.split_whitespace().collect();
+
+    match words.iter().position(|word| *word == "with") {
+        Some(position) => (
+            words[..position].join(" "),
+            words.get(position + 1).map(|name| name.to_string()),
+        ),
+        None => (words.join(" "), None),
+    }
+}
+
+/// Looks up the transition named in a `with` clause, warning about unknown names
+pub fn resolve_transition(
+    transitions: &NovelTransitions,
+    name: Option<&str>,
+) -> Option<Arc<dyn NovelTransition>> {
+    let name = name?;
+    let transition = transitions.get(name);
+    if transition.is_none() {
+        warn!("Transition `{}` is not registered", name);
+    }
+    transition
+}
+
+#[allow(clippy::type_complexity)]
+#[allow(clippy::too_many_arguments)]
+pub fn animate_transition(
+    mut commands: Commands,
+    mut state: ResMut<NovelTransitionState>,
+    mut old_layers: Query<
+        (Entity, &mut Sprite, &mut Transform, &NovelTransitionOld),
+        Without<NovelTransitionNew>,
+    >,
+    mut new_layers: Query<
+        (Entity, &mut Sprite, &mut Transform, &NovelTransitionNew),
+        Without<NovelTransitionOld>,
+    >,
+    mut overlays: Query<(Entity, &mut BackgroundColor), With<NovelTransitionOverlay>>,
+    mut images: ResMut<Assets<Image>>,
+    windows: Query<&Window>,
+    time: Res<Time>,
+    mut ew_switch_next_node: MessageWriter<EventSwitchNextNode>,
+) {
+    let Some(transition) = state.transition.clone() else {
+        return;
+    };
+
+    state.elapsed += time.delta_secs();
+    let duration = transition.duration();
+
+    if state.elapsed >= duration {
+        for (entity, _, _, _) in old_layers.iter() {
+            commands.entity(entity).despawn();
+        }
+
+        for (entity, mut sprite, _, layer) in new_layers.iter_mut() {
+            sprite.image = layer.image.clone();
+            sprite.rect = None;
+            sprite.color.set_alpha(1.0);
+            commands.entity(entity).remove::<NovelTransitionNew>();
+        }
+
+        for (entity, _) in overlays.iter() {
+            commands.entity(entity).despawn();
+        }
+
+        state.transition = None;
+        state.pixellated.clear();
+
+        ew_switch_next_node.write(EventSwitchNextNode {});
+        return;
+    }
+
+    let Ok(window) = windows.single() else {
+        return;
+    };
+    let window_size = window.size();
+
+    let frame = transition.frame(state.elapsed / duration);
+
+    for (_, mut sprite, mut transform, layer) in old_layers.iter_mut() {
+        transform.translation = layer.translation;
+        apply_layer(
+            &frame.old,
+            &layer.image,
+            &mut sprite,
+            &mut transform,
+            window_size,
+            &mut images,
+            &mut state.pixellated,
+        );
+    }
+
+    for (_, mut sprite, mut transform, layer) in new_layers.iter_mut() {
+        // The translation of new images is reset by `scale_images` only once they are loaded
+        if images.get(&layer.image).is_none() {
+            continue;
+        }
+
+        apply_layer(
+            &frame.new,
+            &layer.image,
+            &mut sprite,
+            &mut transform,
+            window_size,
+            &mut images,
+            &mut state.pixellated,
+        );
+    }
+
+    match overlays.single_mut() {
+        Ok((_, mut color)) => *color = BackgroundColor(frame.overlay),
+        Err(_) => {
+            commands.spawn((
+                NovelTransitionOverlay,
+                Name::new("Novel Transition Overlay"),
+                Node {
+                    position_type: PositionType::Absolute,
+                    width: percent(100),
+                    height: percent(100),
+                    ..default()
+                },
+                BackgroundColor(frame.overlay),
+            ));
+        }
+    }
+}
+
+fn apply_layer(
+    layer: &LayerFrame,
+    image: &Handle<Image>,
+    sprite: &mut Sprite,
+    transform: &mut Transform,
+    window_size: Vec2,
+    images: &mut Assets<Image>,
+    pixellated: &mut HashMap<(AssetId<Image>, u32), Handle<Image>>,
+) {
+    let Some(image_size) = images.get(image).map(|image| image.size()) else {
+        return;
+    };
+
+    // A block larger than the image pixellates it into one colour all the same
+    sprite.image = match layer.pixel_size.min(image_size.max_element()) {
+        0 | 1 => image.clone(),
+        pixel_size => pixellated
+            .entry((image.id(), pixel_size))
+            .or_insert_with(|| {
+                let copy = pixellate_image(images.get(image).unwrap(), pixel_size);
+                images.add(copy)
+            })
+            .clone(),
+    };
+
+    let image_size = image_size.as_vec2();
+    let visible = Rect::from_corners(
+        layer.visible.min * image_size,
+        layer.visible.max * image_size,
+    );
+
+    if visible.is_empty() {
+        sprite.color.set_alpha(0.0);
+        return;
+    }
+
+    sprite.color.set_alpha(layer.alpha);
+    sprite.rect = Some(visible);
+
+    // A cropped sprite is drawn centred on its translation, move it back where the visible
+    // part sits on the whole image
+    let shift = (visible.center() - image_size / 2.0) * transform.scale.truncate();
+    let offset = layer.offset * window_size;
+
+    transform.translation.x += shift.x + offset.x;
+    transform.translation.y += offset.y - shift.y;
+}
+
+/// Copy of `image` where every `pixel_size` block is filled with the colour of its centre
+fn pixellate_image(image: &Image, pixel_size: u32) -> Image {
+    let mut copy = image.clone();
+    let (width, height) = (image.width(), image.height());
+
+    for block_y in (0..height).step_by(pixel_size as usize) {
+        for block_x in (0..width).step_by(pixel_size as usize) {
+            let sample_x = (block_x + pixel_size / 2).min(width - 1);
+            let sample_y = (block_y + pixel_size / 2).min(height - 1);
+            let Ok(color) = image.get_color_at(sample_x, sample_y) else {
+                return copy;
+            };
+
+            for y in block_y..(block_y + pixel_size).min(height) {
+                for x in block_x..(block_x + pixel_size).min(width) {
+                    let _ = copy.set_color_at(x, y, color);
+                }
+            }
+        }
+    }
+
+    copy
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIRECTIONS: [TransitionDirection; 4] = [
+        TransitionDirection::Left,
+        TransitionDirection::Right,
+        TransitionDirection::Up,
+        TransitionDirection::Down,
+    ];
+
+    fn alphas(frame: &TransitionFrame) -> (f32, f32) {
+        (frame.old.alpha, frame.new.alpha)
+    }
+
+    #[test]
+    fn dissolve() {
+        let dissolve = Dissolve { duration: 0.5 };
+
+        assert_eq!(alphas(&dissolve.frame(0.0)), (1.0, 0.0));
+        assert_eq!(alphas(&dissolve.frame(0.5)), (0.5, 0.5));
+        assert_eq!(alphas(&dissolve.frame(1.0)), (0.0, 1.0));
+    }
+
+    #[test]
+    fn fade() {
+        let fade = Fade {
+            out_time: 0.5,
+            hold_time: 0.0,
+            in_time: 0.5,
+            color: Color::BLACK,
+        };
+
+        let frame = fade.frame(0.0);
+        assert_eq!(alphas(&frame), (1.0, 0.0));
+        assert_eq!(frame.overlay.alpha(), 0.0);
+
+        let frame = fade.frame(0.5);
+        assert_eq!(alphas(&frame), (0.0, 1.0));
+        assert_eq!(frame.overlay.alpha(), 1.0);
+
+        let frame = fade.frame(1.0);
+        assert_eq!(alphas(&frame), (0.0, 1.0));
+        assert_eq!(frame.overlay.alpha(), 0.0);
+
+        let hold = Fade {
+            out_time: 0.0,
+            hold_time: 1.0,
+            in_time: 0.0,
+            color: Color::WHITE,
+        };
+        assert_eq!(hold.frame(0.5).overlay.alpha(), 1.0);
+        assert_eq!(hold.frame(1.0).overlay.alpha(), 0.0);
+    }
+
+    #[test]
+    fn pixellate() {
+        let pixellate = Pixellate {
+            duration: 1.0,
+            steps: 5,
+        };
+
+        let frame = pixellate.frame(0.0);
+        assert_eq!(alphas(&frame), (1.0, 0.0));
+        assert_eq!(frame.old.pixel_size, 1);
+
+        let frame = pixellate.frame(0.5);
+        assert_eq!(alphas(&frame), (0.0, 1.0));
+        assert_eq!(frame.new.pixel_size, 32);
+
+        let frame = pixellate.frame(1.0);
+        assert_eq!(alphas(&frame), (0.0, 1.0));
+        assert_eq!(frame.new.pixel_size, 1);
+    }
+
+    #[test]
+    fn pixellate_past_u32() {
+        for steps in [31, 32, 40, u32::MAX] {
+            let pixellate = Pixellate {
+                duration: 1.0,
+                steps,
+            };
+            let expected = if steps == 31 { 1 << 31 } else { u32::MAX };
+
+            assert_eq!(
+                pixellate.frame(0.5).old.pixel_size,
+                expected,
+                "{} steps",
+                steps
+            );
+            assert_eq!(pixellate.frame(0.0).old.pixel_size, 1, "{} steps", steps);
+        }
+    }
+
+    #[test]
+    fn wipe() {
+        let wipe = Wipe {
+            duration: 1.0,
+            direction: TransitionDirection::Left,
+        };
+
+        let frame = wipe.frame(0.5);
+        assert_eq!(frame.old.visible, Rect::new(0.0, 0.0, 0.5, 1.0));
+        assert_eq!(frame.new.visible, Rect::new(0.5, 0.0, 1.0, 1.0));
+
+        let full = Rect::new(0.0, 0.0, 1.0, 1.0);
+        for direction in DIRECTIONS {
+            let wipe = Wipe {
+                duration: 1.0,
+                direction,
+            };
+
+            let frame = wipe.frame(0.0);
+            assert_eq!(frame.old.visible, full, "{:?}", direction);
+            assert!(frame.new.visible.is_empty(), "{:?}", direction);
+
+            let frame = wipe.frame(0.5);
+            assert_eq!(
+                frame.old.visible.size() + frame.new.visible.size(),
+                match direction {
+                    TransitionDirection::Left | TransitionDirection::Right => Vec2::new(1.0, 2.0),
+                    TransitionDirection::Up | TransitionDirection::Down => Vec2::new(2.0, 1.0),
+                },
+                "{:?}",
+                direction
+            );
+
+            let frame = wipe.frame(1.0);
+            assert!(frame.old.visible.is_empty(), "{:?}", direction);
+            assert_eq!(frame.new.visible, full, "{:?}", direction);
+        }
+    }
+
+    #[test]
+    fn slide() {
+        for (direction, start) in DIRECTIONS.into_iter().zip([
+            Vec2::new(1.0, 0.0),
+            Vec2::new(-1.0, 0.0),
+            Vec2::new(0.0, -1.0),
+            Vec2::new(0.0, 1.0),
+        ]) {
+            let slide = Slide {
+                duration: 1.0,
+                direction,
+            };
+
+            assert_eq!(slide.frame(0.0).new.offset, start, "{:?}", direction);
+            assert_eq!(slide.frame(0.5).new.offset, start / 2.0, "{:?}", direction);
+            assert_eq!(slide.frame(1.0).new.offset, Vec2::ZERO, "{:?}", direction);
+            assert_eq!(
+                slide.frame(0.5).old,
+                LayerFrame::default(),
+                "{:?}",
+                direction
+            );
+        }
+    }
+}
@@ -0,0 +1,804 @@
+//! Script variables and the expressions used in `$` lines and `if` conditions.
+//!
+//! Expressions follow a small subset of Python: `True`/`False`, numbers, quoted strings,
+//! variable names, arithmetic (`+ - * / // %`), comparisons, `and`/`or`/`not` and
+//! parentheses.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum NovelValue {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+}
+
+impl NovelValue {
+    /// Truthiness the way Python sees it: `False`, `0`, `0.0` and `""` are false
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            NovelValue::Bool(b) => *b,
+            NovelValue::Int(i) => *i != 0,
+            NovelValue::Float(f) => *f != 0.0,
+            NovelValue::String(s) => !s.is_empty(),
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            NovelValue::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            NovelValue::Int(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    /// Ints are widened to floats
+    pub fn as_float(&self) -> Option<f64> {
+        match self {
+            NovelValue::Int(i) => Some(*i as f64),
+            NovelValue::Float(f) => Some(*f),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            NovelValue::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn type_name(&self) -> &'static str {
+        match self {
+            NovelValue::Bool(_) => "bool",
+            NovelValue::Int(_) => "int",
+            NovelValue::Float(_) => "float",
+            NovelValue::String(_) => "str",
+        }
+    }
+}
+
+impl fmt::Display for NovelValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NovelValue::Bool(true) => write!(f, "True"),
+            NovelValue::Bool(false) => write!(f, "False"),
+            NovelValue::Int(i) => write!(f, "{}", i),
+            NovelValue::Float(v) => write!(f, "{:?}", v),
+            NovelValue::String(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+impl From<bool> for NovelValue {
+    fn from(value: bool) -> Self {
+        NovelValue::Bool(value)
+    }
+}
+
+impl From<i32> for NovelValue {
+    fn from(value: i32) -> Self {
+        NovelValue::Int(value as i64)
+    }
+}
+
+impl From<i64> for NovelValue {
+    fn from(value: i64) -> Self {
+        NovelValue::Int(value)
+    }
+}
+
+impl From<f32> for NovelValue {
+    fn from(value: f32) -> Self {
+        NovelValue::Float(value as f64)
+    }
+}
+
+impl From<f64> for NovelValue {
+    fn from(value: f64) -> Self {
+        NovelValue::Float(value)
+    }
+}
+
+impl From<String> for NovelValue {
+    fn from(value: String) -> Self {
+        NovelValue::String(value)
+    }
+}
+
+impl From<&str> for NovelValue {
+    fn from(value: &str) -> Self {
+        NovelValue::String(value.to_string())
+    }
+}
+
+/// Errors raised while evaluating script expressions
+#[derive(Debug, Error)]
+pub enum ExpressionError {
+    #[error("Unexpected `{0}` in expression")]
+    UnexpectedToken(String),
+    #[error("Unexpected end of expression")]
+    UnexpectedEnd,
+    #[error("Variable `{0}` is not defined")]
+    UndefinedVariable(String),
+    #[error("Unsupported operand types for `{0}`: {1} and {2}")]
+    TypeMismatch(String, &'static str, &'static str),
+    #[error("Division by zero")]
+    DivisionByZero,
+    #[error("Integer overflow in `{0}`")]
+    Overflow(String),
+    #[error("Invalid assignment `{0}`")]
+    InvalidAssignment(String),
+}
+
+/// Variables the script reads and writes, shared with gameplay systems
+#[derive(Resource, Default, Clone, Debug, Serialize, Deserialize)]
+pub struct NovelVariables {
+    values: HashMap<String, NovelValue>,
+}
+
+impl NovelVariables {
+    pub fn get(&self, name: &str) -> Option<&NovelValue> {
+        self.values.get(name)
+    }
+
+    pub fn get_bool(&self, name: &str) -> Option<bool> {
+        self.get(name).and_then(NovelValue::as_bool)
+    }
+
+    pub fn get_int(&self, name: &str) -> Option<i64> {
+        self.get(name).and_then(NovelValue::as_int)
+    }
+
+    pub fn get_float(&self, name: &str) -> Option<f64> {
+        self.get(name).and_then(NovelValue::as_float)
+    }
+
+    pub fn get_str(&self, name: &str) -> Option<&str> {
+        self.get(name).and_then(NovelValue::as_str)
+    }
+
+    pub fn set(&mut self, name: impl Into<String>, value: impl Into<NovelValue>) {
+        self.values.insert(name.into(), value.into());
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<NovelValue> {
+        self.values.remove(name)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.values.contains_key(name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &NovelValue)> {
+        self.values.iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.values.clear();
+    }
+
+    /// Evaluates `expression` against the current variables
+    pub fn evaluate(&self, expression: &str) -> Result<NovelValue, ExpressionError> {
+        let tokens = tokenize(expression)?;
+        let mut parser = Parser {
+            tokens: &tokens,
+            position: 0,
+        };
+
+        let expr = parser.expression()?;
+        if let Some(token) = parser.peek() {
+            return Err(ExpressionError::UnexpectedToken(token.to_string()));
+        }
+
+        expr.evaluate(self)
+    }
+
+    /// Runs a `$` line: `name = expression` or an augmented assignment such as `name += 1`
+    pub fn execute(&mut self, code: &str) -> Result<(), ExpressionError> {
+        let invalid = || ExpressionError::InvalidAssignment(code.to_string());
+
+        let (target, expression) = code.split_once('=').ok_or_else(invalid)?;
+        if expression.starts_with('=') {
+            return Err(invalid());
+        }
+
+        let target = target.trim();
+        // `//=` before `/=`, which would leave a `/` in the name
+        let (name, operator) = match target.strip_suffix("//") {
+            Some(name) => (name.trim(), Some("//")),
+            None => match target.char_indices().last() {
+                Some((i, '+' | '-' | '*' | '/' | '%')) => (target[..i].trim(), Some(&target[i..])),
+                _ => (target, None),
+            },
+        };
+
+        if !is_name(name) {
+            return Err(invalid());
+        }
+
+        let value = match operator {
+            Some(op) => self.evaluate(&format!("{} {} ({})", name, op, expression))?,
+            None => self.evaluate(expression)?,
+        };
+
+        self.set(name, value);
+
+        Ok(())
+    }
+}
+
+fn is_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_alphabetic() || c == '_')
+        && chars.all(|c| c.is_alphanumeric() || c == '_' || c == '.')
+        && !matches!(name, "and" | "or" | "not" | "True" | "False")
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Value(NovelValue),
+    Name(String),
+    Operator(&'static str),
+    LeftParen,
+    RightParen,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Value(NovelValue::String(s)) => write!(f, "{:?}", s),
+            Token::Value(v) => write!(f, "{}", v),
+            Token::Name(n) => write!(f, "{}", n),
+            Token::Operator(op) => write!(f, "{}", op),
+            Token::LeftParen => write!(f, "("),
+            Token::RightParen => write!(f, ")"),
+        }
+    }
+}
+
+const OPERATORS: [&str; 12] = [
+    "==", "!=", "<=", ">=", "//", "<", ">", "+", "-", "*", "/", "%",
+];
+
+fn tokenize(expression: &str) -> Result<Vec<Token>, ExpressionError> {
+    let chars: Vec<char> = expression.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '(' {
+            tokens.push(Token::LeftParen);
+            i += 1;
+        } else if c == ')' {
+            tokens.push(Token::RightParen);
+            i += 1;
+        } else if c == '"' || c == '\'' {
+            let mut value = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => return Err(ExpressionError::UnexpectedEnd),
+                    Some('\\') => {
+                        match chars.get(i + 1) {
+                            Some('n') => value.push('\n'),
+                            Some(escaped) => value.push(*escaped),
+                            None => return Err(ExpressionError::UnexpectedEnd),
+                        }
+                        i += 2;
+                    }
+                    Some(d) if *d == c => {
+                        i += 1;
+                        break;
+                    }
+                    Some(other) => {
+                        value.push(*other);
+                        i += 1;
+                    }
+                }
+            }
+            tokens.push(Token::Value(NovelValue::String(value)));
+        } else if c.is_ascii_digit()
+            || (c == '.' && chars.get(i + 1).is_some_and(char::is_ascii_digit))
+        {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            let number: String = chars[start..i].iter().collect();
+            let value = if number.contains('.') {
+                NovelValue::Float(
+                    number
+                        .parse()
+                        .map_err(|_| ExpressionError::UnexpectedToken(number.clone()))?,
+                )
+            } else {
+                NovelValue::Int(
+                    number
+                        .parse()
+                        .map_err(|_| ExpressionError::UnexpectedToken(number.clone()))?,
+                )
+            };
+            tokens.push(Token::Value(value));
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len()
+                && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '.')
+            {
+                i += 1;
+            }
+            let name: String = chars[start..i].iter().collect();
+            tokens.push(match name.as_str() {
+                "True" => Token::Value(NovelValue::Bool(true)),
+                "False" => Token::Value(NovelValue::Bool(false)),
+                "and" => Token::Operator("and"),
+                "or" => Token::Operator("or"),
+                "not" => Token::Operator("not"),
+                _ => Token::Name(name),
+            });
+        } else {
+            let rest: String = chars[i..].iter().collect();
+            let operator = OPERATORS
+                .iter()
+                .find(|op| rest.starts_with(**op))
+                .ok_or_else(|| ExpressionError::UnexpectedToken(c.to_string()))?;
+            tokens.push(Token::Operator(operator));
+            i += operator.len();
+        }
+    }
+
+    Ok(tokens)
+}
+
+#[derive(Clone)]
+enum Expr {
+    Value(NovelValue),
+    Variable(String),
+    Not(Box<Expr>),
+    Negate(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+}
+
+struct Parser<'a> {
+    tokens: &'a [Token],
+    position: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<&Token> {
+        self.position += 1;
+        self.tokens.get(self.position - 1)
+    }
+
+    fn accept(&mut self, operators: &[&'static str]) -> Option<&'static str> {
+        match self.peek() {
+            Some(Token::Operator(op)) if operators.contains(op) => {
+                let op = *op;
+                self.position += 1;
+                Some(op)
+            }
+            _ => None,
+        }
+    }
+
+    fn expression(&mut self) -> Result<Expr, ExpressionError> {
+        let mut left = self.and()?;
+        while self.accept(&["or"]).is_some() {
+            left = Expr::Or(Box::new(left), Box::new(self.and()?));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Expr, ExpressionError> {
+        let mut left = self.not()?;
+        while self.accept(&["and"]).is_some() {
+            left = Expr::And(Box::new(left), Box::new(self.not()?));
+        }
+        Ok(left)
+    }
+
+    fn not(&mut self) -> Result<Expr, ExpressionError> {
+        if self.accept(&["not"]).is_some() {
+            return Ok(Expr::Not(Box::new(self.not()?)));
+        }
+        self.comparison()
+    }
+
+    /// Chained comparisons such as `a < b < c` read as `a < b and b < c`
+    fn comparison(&mut self) -> Result<Expr, ExpressionError> {
+        let mut left = self.additive()?;
+        let mut chain: Option<Expr> = None;
+
+        while let Some(op) = self.accept(&["==", "!=", "<", "<=", ">", ">="]) {
+            let right = self.additive()?;
+            let comparison = Expr::Binary(op, Box::new(left), Box::new(right.clone()));
+
+            chain = Some(match chain {
+                Some(chain) => Expr::And(Box::new(chain), Box::new(comparison)),
+                None => comparison,
+            });
+            left = right;
+        }
+
+        Ok(chain.unwrap_or(left))
+    }
+
+    fn additive(&mut self) -> Result<Expr, ExpressionError> {
+        let mut left = self.term()?;
+        while let Some(op) = self.accept(&["+", "-"]) {
+            left = Expr::Binary(op, Box::new(left), Box::new(self.term()?));
+        }
+        Ok(left)
+    }
+
+    fn term(&mut self) -> Result<Expr, ExpressionError> {
+        let mut left = self.unary()?;
+        while let Some(op) = self.accept(&["*", "/", "//", "%"]) {
+            left = Expr::Binary(op, Box::new(left), Box::new(self.unary()?));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr, ExpressionError> {
+        if self.accept(&["-"]).is_some() {
+            return Ok(Expr::Negate(Box::new(self.unary()?)));
+        }
+        if self.accept(&["+"]).is_some() {
+            return self.unary();
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr, ExpressionError> {
+        match self.next().cloned() {
+            Some(Token::Value(value)) => Ok(Expr::Value(value)),
+            Some(Token::Name(name)) => Ok(Expr::Variable(name)),
+            Some(Token::LeftParen) => {
+                let expr = self.expression()?;
+                match self.next() {
+                    Some(Token::RightParen) => Ok(expr),
+                    Some(token) => Err(ExpressionError::UnexpectedToken(token.to_string())),
+                    None => Err(ExpressionError::UnexpectedEnd),
+                }
+            }
+            Some(token) => Err(ExpressionError::UnexpectedToken(token.to_string())),
+            None => Err(ExpressionError::UnexpectedEnd),
+        }
+    }
+}
+
+impl Expr {
+    fn evaluate(&self, variables: &NovelVariables) -> Result<NovelValue, ExpressionError> {
+        match self {
+            Expr::Value(value) => Ok(value.clone()),
+            Expr::Variable(name) => variables
+                .get(name)
+                .cloned()
+                .ok_or_else(|| ExpressionError::UndefinedVariable(name.clone())),
+            Expr::Not(expr) => Ok(NovelValue::Bool(!expr.evaluate(variables)?.is_truthy())),
+            Expr::Negate(expr) => match expr.evaluate(variables)? {
+                NovelValue::Int(i) => i
+                    .checked_neg()
+                    .map(NovelValue::Int)
+                    .ok_or_else(|| ExpressionError::Overflow("-".to_string())),
+                NovelValue::Float(f) => Ok(NovelValue::Float(-f)),
+                other => Err(ExpressionError::TypeMismatch(
+                    "-".to_string(),
+                    other.type_name(),
+                    other.type_name(),
+                )),
+            },
+            Expr::And(left, right) => {
+                let left = left.evaluate(variables)?;
+                if left.is_truthy() {
+                    right.evaluate(variables)
+                } else {
+                    Ok(left)
+                }
+            }
+            Expr::Or(left, right) => {
+                let left = left.evaluate(variables)?;
+                if left.is_truthy() {
+                    Ok(left)
+                } else {
+                    right.evaluate(variables)
+                }
+            }
+            Expr::Binary(op, left, right) => {
+                binary(op, left.evaluate(variables)?, right.evaluate(variables)?)
+            }
+        }
+    }
+}
+
+fn binary(op: &str, left: NovelValue, right: NovelValue) -> Result<NovelValue, ExpressionError> {
+    use NovelValue::*;
+
+    let mismatch = |left: &NovelValue, right: &NovelValue| {
+        ExpressionError::TypeMismatch(op.to_string(), left.type_name(), right.type_name())
+    };
+
+    match op {
+        "==" => return Ok(Bool(equals(&left, &right))),
+        "!=" => return Ok(Bool(!equals(&left, &right))),
+        "<" | "<=" | ">" | ">=" => {
+            let ordering = match (&left, &right) {
+                (String(a), String(b)) => a.partial_cmp(b),
+                _ => match (number(&left), number(&right)) {
+                    (Some(a), Some(b)) => a.partial_cmp(&b),
+                    _ => None,
+                },
+            }
+            .ok_or_else(|| mismatch(&left, &right))?;
+
+            return Ok(Bool(match op {
+                "<" => ordering.is_lt(),
+                "<=" => ordering.is_le(),
+                ">" => ordering.is_gt(),
+                _ => ordering.is_ge(),
+            }));
+        }
+        _ => {}
+    }
+
+    match (&left, &right) {
+        (String(a), String(b)) if op == "+" => return Ok(String(format!("{}{}", a, b))),
+        (Int(a), Int(b)) if op != "/" => {
+            let (a, b) = (*a, *b);
+            let result = match op {
+                "+" => a.checked_add(b),
+                "-" => a.checked_sub(b),
+                "*" => a.checked_mul(b),
+                _ if b == 0 => return Err(ExpressionError::DivisionByZero),
+                "//" => floor_div(a, b),
+                _ => Some(floor_rem(a, b)),
+            };
+            return result
+                .map(Int)
+                .ok_or_else(|| ExpressionError::Overflow(op.to_string()));
+        }
+        _ => {}
+    }
+
+    let (a, b) = match (number(&left), number(&right)) {
+        (Some(a), Some(b)) => (a, b),
+        _ => return Err(mismatch(&left, &right)),
+    };
+
+    match op {
+        "+" => Ok(Float(a + b)),
+        "-" => Ok(Float(a - b)),
+        "*" => Ok(Float(a * b)),
+        _ if b == 0.0 => Err(ExpressionError::DivisionByZero),
+        "/" => Ok(Float(a / b)),
+        "//" => Ok(Float((a / b).floor())),
+        _ => {
+            // The remainder takes the sign of the divisor, as in Python
+            let remainder = a % b;
+            if remainder != 0.0 && (remainder < 0.0) != (b < 0.0) {
+                Ok(Float(remainder + b))
+            } else {
+                Ok(Float(remainder))
+            }
+        }
+    }
+}
+
+/// Python's `//`, rounding towards negative infinity. `None` on overflow.
+fn floor_div(a: i64, b: i64) -> Option<i64> {
+    let quotient = a.checked_div(b)?;
+    if a % b != 0 && (a < 0) != (b < 0) {
+        Some(quotient - 1)
+    } else {
+        Some(quotient)
+    }
+}
+
+/// Python's `%`, the remainder taking the sign of the divisor
+fn floor_rem(a: i64, b: i64) -> i64 {
+    // wrapping_rem only wraps for i64::MIN % -1, where the remainder is 0
+    let remainder = a.wrapping_rem(b);
+    if remainder != 0 && (remainder < 0) != (b < 0) {
+        remainder + b
+    } else {
+        remainder
+    }
+}
+
+/// Numeric view of a value; bools count as 0 and 1 like in Python
+fn number(value: &NovelValue) -> Option<f64> {
+    match value {
+        NovelValue::Bool(b) => Some(*b as i64 as f64),
+        other => other.as_float(),
+    }
+}
+
+fn equals(left: &NovelValue, right: &NovelValue) -> bool {
+    match (left, right) {
+        (NovelValue::String(a), NovelValue::String(b)) => a == b,
+        (NovelValue::String(_), _) | (_, NovelValue::String(_)) => false,
+        _ => number(left) == number(right),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn evaluate(expression: &str) -> Result<NovelValue, ExpressionError> {
+        let mut variables = NovelVariables::default();
+        variables.set("points", 3);
+        variables.set("name", "Eileen");
+        variables.evaluate(expression)
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(evaluate("1 + 2 * 3").unwrap(), NovelValue::Int(7));
+        assert_eq!(evaluate("(1 + 2) * 3").unwrap(), NovelValue::Int(9));
+        assert_eq!(evaluate("10 - 4 - 3").unwrap(), NovelValue::Int(3));
+        assert_eq!(evaluate("-2 * 3").unwrap(), NovelValue::Int(-6));
+        assert_eq!(
+            evaluate("not False and False").unwrap(),
+            NovelValue::Bool(false)
+        );
+        assert_eq!(
+            evaluate("True or False and False").unwrap(),
+            NovelValue::Bool(true)
+        );
+        assert_eq!(evaluate("1 < points < 5").unwrap(), NovelValue::Bool(true));
+        assert_eq!(evaluate("1 < points < 2").unwrap(), NovelValue::Bool(false));
+        assert_eq!(evaluate("points + 1 == 4").unwrap(), NovelValue::Bool(true));
+    }
+
+    #[test]
+    fn types() {
+        assert_eq!(evaluate("7 / 2").unwrap(), NovelValue::Float(3.5));
+        assert_eq!(evaluate("1 + 0.5").unwrap(), NovelValue::Float(1.5));
+        assert_eq!(evaluate("1 == 1.0").unwrap(), NovelValue::Bool(true));
+        assert_eq!(evaluate("'1' == 1").unwrap(), NovelValue::Bool(false));
+        assert_eq!(
+            evaluate("'Hi ' + name").unwrap(),
+            NovelValue::String("Hi Eileen".to_string())
+        );
+        assert_eq!(
+            evaluate("0 or 'x'").unwrap(),
+            NovelValue::String("x".to_string())
+        );
+        assert_eq!(evaluate("'a' < 'b'").unwrap(), NovelValue::Bool(true));
+    }
+
+    #[test]
+    fn floor_division_and_modulo() {
+        assert_eq!(evaluate("7 // 2").unwrap(), NovelValue::Int(3));
+        assert_eq!(evaluate("7 // -2").unwrap(), NovelValue::Int(-4));
+        assert_eq!(evaluate("-7 // 2").unwrap(), NovelValue::Int(-4));
+        assert_eq!(evaluate("-7 // -2").unwrap(), NovelValue::Int(3));
+        assert_eq!(evaluate("7 % -2").unwrap(), NovelValue::Int(-1));
+        assert_eq!(evaluate("-7 % 2").unwrap(), NovelValue::Int(1));
+        assert_eq!(evaluate("6 % -3").unwrap(), NovelValue::Int(0));
+        assert_eq!(evaluate("7.0 // -2").unwrap(), NovelValue::Float(-4.0));
+        assert_eq!(evaluate("7.5 % -2").unwrap(), NovelValue::Float(-0.5));
+        assert_eq!(evaluate("-7.5 % 2").unwrap(), NovelValue::Float(0.5));
+    }
+
+    #[test]
+    fn overflow() {
+        let mut variables = NovelVariables::default();
+        variables.set("max", i64::MAX);
+        variables.set("min", i64::MIN);
+
+        for expression in ["max + 1", "min - 1", "max * 2", "-min", "min // -1"] {
+            assert!(
+                matches!(
+                    variables.evaluate(expression),
+                    Err(ExpressionError::Overflow(_))
+                ),
+                "{}",
+                expression
+            );
+        }
+        assert_eq!(variables.evaluate("min % -1").unwrap(), NovelValue::Int(0));
+    }
+
+    #[test]
+    fn errors() {
+        assert!(matches!(
+            evaluate("missing + 1"),
+            Err(ExpressionError::UndefinedVariable(name)) if name == "missing"
+        ));
+        assert!(matches!(
+            evaluate("1 // 0"),
+            Err(ExpressionError::DivisionByZero)
+        ));
+        assert!(matches!(
+            evaluate("1 % 0"),
+            Err(ExpressionError::DivisionByZero)
+        ));
+        assert!(matches!(
+            evaluate("1.0 / 0"),
+            Err(ExpressionError::DivisionByZero)
+        ));
+        assert!(matches!(
+            evaluate("1 +"),
+            Err(ExpressionError::UnexpectedEnd)
+        ));
+        assert!(matches!(
+            evaluate("(1 + 2"),
+            Err(ExpressionError::UnexpectedEnd)
+        ));
+        assert!(matches!(
+            evaluate("'open"),
+            Err(ExpressionError::UnexpectedEnd)
+        ));
+        assert!(matches!(
+            evaluate("1 2"),
+            Err(ExpressionError::UnexpectedToken(_))
+        ));
+        assert!(matches!(
+            evaluate("1 $ 2"),
+            Err(ExpressionError::UnexpectedToken(_))
+        ));
+        assert!(matches!(
+            evaluate("'a' - 'b'"),
+            Err(ExpressionError::TypeMismatch(_, "str", "str"))
+        ));
+        assert!(matches!(
+            evaluate("-'a'"),
+            Err(ExpressionError::TypeMismatch(_, "str", "str"))
+        ));
+    }
+
+    #[test]
+    fn execute() {
+        let mut variables = NovelVariables::default();
+        variables.execute("points = 2").unwrap();
+        variables.execute("points += 3").unwrap();
+        variables.execute("points -= 8").unwrap();
+        assert_eq!(variables.get_int("points"), Some(-3));
+
+        variables.execute("halved = 7").unwrap();
+        variables.execute("halved //= 2").unwrap();
+        assert_eq!(variables.get_int("halved"), Some(3));
+        variables.execute("halved //= -2").unwrap();
+        assert_eq!(variables.get_int("halved"), Some(-2));
+
+        variables.execute("met = points < 0").unwrap();
+        assert_eq!(variables.get_bool("met"), Some(true));
+
+        assert!(matches!(
+            variables.execute("points == 1"),
+            Err(ExpressionError::InvalidAssignment(_))
+        ));
+        assert!(matches!(
+            variables.execute("1x = 1"),
+            Err(ExpressionError::InvalidAssignment(_))
+        ));
+        assert!(matches!(
+            variables.execute("points"),
+            Err(ExpressionError::InvalidAssignment(_))
+        ));
+    }
+}